@@ -0,0 +1,8 @@
+CREATE TABLE password_reset_tokens(
+    token_hash TEXT NOT NULL,
+    PRIMARY KEY (token_hash),
+    user_id uuid NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    created_at timestamptz NOT NULL,
+    expires_at timestamptz NOT NULL,
+    used_at timestamptz NULL
+);
@@ -0,0 +1,11 @@
+ALTER TABLE users
+    ADD COLUMN totp_secret TEXT NULL,
+    ADD COLUMN totp_enabled BOOLEAN NOT NULL DEFAULT false,
+    ADD COLUMN totp_last_used_step BIGINT NULL;
+
+CREATE TABLE totp_recovery_codes(
+    code_hash TEXT NOT NULL,
+    user_id uuid NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    PRIMARY KEY (user_id, code_hash),
+    used_at timestamptz NULL
+);
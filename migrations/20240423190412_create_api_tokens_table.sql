@@ -0,0 +1,14 @@
+CREATE TABLE api_tokens(
+    token_id uuid NOT NULL,
+    PRIMARY KEY (token_id),
+    user_id uuid NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    scopes TEXT[] NOT NULL,
+    created_at timestamptz NOT NULL,
+    expires_at timestamptz NOT NULL,
+    last_used_at timestamptz NULL,
+    revoked_at timestamptz NULL
+);
+
+CREATE INDEX api_tokens_user_id_idx ON api_tokens (user_id);
@@ -0,0 +1,9 @@
+CREATE TABLE failed_login_attempts(
+    id BIGSERIAL PRIMARY KEY,
+    username TEXT NOT NULL,
+    ip_address TEXT NOT NULL,
+    attempted_at timestamptz NOT NULL
+);
+
+CREATE INDEX failed_login_attempts_username_idx ON failed_login_attempts (username, attempted_at);
+CREATE INDEX failed_login_attempts_ip_address_idx ON failed_login_attempts (ip_address, attempted_at);
@@ -0,0 +1,28 @@
+CREATE TABLE roles(
+    name TEXT PRIMARY KEY
+);
+
+CREATE TABLE role_permissions(
+    role TEXT NOT NULL REFERENCES roles (name) ON DELETE CASCADE,
+    permission TEXT NOT NULL,
+    PRIMARY KEY (role, permission)
+);
+
+INSERT INTO roles (name) VALUES ('admin'), ('editor'), ('viewer');
+
+INSERT INTO role_permissions (role, permission) VALUES
+    ('admin', 'newsletters:publish'),
+    ('admin', 'subscribers:read'),
+    ('admin', 'stats:read'),
+    ('admin', 'users:manage'),
+    ('admin', 'api_tokens:manage'),
+    ('editor', 'newsletters:publish'),
+    ('editor', 'subscribers:read'),
+    ('editor', 'stats:read'),
+    ('viewer', 'subscribers:read'),
+    ('viewer', 'stats:read');
+
+-- everybody used to be allowed everything, keep it that way for existing users
+ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'admin' REFERENCES roles (name);
+-- new users get the least privileges unless told otherwise
+ALTER TABLE users ALTER COLUMN role SET DEFAULT 'viewer';
@@ -0,0 +1,16 @@
+-- Saved responses of POST /newsletters, keyed by the client supplied Idempotency-Key
+CREATE TYPE header_pair AS (
+    name TEXT,
+    value BYTEA
+);
+
+CREATE TABLE idempotency (
+    user_id UUID NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    idempotency_key TEXT NOT NULL,
+    -- NULL while the first request with this key is still being processed
+    response_status_code SMALLINT,
+    response_headers header_pair[],
+    response_body BYTEA,
+    created_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (user_id, idempotency_key)
+);
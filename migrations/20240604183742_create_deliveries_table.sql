@@ -0,0 +1,22 @@
+-- Outcome per issue and recipient, the queue only knows what is still pending.
+--   queued:  waiting for the delivery worker
+--   sent:    accepted by the email provider
+--   failed:  the provider could not be reached or kept failing, can be re-queued
+--   bounced: the address was refused for good (invalid, or rejected by the provider)
+CREATE TABLE deliveries (
+    newsletter_issue_id UUID NOT NULL
+        REFERENCES newsletter_issues (newsletter_issue_id),
+    subscriber_email TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'queued'
+        CHECK (status IN ('queued', 'sent', 'failed', 'bounced')),
+    n_attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    provider_message_id TEXT,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (newsletter_issue_id, subscriber_email)
+);
+
+-- issues published before, as far as we still know about them
+INSERT INTO deliveries (newsletter_issue_id, subscriber_email)
+SELECT newsletter_issue_id, subscriber_email
+FROM issue_delivery_queue;
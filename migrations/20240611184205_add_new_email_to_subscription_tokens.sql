@@ -0,0 +1,2 @@
+-- a token with a new email confirms an email change instead of a subscription
+ALTER TABLE subscription_tokens ADD COLUMN new_email TEXT NULL;
@@ -0,0 +1,8 @@
+-- the defaults only backfill existing rows: links that are out already stay valid for another day
+ALTER TABLE subscription_tokens
+    ADD COLUMN created_at timestamptz NOT NULL DEFAULT now(),
+    ADD COLUMN expires_at timestamptz NOT NULL DEFAULT now() + interval '24 hours';
+
+ALTER TABLE subscription_tokens
+    ALTER COLUMN created_at DROP DEFAULT,
+    ALTER COLUMN expires_at DROP DEFAULT;
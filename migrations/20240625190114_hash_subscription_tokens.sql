@@ -0,0 +1,13 @@
+-- tokens are looked up by their keyed hash (HMAC with `hmac_secret`) from now on.
+-- SQL doesn't know the secret, so rows from before keep their plain text token until
+-- `rust2prod migrate` hashes them right after this migration.
+ALTER TABLE subscription_tokens ADD COLUMN token_hash TEXT NULL;
+ALTER TABLE subscription_tokens DROP CONSTRAINT subscription_tokens_pkey;
+ALTER TABLE subscription_tokens ALTER COLUMN subscription_token DROP NOT NULL;
+CREATE UNIQUE INDEX subscription_tokens_token_hash_idx ON subscription_tokens (token_hash);
+ALTER TABLE subscription_tokens ADD CONSTRAINT subscription_tokens_hashed_or_legacy
+    CHECK ((token_hash IS NULL) <> (subscription_token IS NULL));
+
+-- reset tokens were hashed without a key and can't be converted, they are only valid
+-- for an hour anyway: outstanding ones are dropped and have to be requested again
+DELETE FROM password_reset_tokens;
@@ -0,0 +1,4 @@
+--   skipped: the subscriber left (unsubscribed) after the issue was queued, nothing was sent
+ALTER TABLE deliveries DROP CONSTRAINT deliveries_status_check;
+ALTER TABLE deliveries ADD CONSTRAINT deliveries_status_check
+    CHECK (status IN ('queued', 'sent', 'failed', 'bounced', 'skipped'));
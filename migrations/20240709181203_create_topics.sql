@@ -0,0 +1,17 @@
+-- What an issue is about, issues without a topic go to every confirmed subscriber
+CREATE TABLE topics (
+    topic_id UUID PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL
+);
+
+-- Subscribers hear about every topic until they turn it off in the preference center:
+-- existing subscribers keep getting what they signed up for, and a new topic reaches
+-- the whole list
+CREATE TABLE topic_opt_outs (
+    subscriber_id UUID NOT NULL REFERENCES subscriptions (id) ON DELETE CASCADE,
+    topic_id UUID NOT NULL REFERENCES topics (topic_id) ON DELETE CASCADE,
+    PRIMARY KEY (subscriber_id, topic_id)
+);
+
+ALTER TABLE newsletter_issues ADD COLUMN topic_id UUID NULL REFERENCES topics (topic_id);
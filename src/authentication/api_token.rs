@@ -0,0 +1,180 @@
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::token::{generate_token, hash_token};
+use super::AuthError;
+
+/// Makes API tokens recognisable, e.g. for secret scanners
+const API_TOKEN_PREFIX: &str = "r2p_";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ApiScope {
+    NewslettersPublish,
+    SubscribersRead,
+}
+
+impl ApiScope {
+    pub const ALL: [ApiScope; 2] = [ApiScope::NewslettersPublish, ApiScope::SubscribersRead];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::NewslettersPublish => "newsletters:publish",
+            Self::SubscribersRead => "subscribers:read",
+        }
+    }
+}
+
+impl std::fmt::Display for ApiScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl TryFrom<&str> for ApiScope {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s {
+            "newsletters:publish" => Ok(Self::NewslettersPublish),
+            "subscribers:read" => Ok(Self::SubscribersRead),
+            other => Err(format!("{} is not a valid API scope", other)),
+        }
+    }
+}
+
+/// The owner of a valid API token and what it may be used for
+pub struct ApiTokenGrant {
+    pub token_id: Uuid,
+    pub user_id: Uuid,
+    pub scopes: Vec<ApiScope>,
+}
+
+impl ApiTokenGrant {
+    pub fn has_scope(&self, scope: ApiScope) -> bool {
+        self.scopes.contains(&scope)
+    }
+}
+
+/// What the admin UI shows about a token, the token itself is never stored
+pub struct ApiTokenInfo {
+    pub token_id: Uuid,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// Creates a new token and returns it in plain text, only its hash is persisted
+#[tracing::instrument(name = "Create API token", skip(pool))]
+pub async fn create_api_token(
+    pool: &PgPool,
+    user_id: Uuid,
+    name: &str,
+    scopes: &[ApiScope],
+    expires_at: DateTime<Utc>,
+) -> Result<String, anyhow::Error> {
+    let token = format!("{}{}", API_TOKEN_PREFIX, generate_token(40));
+    let scopes: Vec<String> = scopes.iter().map(|s| s.as_str().to_string()).collect();
+
+    sqlx::query!(
+        r#"
+        INSERT INTO api_tokens (token_id, user_id, name, token_hash, scopes, created_at, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        "#,
+        Uuid::new_v4(),
+        user_id,
+        name,
+        hash_token(&token),
+        &scopes,
+        Utc::now(),
+        expires_at
+    )
+    .execute(pool)
+    .await
+    .context("Failed to store API token.")?;
+
+    Ok(token)
+}
+
+#[tracing::instrument(name = "List API tokens", skip(pool))]
+pub async fn list_api_tokens(
+    pool: &PgPool,
+    user_id: Uuid,
+) -> Result<Vec<ApiTokenInfo>, anyhow::Error> {
+    let tokens = sqlx::query_as!(
+        ApiTokenInfo,
+        r#"
+        SELECT token_id, name, scopes, created_at, expires_at, last_used_at, revoked_at
+        FROM api_tokens
+        WHERE user_id = $1
+        ORDER BY created_at DESC
+        "#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to list API tokens.")?;
+
+    Ok(tokens)
+}
+
+/// Returns false if the token does not exist, belongs to somebody else or was revoked before
+#[tracing::instrument(name = "Revoke API token", skip(pool))]
+pub async fn revoke_api_token(
+    pool: &PgPool,
+    user_id: Uuid,
+    token_id: Uuid,
+) -> Result<bool, anyhow::Error> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE api_tokens
+        SET revoked_at = now()
+        WHERE token_id = $1 AND user_id = $2 AND revoked_at IS NULL
+        "#,
+        token_id,
+        user_id
+    )
+    .execute(pool)
+    .await
+    .context("Failed to revoke API token.")?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/// Looks up a presented token and records its use
+#[tracing::instrument(name = "Validate API token", skip(token, pool))]
+pub async fn validate_api_token(token: &str, pool: &PgPool) -> Result<ApiTokenGrant, AuthError> {
+    let row = sqlx::query!(
+        r#"
+        UPDATE api_tokens
+        SET last_used_at = now()
+        FROM users
+        WHERE users.user_id = api_tokens.user_id
+            AND users.disabled_at IS NULL
+            AND token_hash = $1
+            AND revoked_at IS NULL
+            AND expires_at > now()
+        RETURNING token_id, api_tokens.user_id, scopes
+        "#,
+        hash_token(token)
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to perform a query to validate an API token.")?
+    .ok_or_else(|| AuthError::InvalidCredentials(anyhow::anyhow!("Unknown API token.")))?;
+
+    Ok(ApiTokenGrant {
+        token_id: row.token_id,
+        user_id: row.user_id,
+        // scopes that are not known (anymore) are simply ignored
+        scopes: row
+            .scopes
+            .iter()
+            .filter_map(|s| ApiScope::try_from(s.as_str()).ok())
+            .collect(),
+    })
+}
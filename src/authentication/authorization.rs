@@ -0,0 +1,174 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::{web, HttpMessage, HttpResponse};
+use actix_web_lab::middleware::Next;
+use anyhow::Context;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::UserId;
+use crate::errors::error_chain_fmt;
+use crate::utils::e500;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Role {
+    Admin,
+    Editor,
+    Viewer,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Admin => "admin",
+            Self::Editor => "editor",
+            Self::Viewer => "viewer",
+        }
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl TryFrom<&str> for Role {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s {
+            "admin" => Ok(Self::Admin),
+            "editor" => Ok(Self::Editor),
+            "viewer" => Ok(Self::Viewer),
+            other => Err(format!(
+                "{} is not a valid role. Use either `admin`, `editor` or `viewer`.",
+                other
+            )),
+        }
+    }
+}
+
+/// What a role may do, the mapping lives in the `role_permissions` table
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Permission {
+    PublishNewsletters,
+    ReadSubscribers,
+    ReadStats,
+    ManageUsers,
+    ManageApiTokens,
+}
+
+impl Permission {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::PublishNewsletters => "newsletters:publish",
+            Self::ReadSubscribers => "subscribers:read",
+            Self::ReadStats => "stats:read",
+            Self::ManageUsers => "users:manage",
+            Self::ManageApiTokens => "api_tokens:manage",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            Self::PublishNewsletters => "draft and send newsletter issues",
+            Self::ReadSubscribers => "see subscribers",
+            Self::ReadStats => "see statistics",
+            Self::ManageUsers => "manage users",
+            Self::ManageApiTokens => "manage API tokens",
+        }
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum AuthorizationError {
+    #[error("Your role ({role}) does not allow you to {}.", .permission.description())]
+    Forbidden {
+        role: String,
+        permission: Permission,
+    },
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for AuthorizationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+#[tracing::instrument(name = "Get role", skip(pool))]
+pub async fn get_role(pool: &PgPool, user_id: Uuid) -> Result<Role, anyhow::Error> {
+    let role = sqlx::query!("SELECT role FROM users WHERE user_id = $1", user_id)
+        .fetch_one(pool)
+        .await
+        .context("Failed to retrieve the role of a user.")?
+        .role;
+
+    Role::try_from(role.as_str()).map_err(anyhow::Error::msg)
+}
+
+/// The reusable guard: checks the role of a user against the permissions stored for it
+#[tracing::instrument(name = "Authorize", skip(pool))]
+pub async fn authorize(
+    pool: &PgPool,
+    user_id: Uuid,
+    permission: Permission,
+) -> Result<(), AuthorizationError> {
+    let row = sqlx::query!(
+        r#"
+        SELECT
+            role,
+            EXISTS (
+                SELECT 1 FROM role_permissions rp
+                WHERE rp.role = users.role AND rp.permission = $2
+            ) AS "granted!"
+        FROM users
+        WHERE user_id = $1
+        "#,
+        user_id,
+        permission.as_str()
+    )
+    .fetch_one(pool)
+    .await
+    .context("Failed to check the permissions of a user.")?;
+
+    if !row.granted {
+        tracing::warn!(role = %row.role, permission = permission.as_str(), "Permission denied.");
+        return Err(AuthorizationError::Forbidden {
+            role: row.role,
+            permission,
+        });
+    }
+
+    Ok(())
+}
+
+/// [`authorize`] as middleware for a whole scope, wrap it in a closure to pick the permission.
+/// Has to run behind [`reject_anonymous_users`](super::reject_anonymous_users).
+pub async fn require_permission(
+    permission: Permission,
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let user_id = *req
+        .extensions()
+        .get::<UserId>()
+        .copied()
+        .ok_or_else(|| e500("No user id, is reject_anonymous_users in place?"))?;
+    let pool = req
+        .app_data::<web::Data<PgPool>>()
+        .cloned()
+        .ok_or_else(|| e500("No connection pool in the application data."))?;
+
+    match authorize(&pool, user_id, permission).await {
+        Ok(()) => next.call(req).await,
+        Err(e @ AuthorizationError::Forbidden { .. }) => {
+            let response = HttpResponse::Forbidden().body(e.to_string());
+            Err(InternalError::from_response(e, response).into())
+        }
+        Err(e) => Err(e500(e)),
+    }
+}
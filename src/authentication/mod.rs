@@ -0,0 +1,37 @@
+mod api_token;
+mod authorization;
+mod middleware;
+mod password;
+mod password_reset;
+mod throttle;
+mod token;
+mod two_factor;
+mod users;
+
+pub use api_token::{
+    create_api_token, list_api_tokens, revoke_api_token, validate_api_token, ApiScope,
+    ApiTokenGrant, ApiTokenInfo,
+};
+pub use authorization::{
+    authorize, get_role, require_permission, AuthorizationError, Permission, Role,
+};
+pub use middleware::{reject_anonymous_users, UserId};
+pub use password::{
+    change_password, get_user_id, get_username, validate_credentials, AuthError, Credentials,
+    PasswordHashing,
+};
+pub use password_reset::{
+    consume_password_reset_token, create_password_reset_token, get_user_id_by_password_reset_token,
+};
+pub use throttle::{
+    check_login_throttle, clear_failed_logins, client_ip, record_failed_login, Lockout,
+    LockoutScope, TrustedProxies,
+};
+pub use token::hmac_token;
+pub use two_factor::{
+    begin_totp_enrollment, disable_totp, enable_totp, get_totp_enrollment, is_totp_enabled,
+    use_recovery_code, verify_second_factor, verify_totp_code, TotpEncryptionKey, TotpEnrollment,
+};
+pub use users::{
+    create_user, disable_user, enable_user, find_user, list_users, set_role, UserInfo,
+};
@@ -0,0 +1,335 @@
+use anyhow::Context;
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use secrecy::ExposeSecret;
+use secrecy::Secret;
+use sqlx::{PgExecutor, PgPool};
+use tracing::Instrument;
+
+use crate::configuration::PasswordHashingSettings;
+use crate::domain::NewPassword;
+use crate::telemetry::spawn_blocking_with_tracing;
+
+/// The configured argon2id cost, used for new hashes and to spot outdated ones
+#[derive(Clone)]
+pub struct PasswordHashing {
+    params: Params,
+    // verified against for unknown users, so they take as long as known ones
+    dummy_hash: Secret<String>,
+}
+
+impl PasswordHashing {
+    pub fn new(settings: &PasswordHashingSettings) -> Result<Self, anyhow::Error> {
+        let params = Params::new(
+            settings.memory_kib,
+            settings.iterations,
+            settings.parallelism,
+            None,
+        )
+        .map_err(|e| anyhow::anyhow!("Invalid argon2 parameters: {}", e))?;
+
+        let salt = SaltString::encode_b64(b"dummy-salt").map_err(anyhow::Error::msg)?;
+        let dummy_hash = argon2(params.clone())
+            .hash_password(b"password", &salt)
+            .map_err(anyhow::Error::msg)?
+            .to_string();
+
+        Ok(Self {
+            params,
+            dummy_hash: Secret::new(dummy_hash),
+        })
+    }
+
+    pub(super) fn params(&self) -> Params {
+        self.params.clone()
+    }
+
+    fn needs_rehash(&self, password_hash: &PasswordHash) -> bool {
+        let Ok(params) = Params::try_from(password_hash) else {
+            return true;
+        };
+
+        password_hash.algorithm != Algorithm::Argon2id.ident()
+            || password_hash.version != Some(Version::V0x13.into())
+            || params.m_cost() != self.params.m_cost()
+            || params.t_cost() != self.params.t_cost()
+            || params.p_cost() != self.params.p_cost()
+    }
+}
+
+fn argon2(params: Params) -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+}
+
+pub struct Credentials {
+    pub username: String,
+    pub password: Secret<String>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AuthError {
+    #[error("Invalid credentials.")]
+    InvalidCredentials(#[source] anyhow::Error),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+#[tracing::instrument(name = "Validate credentials", skip(credentials, hashing, pool))]
+pub async fn validate_credentials(
+    credentials: Credentials,
+    hashing: &PasswordHashing,
+    pool: &PgPool,
+) -> Result<uuid::Uuid, AuthError> {
+    let mut user_id = None;
+    let mut expected_password_hash = hashing.dummy_hash.clone();
+
+    if let Some((stored_user_id, stored_password_hash)) =
+        get_stored_credentials(&credentials.username, pool).await?
+    {
+        user_id = Some(stored_user_id);
+        expected_password_hash = stored_password_hash;
+    }
+
+    let password = credentials.password.clone();
+    let stored_password_hash = expected_password_hash.clone();
+    spawn_blocking_with_tracing(move || {
+        verify_password_hash(expected_password_hash, credentials.password)
+    })
+    .await
+    .context("Failed to spawn blocking task to validate password hash.")??;
+
+    let user_id = user_id
+        .ok_or_else(|| anyhow::anyhow!("Unknown username"))
+        .map_err(AuthError::InvalidCredentials)?;
+
+    // the only moment we know the password, use it to bring the hash up to the configured cost
+    let outdated = PasswordHash::new(stored_password_hash.expose_secret())
+        .map(|hash| hashing.needs_rehash(&hash))
+        .unwrap_or(true);
+    if outdated {
+        let hashing = hashing.clone();
+        let pool = pool.clone();
+        tokio::spawn(
+            async move {
+                if let Err(e) =
+                    upgrade_password_hash(user_id, password, stored_password_hash, &hashing, &pool)
+                        .await
+                {
+                    tracing::error!(error.cause_chain = ?e, "Failed to upgrade password hash.");
+                }
+            }
+            .instrument(tracing::Span::current()),
+        );
+    }
+
+    Ok(user_id)
+}
+
+#[tracing::instrument(name = "Upgrade password hash", skip_all, fields(user_id=%user_id))]
+async fn upgrade_password_hash(
+    user_id: uuid::Uuid,
+    password: Secret<String>,
+    outdated_password_hash: Secret<String>,
+    hashing: &PasswordHashing,
+    pool: &PgPool,
+) -> Result<(), anyhow::Error> {
+    let params = hashing.params();
+    let password_hash =
+        spawn_blocking_with_tracing(move || compute_password_hash(password, params))
+            .await?
+            .context("Failed to hash password.")?;
+
+    // a password change in the meantime wins
+    sqlx::query!(
+        r#"
+        UPDATE users
+        SET password_hash = $1
+        WHERE user_id = $2 AND password_hash = $3
+        "#,
+        password_hash.expose_secret(),
+        user_id,
+        outdated_password_hash.expose_secret()
+    )
+    .execute(pool)
+    .await
+    .context("Failed to store the upgraded password hash.")?;
+
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Verify password hash",
+    skip(expected_password_hash, password_candidate)
+)]
+fn verify_password_hash(
+    expected_password_hash: Secret<String>,
+    password_candidate: Secret<String>,
+) -> Result<(), AuthError> {
+    let expected_password_hash = PasswordHash::new(expected_password_hash.expose_secret())
+        .context("Failed to parse password hash in PHC format.")?;
+
+    Argon2::default()
+        .verify_password(
+            password_candidate.expose_secret().as_bytes(),
+            &expected_password_hash,
+        )
+        .context("Invalid password.")
+        .map_err(AuthError::InvalidCredentials)
+}
+
+#[tracing::instrument(name = "Get stored credentials", skip(username, pool))]
+async fn get_stored_credentials(
+    username: &str,
+    pool: &PgPool,
+) -> Result<Option<(uuid::Uuid, Secret<String>)>, anyhow::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT user_id, password_hash
+        FROM users
+        WHERE username = $1 AND disabled_at IS NULL
+        "#,
+        username,
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to perform a query to validate auth credentials.")?
+    .map(|row| (row.user_id, Secret::new(row.password_hash)));
+
+    Ok(row)
+}
+
+#[tracing::instrument(name = "Get username", skip(pool))]
+pub async fn get_username(user_id: uuid::Uuid, pool: &PgPool) -> Result<String, anyhow::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT username
+        FROM users
+        WHERE user_id = $1
+        "#,
+        user_id,
+    )
+    .fetch_one(pool)
+    .await
+    .context("Failed to perform a query to retrieve a username.")?;
+
+    Ok(row.username)
+}
+
+#[tracing::instrument(name = "Change password", skip(password, hashing, executor))]
+pub async fn change_password(
+    user_id: uuid::Uuid,
+    password: NewPassword,
+    hashing: &PasswordHashing,
+    executor: impl PgExecutor<'_>,
+) -> Result<(), anyhow::Error> {
+    let params = hashing.params();
+    let password_hash =
+        spawn_blocking_with_tracing(move || compute_password_hash(password, params))
+            .await?
+            .context("Failed to hash password.")?;
+
+    sqlx::query!(
+        r#"
+        UPDATE users
+        SET password_hash = $1
+        WHERE user_id = $2
+        "#,
+        password_hash.expose_secret(),
+        user_id
+    )
+    .execute(executor)
+    .await
+    .context("Failed to change user's password in the database.")?;
+
+    Ok(())
+}
+
+pub(super) fn compute_password_hash(
+    password: impl ExposeSecret<String>,
+    params: Params,
+) -> Result<Secret<String>, anyhow::Error> {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    let password_hash = argon2(params)
+        .hash_password(password.expose_secret().as_bytes(), &salt)?
+        .to_string();
+
+    Ok(Secret::new(password_hash))
+}
+
+#[tracing::instrument(name = "Get user id", skip(username, pool))]
+pub async fn get_user_id(
+    username: &str,
+    pool: &PgPool,
+) -> Result<Option<uuid::Uuid>, anyhow::Error> {
+    let user_id = get_stored_credentials(username, pool)
+        .await?
+        .map(|(user_id, _)| user_id);
+
+    Ok(user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hashing(memory_kib: u32, iterations: u32, parallelism: u32) -> PasswordHashing {
+        PasswordHashing::new(&PasswordHashingSettings {
+            memory_kib,
+            iterations,
+            parallelism,
+        })
+        .unwrap()
+    }
+
+    fn hash_with(hashing: &PasswordHashing) -> String {
+        compute_password_hash(Secret::new("password".to_string()), hashing.params())
+            .unwrap()
+            .expose_secret()
+            .clone()
+    }
+
+    #[test]
+    fn hashes_with_the_configured_cost_are_up_to_date() {
+        let hashing = hashing(15000, 2, 1);
+        let hash = hash_with(&hashing);
+
+        assert!(!hashing.needs_rehash(&PasswordHash::new(&hash).unwrap()));
+    }
+
+    #[test]
+    fn hashes_with_a_different_cost_are_outdated() {
+        let hash = hash_with(&hashing(8192, 1, 1));
+
+        for current in [
+            hashing(15000, 1, 1),
+            hashing(8192, 2, 1),
+            hashing(8192, 1, 2),
+        ] {
+            assert!(current.needs_rehash(&PasswordHash::new(&hash).unwrap()));
+        }
+    }
+
+    #[test]
+    fn other_algorithms_are_outdated() {
+        let hash = Argon2::new(Algorithm::Argon2i, Version::V0x13, Params::default())
+            .hash_password(b"password", &SaltString::generate(&mut rand::thread_rng()))
+            .unwrap()
+            .to_string();
+
+        let current = PasswordHashing::new(&PasswordHashingSettings {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        })
+        .unwrap();
+        assert!(current.needs_rehash(&PasswordHash::new(&hash).unwrap()));
+    }
+
+    #[test]
+    fn the_dummy_hash_uses_the_configured_cost() {
+        let hashing = hashing(8192, 3, 1);
+
+        let dummy_hash = hashing.dummy_hash.expose_secret().clone();
+        assert!(!hashing.needs_rehash(&PasswordHash::new(&dummy_hash).unwrap()));
+    }
+}
@@ -0,0 +1,101 @@
+use anyhow::Context;
+use chrono::Utc;
+use secrecy::Secret;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use super::token::{generate_token, hmac_token};
+
+/// How long a password reset link stays valid
+const PASSWORD_RESET_TOKEN_TTL: chrono::Duration = chrono::Duration::hours(1);
+
+/// Creates a new single-use reset token for the user and returns it in plain text,
+/// only its keyed hash is persisted.
+#[tracing::instrument(name = "Create password reset token", skip(pool, secret))]
+pub async fn create_password_reset_token(
+    pool: &PgPool,
+    secret: &Secret<String>,
+    user_id: Uuid,
+) -> Result<String, anyhow::Error> {
+    let token = generate_token(32);
+    let now = Utc::now();
+
+    sqlx::query!(
+        r#"
+        INSERT INTO password_reset_tokens (token_hash, user_id, created_at, expires_at)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        hmac_token(secret, &token),
+        user_id,
+        now,
+        now + PASSWORD_RESET_TOKEN_TTL
+    )
+    .execute(pool)
+    .await
+    .context("Failed to store password reset token.")?;
+
+    Ok(token)
+}
+
+/// Returns the owner of a token that is neither expired nor used yet
+#[tracing::instrument(name = "Check password reset token", skip(pool, secret, token))]
+pub async fn get_user_id_by_password_reset_token(
+    pool: &PgPool,
+    secret: &Secret<String>,
+    token: &str,
+) -> Result<Option<Uuid>, anyhow::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT user_id
+        FROM password_reset_tokens
+        WHERE token_hash = $1 AND used_at IS NULL AND expires_at > now()
+        "#,
+        hmac_token(secret, token)
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to look up password reset token.")?;
+
+    Ok(row.map(|r| r.user_id))
+}
+
+/// Marks the token as used and returns its owner, a token can only be consumed once.
+/// All other outstanding tokens of the user are invalidated as well.
+#[tracing::instrument(
+    name = "Consume password reset token",
+    skip(transaction, secret, token)
+)]
+pub async fn consume_password_reset_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    secret: &Secret<String>,
+    token: &str,
+) -> Result<Option<Uuid>, anyhow::Error> {
+    let row = sqlx::query!(
+        r#"
+        UPDATE password_reset_tokens
+        SET used_at = now()
+        WHERE token_hash = $1 AND used_at IS NULL AND expires_at > now()
+        RETURNING user_id
+        "#,
+        hmac_token(secret, token)
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+    .context("Failed to consume password reset token.")?;
+
+    if let Some(row) = &row {
+        sqlx::query!(
+            r#"
+            UPDATE password_reset_tokens
+            SET used_at = now()
+            WHERE user_id = $1 AND used_at IS NULL
+            "#,
+            row.user_id
+        )
+        .execute(&mut **transaction)
+        .await
+        .context("Failed to invalidate outstanding password reset tokens.")?;
+    }
+
+    Ok(row.map(|r| r.user_id))
+}
@@ -0,0 +1,274 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use actix_web::{web, HttpRequest};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+/// Failed attempts for one username before the backoff kicks in
+const USERNAME_FREE_ATTEMPTS: i64 = 5;
+/// More generous, several users may share an address (NAT, offices)
+const IP_FREE_ATTEMPTS: i64 = 20;
+/// Failed attempts older than this are forgotten
+const FAILURE_WINDOW: chrono::Duration = chrono::Duration::hours(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(15 * 60);
+
+/// What a lockout is keyed on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockoutScope {
+    Username,
+    IpAddress,
+}
+
+impl std::fmt::Display for LockoutScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Username => f.write_str("username"),
+            Self::IpAddress => f.write_str("ip_address"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Lockout {
+    pub scope: LockoutScope,
+    pub retry_after: Duration,
+}
+
+impl Lockout {
+    /// Rounded up, so clients waiting for it don't run into the lockout again
+    pub fn retry_after_secs(&self) -> u64 {
+        self.retry_after.as_secs() + u64::from(self.retry_after.subsec_nanos() > 0)
+    }
+}
+
+/// Proxies in front of the application, see `ApplicationSettings::trusted_proxies`
+pub struct TrustedProxies(pub Vec<IpAddr>);
+
+/// The address used for per-IP limits. `X-Forwarded-For` is only honoured when the request
+/// comes in through a trusted proxy, anyone else could pick a new address for every attempt.
+pub fn client_ip(request: &HttpRequest) -> String {
+    let Some(peer) = request.peer_addr().map(|address| address.ip()) else {
+        return "unknown".to_string();
+    };
+    let trusted = request
+        .app_data::<web::Data<TrustedProxies>>()
+        .map(|proxies| proxies.0.as_slice())
+        .unwrap_or_default();
+    let forwarded_for = request
+        .headers()
+        .get_all("X-Forwarded-For")
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+
+    forwarded_client(peer, &forwarded_for, trusted).to_string()
+}
+
+/// Every proxy appends the address it got the request from, so walking the list from the
+/// right the first address that isn't one of our proxies is the client. Entries further left
+/// come from the client itself and can't be trusted.
+fn forwarded_client(peer: IpAddr, forwarded_for: &str, trusted: &[IpAddr]) -> IpAddr {
+    let mut client = peer;
+    if !trusted.contains(&client) {
+        return client;
+    }
+    for entry in forwarded_for.rsplit(',') {
+        let Ok(address) = entry.trim().parse::<IpAddr>() else {
+            break;
+        };
+        client = address;
+        if !trusted.contains(&client) {
+            break;
+        }
+    }
+
+    client
+}
+
+/// Usernames are email addresses, casing must not buy an attacker extra attempts
+fn throttle_key(username: &str) -> String {
+    username.trim().to_lowercase()
+}
+
+/// 1s after the last free attempt, doubling with every further failure
+fn backoff(failures: i64, free_attempts: i64) -> Option<Duration> {
+    if failures < free_attempts {
+        return None;
+    }
+    let exponent = (failures - free_attempts).min(20) as u32;
+    Some(Duration::from_secs(2u64.pow(exponent)).min(MAX_BACKOFF))
+}
+
+fn remaining(last_failure: DateTime<Utc>, backoff: Duration) -> Option<Duration> {
+    let locked_until = last_failure + chrono::Duration::from_std(backoff).ok()?;
+    (locked_until - Utc::now()).to_std().ok()
+}
+
+/// Returns the lockout that is currently in place for this username or address, if any
+#[tracing::instrument(name = "Check login throttle", skip(pool))]
+pub async fn check_login_throttle(
+    pool: &PgPool,
+    username: &str,
+    ip_address: &str,
+) -> Result<Option<Lockout>, anyhow::Error> {
+    let window_start = Utc::now() - FAILURE_WINDOW;
+
+    let row = sqlx::query!(
+        r#"
+        SELECT
+            count(*) FILTER (WHERE username = $1) AS "username_failures!",
+            max(attempted_at) FILTER (WHERE username = $1) AS username_last_failure,
+            count(*) FILTER (WHERE ip_address = $2) AS "ip_failures!",
+            max(attempted_at) FILTER (WHERE ip_address = $2) AS ip_last_failure
+        FROM failed_login_attempts
+        WHERE (username = $1 OR ip_address = $2) AND attempted_at > $3
+        "#,
+        throttle_key(username),
+        ip_address,
+        window_start
+    )
+    .fetch_one(pool)
+    .await
+    .context("Failed to count failed login attempts.")?;
+
+    let checks = [
+        (
+            LockoutScope::Username,
+            row.username_failures,
+            row.username_last_failure,
+            USERNAME_FREE_ATTEMPTS,
+        ),
+        (
+            LockoutScope::IpAddress,
+            row.ip_failures,
+            row.ip_last_failure,
+            IP_FREE_ATTEMPTS,
+        ),
+    ];
+
+    // the longer lockout wins if both apply
+    let lockout = checks
+        .into_iter()
+        .filter_map(|(scope, failures, last_failure, free_attempts)| {
+            let retry_after = remaining(last_failure?, backoff(failures, free_attempts)?)?;
+            Some(Lockout { scope, retry_after })
+        })
+        .max_by_key(|lockout| lockout.retry_after);
+
+    Ok(lockout)
+}
+
+#[tracing::instrument(name = "Record failed login", skip(pool))]
+pub async fn record_failed_login(
+    pool: &PgPool,
+    username: &str,
+    ip_address: &str,
+) -> Result<(), anyhow::Error> {
+    // piggyback on failures to forget the ones that don't count anymore
+    sqlx::query!(
+        "DELETE FROM failed_login_attempts WHERE attempted_at <= $1",
+        Utc::now() - FAILURE_WINDOW
+    )
+    .execute(pool)
+    .await
+    .context("Failed to delete outdated failed login attempts.")?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO failed_login_attempts (username, ip_address, attempted_at)
+        VALUES ($1, $2, $3)
+        "#,
+        throttle_key(username),
+        ip_address,
+        Utc::now()
+    )
+    .execute(pool)
+    .await
+    .context("Failed to record a failed login attempt.")?;
+
+    Ok(())
+}
+
+/// A successful login resets the username backoff, the per-IP counter keeps running
+#[tracing::instrument(name = "Clear failed logins", skip(pool))]
+pub async fn clear_failed_logins(pool: &PgPool, username: &str) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        "DELETE FROM failed_login_attempts WHERE username = $1",
+        throttle_key(username)
+    )
+    .execute(pool)
+    .await
+    .context("Failed to clear failed login attempts.")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn there_is_no_backoff_for_the_free_attempts() {
+        assert_eq!(backoff(0, 5), None);
+        assert_eq!(backoff(4, 5), None);
+    }
+
+    #[test]
+    fn the_backoff_doubles_with_every_failure() {
+        assert_eq!(backoff(5, 5), Some(Duration::from_secs(1)));
+        assert_eq!(backoff(6, 5), Some(Duration::from_secs(2)));
+        assert_eq!(backoff(9, 5), Some(Duration::from_secs(16)));
+    }
+
+    #[test]
+    fn the_backoff_is_capped() {
+        assert_eq!(backoff(20, 5), Some(MAX_BACKOFF));
+        assert_eq!(backoff(i64::MAX, 5), Some(MAX_BACKOFF));
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_without_a_trusted_proxy() {
+        assert_eq!(
+            forwarded_client(ip("203.0.113.7"), "10.0.0.1", &[]),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn the_address_added_by_the_trusted_proxy_is_used() {
+        let trusted = [ip("10.1.0.1")];
+        assert_eq!(
+            forwarded_client(ip("10.1.0.1"), "198.51.100.1, 203.0.113.7", &trusted),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn chained_trusted_proxies_are_skipped() {
+        let trusted = [ip("10.1.0.1"), ip("10.1.0.2")];
+        assert_eq!(
+            forwarded_client(ip("10.1.0.1"), "203.0.113.7, 10.1.0.2", &trusted),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn garbage_in_the_header_falls_back_to_the_last_trusted_address() {
+        let trusted = [ip("10.1.0.1")];
+        assert_eq!(
+            forwarded_client(ip("10.1.0.1"), "not-an-address", &trusted),
+            ip("10.1.0.1")
+        );
+    }
+
+    #[test]
+    fn usernames_are_throttled_case_insensitively() {
+        assert_eq!(throttle_key(" Ursula@Example.com"), "ursula@example.com");
+    }
+}
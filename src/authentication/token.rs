@@ -0,0 +1,40 @@
+use hmac::{Hmac, Mac};
+use rand::distributions::{Alphanumeric, Distribution, Slice};
+use rand::{thread_rng, Rng};
+use secrecy::{ExposeSecret, Secret};
+use sha2::{Digest, Sha256};
+
+/// Random alphanumeric token, suitable for links sent by email
+pub(crate) fn generate_token(length: usize) -> String {
+    let mut rng = thread_rng();
+    std::iter::repeat_with(|| rng.sample(Alphanumeric))
+        .map(char::from)
+        .take(length)
+        .collect()
+}
+
+/// Random token from lowercase letters and digits only, easy to read off and type in
+pub(crate) fn generate_lowercase_token(length: usize) -> String {
+    const CHARSET: &[char] = &[
+        'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'm', 'n', 'p', 'q', 'r', 's', 't',
+        'u', 'v', 'w', 'x', 'y', 'z', '2', '3', '4', '5', '6', '7', '8', '9',
+    ];
+    let charset = Slice::new(CHARSET).unwrap();
+
+    charset.sample_iter(thread_rng()).take(length).collect()
+}
+
+/// Tokens are only ever stored as their hash, a leaked table is useless to an attacker
+pub(crate) fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Like `hash_token`, keyed with `hmac_secret`: without the secret a leaked table can't
+/// even be checked against guesses. For the tokens we send out by email.
+pub fn hmac_token(secret: &Secret<String>, token: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.expose_secret().as_bytes())
+        .expect("HMAC takes keys of any length");
+    mac.update(token.as_bytes());
+
+    format!("{:x}", mac.finalize().into_bytes())
+}
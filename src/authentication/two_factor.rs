@@ -0,0 +1,419 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::Context;
+use base64::Engine;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use totp_rs::{Algorithm, TOTP};
+use uuid::Uuid;
+
+use super::token::{generate_lowercase_token, hash_token};
+
+const TOTP_ISSUER: &str = "rust2prod";
+const TOTP_STEP_SECONDS: u64 = 30;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Key used to encrypt the TOTP secrets at rest (base64 encoded, 32 bytes)
+#[derive(Clone)]
+pub struct TotpEncryptionKey(pub Secret<String>);
+
+impl TotpEncryptionKey {
+    /// Checked when the server starts rather than when the first user turns on two-factor
+    pub fn parse(key: Secret<String>) -> Result<Self, anyhow::Error> {
+        let key = Self(key);
+        cipher(&key)?;
+
+        Ok(key)
+    }
+}
+
+/// A TOTP secret of a user, either still being enrolled or already enabled
+pub struct TotpEnrollment {
+    secret: Secret<Vec<u8>>,
+    username: String,
+    pub enabled: bool,
+}
+
+impl TotpEnrollment {
+    fn totp(&self) -> Result<TOTP, anyhow::Error> {
+        TOTP::new(
+            Algorithm::SHA1,
+            6,
+            1,
+            TOTP_STEP_SECONDS,
+            self.secret.expose_secret().clone(),
+            Some(TOTP_ISSUER.to_string()),
+            self.username.clone(),
+        )
+        .context("Failed to build TOTP from the stored secret.")
+    }
+
+    /// `otpauth://` URI understood by authenticator apps
+    pub fn otpauth_uri(&self) -> Result<String, anyhow::Error> {
+        Ok(self.totp()?.get_url())
+    }
+
+    /// Base32 encoded secret, for manual entry into an authenticator app
+    pub fn secret_base32(&self) -> Result<String, anyhow::Error> {
+        Ok(self.totp()?.get_secret_base32())
+    }
+
+    /// QR code of the `otpauth://` URI as base64 encoded png
+    pub fn qr_code_base64(&self) -> Result<String, anyhow::Error> {
+        self.totp()?
+            .get_qr_base64()
+            .map_err(|e| anyhow::anyhow!(e))
+            .context("Failed to render the TOTP QR code.")
+    }
+}
+
+#[tracing::instrument(name = "Get TOTP enrollment", skip(pool, key))]
+pub async fn get_totp_enrollment(
+    pool: &PgPool,
+    key: &TotpEncryptionKey,
+    user_id: Uuid,
+) -> Result<Option<TotpEnrollment>, anyhow::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT username, totp_secret, totp_enabled
+        FROM users
+        WHERE user_id = $1
+        "#,
+        user_id
+    )
+    .fetch_one(pool)
+    .await
+    .context("Failed to retrieve the TOTP secret.")?;
+
+    let Some(encrypted_secret) = row.totp_secret else {
+        return Ok(None);
+    };
+
+    Ok(Some(TotpEnrollment {
+        secret: Secret::new(decrypt_secret(key, user_id, &encrypted_secret)?),
+        username: row.username,
+        enabled: row.totp_enabled,
+    }))
+}
+
+#[tracing::instrument(name = "Check if TOTP is enabled", skip(pool))]
+pub async fn is_totp_enabled(pool: &PgPool, user_id: Uuid) -> Result<bool, anyhow::Error> {
+    let row = sqlx::query!("SELECT totp_enabled FROM users WHERE user_id = $1", user_id)
+        .fetch_one(pool)
+        .await
+        .context("Failed to check whether TOTP is enabled.")?;
+
+    Ok(row.totp_enabled)
+}
+
+/// Generates and stores a fresh, not yet enabled, TOTP secret for the user
+#[tracing::instrument(name = "Begin TOTP enrollment", skip(pool, key))]
+pub async fn begin_totp_enrollment(
+    pool: &PgPool,
+    key: &TotpEncryptionKey,
+    user_id: Uuid,
+) -> Result<(), anyhow::Error> {
+    let secret = totp_rs::Secret::generate_secret()
+        .to_bytes()
+        .map_err(|e| anyhow::anyhow!("{:?}", e))
+        .context("Failed to generate a TOTP secret.")?;
+
+    sqlx::query!(
+        r#"
+        UPDATE users
+        SET totp_secret = $2, totp_enabled = false, totp_last_used_step = NULL
+        WHERE user_id = $1 AND totp_enabled = false
+        "#,
+        user_id,
+        encrypt_secret(key, user_id, &secret)?
+    )
+    .execute(pool)
+    .await
+    .context("Failed to store the TOTP secret.")?;
+
+    Ok(())
+}
+
+/// Checks a code against the stored secret (enabled or not).
+/// Every time step can only be used once, so an observed code can't be replayed.
+#[tracing::instrument(name = "Verify TOTP code", skip(pool, key, code))]
+pub async fn verify_totp_code(
+    pool: &PgPool,
+    key: &TotpEncryptionKey,
+    user_id: Uuid,
+    code: &str,
+) -> Result<bool, anyhow::Error> {
+    let Some(enrollment) = get_totp_enrollment(pool, key, user_id).await? else {
+        return Ok(false);
+    };
+    let totp = enrollment.totp()?;
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .context("System time is before the unix epoch.")?
+        .as_secs();
+    let current_step = now / TOTP_STEP_SECONDS;
+
+    // accept one step of clock drift in each direction
+    let matching_step = [current_step - 1, current_step, current_step + 1]
+        .into_iter()
+        .find(|step| totp.generate(step * TOTP_STEP_SECONDS) == code);
+
+    let Some(step) = matching_step else {
+        return Ok(false);
+    };
+
+    let result = sqlx::query!(
+        r#"
+        UPDATE users
+        SET totp_last_used_step = $2
+        WHERE user_id = $1 AND (totp_last_used_step IS NULL OR totp_last_used_step < $2)
+        "#,
+        user_id,
+        step as i64
+    )
+    .execute(pool)
+    .await
+    .context("Failed to record the used TOTP step.")?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/// Enables TOTP for the user and returns a fresh set of recovery codes,
+/// the codes are shown to the user once and only stored as hashes.
+#[tracing::instrument(name = "Enable TOTP", skip(pool))]
+pub async fn enable_totp(pool: &PgPool, user_id: Uuid) -> Result<Vec<String>, anyhow::Error> {
+    let recovery_codes: Vec<String> = std::iter::repeat_with(|| {
+        let code = generate_lowercase_token(10);
+        format!("{}-{}", &code[..5], &code[5..])
+    })
+    .take(RECOVERY_CODE_COUNT)
+    .collect();
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a postgres connection from the pool")?;
+
+    sqlx::query!(
+        "UPDATE users SET totp_enabled = true WHERE user_id = $1",
+        user_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to enable TOTP.")?;
+
+    sqlx::query!(
+        "DELETE FROM totp_recovery_codes WHERE user_id = $1",
+        user_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to delete old recovery codes.")?;
+
+    for code in &recovery_codes {
+        sqlx::query!(
+            r#"
+            INSERT INTO totp_recovery_codes (code_hash, user_id)
+            VALUES ($1, $2)
+            "#,
+            hash_token(&normalize_recovery_code(code)),
+            user_id
+        )
+        .execute(&mut *transaction)
+        .await
+        .context("Failed to store a recovery code.")?;
+    }
+
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to enable TOTP.")?;
+
+    Ok(recovery_codes)
+}
+
+#[tracing::instrument(name = "Disable TOTP", skip(pool))]
+pub async fn disable_totp(pool: &PgPool, user_id: Uuid) -> Result<(), anyhow::Error> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a postgres connection from the pool")?;
+
+    sqlx::query!(
+        r#"
+        UPDATE users
+        SET totp_secret = NULL, totp_enabled = false, totp_last_used_step = NULL
+        WHERE user_id = $1
+        "#,
+        user_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to disable TOTP.")?;
+
+    sqlx::query!(
+        "DELETE FROM totp_recovery_codes WHERE user_id = $1",
+        user_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to delete recovery codes.")?;
+
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to disable TOTP.")?;
+
+    Ok(())
+}
+
+/// Marks a recovery code as used, returns false if it is unknown or was used before
+#[tracing::instrument(name = "Use recovery code", skip(pool, code))]
+pub async fn use_recovery_code(
+    pool: &PgPool,
+    user_id: Uuid,
+    code: &str,
+) -> Result<bool, anyhow::Error> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE totp_recovery_codes
+        SET used_at = now()
+        WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+        "#,
+        user_id,
+        hash_token(&normalize_recovery_code(code))
+    )
+    .execute(pool)
+    .await
+    .context("Failed to use a recovery code.")?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/// Accepts either a current TOTP code or an unused recovery code
+pub async fn verify_second_factor(
+    pool: &PgPool,
+    key: &TotpEncryptionKey,
+    user_id: Uuid,
+    code: &str,
+) -> Result<bool, anyhow::Error> {
+    let code = code.trim();
+
+    if code.len() == 6 && code.chars().all(|c| c.is_ascii_digit()) {
+        verify_totp_code(pool, key, user_id, code).await
+    } else {
+        use_recovery_code(pool, user_id, code).await
+    }
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn cipher(key: &TotpEncryptionKey) -> Result<Aes256Gcm, anyhow::Error> {
+    let key_bytes = base64::engine::general_purpose::STANDARD
+        .decode(key.0.expose_secret())
+        .context("Failed to decode the TOTP encryption key as base64.")?;
+
+    anyhow::ensure!(
+        key_bytes.len() == 32,
+        "The TOTP encryption key must be 32 bytes long."
+    );
+
+    Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key_bytes)))
+}
+
+// the user id is bound as associated data, a secret can't be moved to another user
+fn encrypt_secret(
+    key: &TotpEncryptionKey,
+    user_id: Uuid,
+    secret: &[u8],
+) -> Result<String, anyhow::Error> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher(key)?
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: secret,
+                aad: user_id.as_bytes(),
+            },
+        )
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt the TOTP secret."))?;
+
+    let mut bytes = nonce.to_vec();
+    bytes.extend(ciphertext);
+
+    Ok(base64::engine::general_purpose::STANDARD.encode(bytes))
+}
+
+fn decrypt_secret(
+    key: &TotpEncryptionKey,
+    user_id: Uuid,
+    encrypted_secret: &str,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(encrypted_secret)
+        .context("Failed to decode the stored TOTP secret.")?;
+
+    anyhow::ensure!(bytes.len() > 12, "The stored TOTP secret is too short.");
+    let (nonce, ciphertext) = bytes.split_at(12);
+
+    cipher(key)?
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: user_id.as_bytes(),
+            },
+        )
+        .map_err(|_| anyhow::anyhow!("Failed to decrypt the stored TOTP secret."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decrypt_secret, encrypt_secret, normalize_recovery_code, TotpEncryptionKey};
+    use claims::{assert_err, assert_ok_eq};
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    fn key() -> TotpEncryptionKey {
+        TotpEncryptionKey(Secret::new(
+            "TWn6ExdWEawAf3q5ENYoztOUxhLClE0jTqYedb9wytM=".to_string(),
+        ))
+    }
+
+    #[test]
+    fn an_encrypted_secret_can_be_decrypted() {
+        let user_id = Uuid::new_v4();
+        let encrypted = encrypt_secret(&key(), user_id, b"top secret").unwrap();
+
+        assert_ok_eq!(
+            decrypt_secret(&key(), user_id, &encrypted),
+            b"top secret".to_vec()
+        );
+    }
+
+    #[test]
+    fn an_encrypted_secret_is_bound_to_its_user() {
+        let encrypted = encrypt_secret(&key(), Uuid::new_v4(), b"top secret").unwrap();
+
+        assert_err!(decrypt_secret(&key(), Uuid::new_v4(), &encrypted));
+    }
+
+    #[test]
+    fn keys_must_be_32_bytes_of_base64() {
+        assert!(TotpEncryptionKey::parse(key().0).is_ok());
+        for invalid in ["", "not base64!", "c2hvcnQ="] {
+            assert!(TotpEncryptionKey::parse(Secret::new(invalid.to_string())).is_err());
+        }
+    }
+
+    #[test]
+    fn recovery_codes_are_normalized() {
+        assert_eq!(normalize_recovery_code(" ABcde-fgh23 "), "abcdefgh23");
+    }
+}
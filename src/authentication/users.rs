@@ -0,0 +1,125 @@
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use secrecy::ExposeSecret;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::password::{compute_password_hash, PasswordHashing};
+use super::Role;
+use crate::domain::{NewPassword, SubscriberEmail};
+use crate::session_store::revoke_user_sessions;
+use crate::telemetry::spawn_blocking_with_tracing;
+
+pub struct UserInfo {
+    pub user_id: Uuid,
+    pub username: String,
+    pub role: String,
+    pub totp_enabled: bool,
+    pub disabled_at: Option<DateTime<Utc>>,
+}
+
+/// Usernames are email addresses, they are the address password reset links go to
+#[tracing::instrument(name = "Create user", skip(password, hashing, pool))]
+pub async fn create_user(
+    pool: &PgPool,
+    username: &str,
+    password: NewPassword,
+    role: Role,
+    hashing: &PasswordHashing,
+) -> Result<Uuid, anyhow::Error> {
+    let username = SubscriberEmail::parse(username.to_string()).map_err(anyhow::Error::msg)?;
+    let params = hashing.params();
+    let password_hash =
+        spawn_blocking_with_tracing(move || compute_password_hash(password, params))
+            .await?
+            .context("Failed to hash password.")?;
+
+    let user_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO users (user_id, username, password_hash, role)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        user_id,
+        username.as_ref(),
+        password_hash.expose_secret(),
+        role.as_str()
+    )
+    .execute(pool)
+    .await
+    .context("Failed to store a new user.")?;
+
+    Ok(user_id)
+}
+
+#[tracing::instrument(name = "List users", skip(pool))]
+pub async fn list_users(pool: &PgPool) -> Result<Vec<UserInfo>, anyhow::Error> {
+    let users = sqlx::query_as!(
+        UserInfo,
+        r#"
+        SELECT user_id, username, role, totp_enabled, disabled_at
+        FROM users
+        ORDER BY username
+        "#
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to list users.")?;
+
+    Ok(users)
+}
+
+#[tracing::instrument(name = "Set role", skip(pool))]
+pub async fn set_role(pool: &PgPool, user_id: Uuid, role: Role) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        "UPDATE users SET role = $1 WHERE user_id = $2",
+        role.as_str(),
+        user_id
+    )
+    .execute(pool)
+    .await
+    .context("Failed to change the role of a user.")?;
+
+    Ok(())
+}
+
+/// Disabled users can't log in or use their API tokens, their sessions are revoked right away
+#[tracing::instrument(name = "Disable user", skip(pool))]
+pub async fn disable_user(pool: &PgPool, user_id: Uuid) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        "UPDATE users SET disabled_at = now() WHERE user_id = $1 AND disabled_at IS NULL",
+        user_id
+    )
+    .execute(pool)
+    .await
+    .context("Failed to disable a user.")?;
+
+    revoke_user_sessions(pool, user_id).await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "Enable user", skip(pool))]
+pub async fn enable_user(pool: &PgPool, user_id: Uuid) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        "UPDATE users SET disabled_at = NULL WHERE user_id = $1",
+        user_id
+    )
+    .execute(pool)
+    .await
+    .context("Failed to enable a user.")?;
+
+    Ok(())
+}
+
+/// Unlike [`get_user_id`](super::get_user_id) this also finds disabled users
+#[tracing::instrument(name = "Find user", skip(pool))]
+pub async fn find_user(pool: &PgPool, username: &str) -> Result<Option<Uuid>, anyhow::Error> {
+    let user_id = sqlx::query!("SELECT user_id FROM users WHERE username = $1", username)
+        .fetch_optional(pool)
+        .await
+        .context("Failed to look up a user.")?
+        .map(|row| row.user_id);
+
+    Ok(user_id)
+}
@@ -0,0 +1,267 @@
+use std::io::{BufRead, IsTerminal};
+
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+use secrecy::Secret;
+use sqlx::PgPool;
+
+use crate::authentication::{
+    self, create_api_token, create_user, disable_user, enable_user, find_user, list_users,
+    set_role, ApiScope, PasswordHashing, Role,
+};
+use crate::configuration::Settings;
+use crate::domain::NewPassword;
+use crate::routes::hash_legacy_subscription_tokens;
+use crate::session_store::revoke_user_sessions;
+use crate::startup::{get_connection_pool, Application};
+use crate::topics::{create_topic, list_topics};
+
+/// Runs the newsletter service and lets operators manage it without writing SQL
+#[derive(Parser)]
+#[command(name = "rust2prod", version)]
+pub struct Cli {
+    /// Starts the server if left out
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Start the web server
+    Serve,
+    /// Apply pending database migrations
+    Migrate,
+    /// Manage the users of the admin area
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Manage API tokens
+    #[command(subcommand)]
+    Token(TokenCommand),
+    /// Manage the topics issues can be published under
+    #[command(subcommand)]
+    Topic(TopicCommand),
+}
+
+#[derive(Subcommand)]
+pub enum UserCommand {
+    /// Create a user, the password is read from stdin
+    Create {
+        /// Email address of the user
+        username: String,
+        #[arg(long, default_value = "viewer", value_parser = parse_role)]
+        role: Role,
+    },
+    /// List all users
+    List,
+    /// Set a new password, read from stdin
+    SetPassword { username: String },
+    /// Change the role of a user
+    SetRole {
+        username: String,
+        #[arg(value_parser = parse_role)]
+        role: Role,
+    },
+    /// Block logins and API tokens of a user and end all of their sessions
+    Disable { username: String },
+    /// Undo `disable`
+    Enable { username: String },
+}
+
+#[derive(Subcommand)]
+pub enum TokenCommand {
+    /// Issue an API token for a user and print it
+    Issue {
+        username: String,
+        /// Shown in the admin area
+        #[arg(long)]
+        name: String,
+        /// Can be given multiple times
+        #[arg(long = "scope", required = true, value_parser = parse_scope)]
+        scopes: Vec<ApiScope>,
+        /// At most ten years
+        #[arg(long, default_value_t = 90, value_parser = clap::value_parser!(i64).range(1..=3650))]
+        expires_in_days: i64,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum TopicCommand {
+    /// Add a topic, subscribers receive it until they opt out
+    Create { name: String },
+    /// List all topics
+    List,
+}
+
+fn parse_role(s: &str) -> Result<Role, String> {
+    Role::try_from(s)
+}
+
+fn parse_scope(s: &str) -> Result<ApiScope, String> {
+    ApiScope::try_from(s)
+}
+
+impl Command {
+    /// The server logs to stdout, everything else keeps stdout for its output
+    pub fn logs_to_stdout(&self) -> bool {
+        matches!(self, Command::Serve)
+    }
+}
+
+pub async fn run(command: Command, configuration: Settings) -> Result<(), anyhow::Error> {
+    let pool = || get_connection_pool(&configuration.database);
+
+    match command {
+        Command::Serve => {
+            let application = Application::build(configuration.clone()).await?;
+            application.run_until_stopped().await?;
+        }
+        Command::Migrate => {
+            let pool = pool();
+            sqlx::migrate!("./migrations")
+                .run(&pool)
+                .await
+                .context("Failed to migrate the database.")?;
+            // SQL can't compute the keyed hash, tokens from before hashing are converted here
+            let hashed =
+                hash_legacy_subscription_tokens(&pool, &configuration.application.hmac_secret)
+                    .await?;
+            if hashed > 0 {
+                println!(
+                    "Hashed {} subscription tokens stored in plain text.",
+                    hashed
+                );
+            }
+            println!("The database is up to date.");
+        }
+        Command::User(command) => {
+            let hashing = PasswordHashing::new(&configuration.application.password_hashing)?;
+            run_user_command(command, &hashing, &pool()).await?
+        }
+        Command::Token(command) => run_token_command(command, &pool()).await?,
+        Command::Topic(command) => run_topic_command(command, &pool()).await?,
+    }
+
+    Ok(())
+}
+
+async fn run_user_command(
+    command: UserCommand,
+    hashing: &PasswordHashing,
+    pool: &PgPool,
+) -> Result<(), anyhow::Error> {
+    match command {
+        UserCommand::Create { username, role } => {
+            let password = read_new_password()?;
+            let user_id = create_user(pool, &username, password, role, hashing).await?;
+            println!("Created {} ({}) with id {}.", username, role, user_id);
+        }
+        UserCommand::List => {
+            for user in list_users(pool).await? {
+                println!(
+                    "{}\t{}\t{}\t2fa: {}\t{}",
+                    user.user_id,
+                    user.username,
+                    user.role,
+                    if user.totp_enabled { "on" } else { "off" },
+                    if user.disabled_at.is_some() {
+                        "disabled"
+                    } else {
+                        "active"
+                    }
+                );
+            }
+        }
+        UserCommand::SetPassword { username } => {
+            let user_id = existing_user(pool, &username).await?;
+            let password = read_new_password()?;
+            authentication::change_password(user_id, password, hashing, pool).await?;
+            // same as a change in the admin area, sessions with the old password end
+            let n_sessions = revoke_user_sessions(pool, user_id).await?;
+            println!(
+                "Changed the password of {} and ended {} sessions.",
+                username, n_sessions
+            );
+        }
+        UserCommand::SetRole { username, role } => {
+            let user_id = existing_user(pool, &username).await?;
+            set_role(pool, user_id, role).await?;
+            println!("{} is now {}.", username, role);
+        }
+        UserCommand::Disable { username } => {
+            let user_id = existing_user(pool, &username).await?;
+            disable_user(pool, user_id).await?;
+            println!("Disabled {}.", username);
+        }
+        UserCommand::Enable { username } => {
+            let user_id = existing_user(pool, &username).await?;
+            enable_user(pool, user_id).await?;
+            println!("Enabled {}.", username);
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_token_command(command: TokenCommand, pool: &PgPool) -> Result<(), anyhow::Error> {
+    match command {
+        TokenCommand::Issue {
+            username,
+            name,
+            scopes,
+            expires_in_days,
+        } => {
+            let user_id = existing_user(pool, &username).await?;
+            let expires_at = chrono::Utc::now() + chrono::Duration::days(expires_in_days);
+            let token = create_api_token(pool, user_id, &name, &scopes, expires_at).await?;
+            // the token alone on stdout, so it can be piped into a secret store
+            println!("{}", token);
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_topic_command(command: TopicCommand, pool: &PgPool) -> Result<(), anyhow::Error> {
+    match command {
+        TopicCommand::Create { name } => {
+            let topic_id = create_topic(pool, &name).await?;
+            println!("Created topic {} with id {}.", name.trim(), topic_id);
+        }
+        TopicCommand::List => {
+            for topic in list_topics(pool).await? {
+                println!("{}\t{}", topic.topic_id, topic.name);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn existing_user(pool: &PgPool, username: &str) -> Result<uuid::Uuid, anyhow::Error> {
+    find_user(pool, username)
+        .await?
+        .with_context(|| format!("There is no user {}.", username))
+}
+
+/// Read from stdin instead of an argument, so passwords don't end up in the shell history.
+/// On a terminal it isn't echoed and has to be entered twice.
+fn read_new_password() -> Result<NewPassword, anyhow::Error> {
+    let stdin = std::io::stdin();
+    let password = if stdin.is_terminal() {
+        let password = rpassword::prompt_password("Password: ")
+            .context("Failed to read the password from the terminal.")?;
+        let password_check = rpassword::prompt_password("Repeat the password: ")
+            .context("Failed to read the password from the terminal.")?;
+        anyhow::ensure!(password == password_check, "The passwords don't match.");
+        password
+    } else {
+        let mut password = String::new();
+        stdin
+            .lock()
+            .read_line(&mut password)
+            .context("Failed to read the password from stdin.")?;
+        password.trim_end_matches(['\r', '\n']).to_string()
+    };
+
+    NewPassword::parse(Secret::new(password)).map_err(anyhow::Error::msg)
+}
@@ -1,9 +1,15 @@
 use crate::domain::SubscriberEmail;
+use crate::email_client::{
+    EmailSender, FileEmailSender, HttpEmailSender, RateLimiter, RetryPolicy, SmtpEmailSender,
+    SmtpTls,
+};
 use config::Config;
 use secrecy::{ExposeSecret, Secret};
 use serde_aux::field_attributes::deserialize_number_from_string;
 use sqlx::postgres::{PgConnectOptions, PgSslMode};
 use sqlx::ConnectOptions;
+use std::net::IpAddr;
+use std::sync::Arc;
 
 #[derive(serde::Deserialize, Clone)]
 pub struct Settings {
@@ -19,6 +25,22 @@ pub struct ApplicationSettings {
     pub host: String,
     pub base_url: String,
     pub hmac_secret: Secret<String>,
+    pub totp_encryption_key: Secret<String>,
+    pub password_hashing: PasswordHashingSettings,
+    /// Load balancers whose `X-Forwarded-For` is believed, the header is ignored for everyone else
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+/// Argon2id cost for new password hashes, outdated hashes are upgraded on login
+#[derive(serde::Deserialize, Clone)]
+pub struct PasswordHashingSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub memory_kib: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub iterations: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub parallelism: u32,
 }
 
 #[derive(serde::Deserialize, Clone)]
@@ -34,10 +56,69 @@ pub struct DatabaseSettings {
 
 #[derive(serde::Deserialize, Clone)]
 pub struct EmailClientSettings {
+    #[serde(default)]
+    pub kind: EmailClientKind,
     pub base_url: String,
     pub sender_email: String,
     pub authorization_token: Secret<String>,
     pub timeout_milliseconds: u64,
+    pub retry: RetrySettings,
+    pub rate_limit: RateLimitSettings,
+    /// How many deliveries the issue delivery worker has in flight at the same time
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub concurrency: usize,
+    /// Required with `kind: smtp`
+    pub smtp: Option<SmtpSettings>,
+    /// Required with `kind: file`
+    pub file: Option<FileSettings>,
+}
+
+/// Which backend delivers our emails
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailClientKind {
+    /// Resend style HTTP API at `base_url`, authenticated with `authorization_token`
+    #[default]
+    Http,
+    Smtp,
+    /// Writes `.eml` files instead of sending anything
+    File,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct SmtpSettings {
+    pub host: String,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub port: u16,
+    pub tls: SmtpTls,
+    /// `AUTH` is skipped without a username
+    pub username: Option<String>,
+    pub password: Option<Secret<String>>,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct FileSettings {
+    pub directory: String,
+}
+
+/// Retries of transient email provider failures, with exponential backoff
+#[derive(serde::Deserialize, Clone)]
+pub struct RetrySettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_attempts: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub base_delay_milliseconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_delay_milliseconds: u64,
+}
+
+/// Token bucket in front of the email provider, the rate adapts to 429 responses
+#[derive(serde::Deserialize, Clone)]
+pub struct RateLimitSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub messages_per_second: f64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub burst: u32,
 }
 
 pub enum Environment {
@@ -101,6 +182,69 @@ impl EmailClientSettings {
     pub fn timeout(&self) -> std::time::Duration {
         std::time::Duration::from_millis(self.timeout_milliseconds)
     }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.retry.max_attempts.max(1),
+            base_delay: std::time::Duration::from_millis(self.retry.base_delay_milliseconds),
+            max_delay: std::time::Duration::from_millis(self.retry.max_delay_milliseconds),
+        }
+    }
+
+    pub fn rate_limiter(&self) -> RateLimiter {
+        RateLimiter::new(self.rate_limit.messages_per_second, self.rate_limit.burst)
+    }
+
+    pub fn client(self) -> Arc<dyn EmailSender> {
+        let sender_email = self.sender().expect("Invalid sender email address");
+        let timeout = self.timeout();
+        let retry_policy = self.retry_policy();
+        let rate_limiter = self.rate_limiter();
+
+        match self.kind {
+            EmailClientKind::Http => Arc::new(
+                HttpEmailSender::new(
+                    self.base_url,
+                    sender_email,
+                    self.authorization_token,
+                    timeout,
+                    retry_policy,
+                )
+                .with_rate_limiter(rate_limiter),
+            ),
+            EmailClientKind::Smtp => {
+                let smtp = self.smtp.expect("Missing email_client.smtp settings");
+                let credentials = smtp.username.map(|username| {
+                    (
+                        username,
+                        smtp.password.unwrap_or_else(|| Secret::new(String::new())),
+                    )
+                });
+
+                Arc::new(
+                    SmtpEmailSender::new(
+                        &smtp.host,
+                        smtp.port,
+                        smtp.tls,
+                        credentials,
+                        sender_email,
+                        timeout,
+                        retry_policy,
+                    )
+                    .expect("Invalid email_client.smtp settings")
+                    .with_rate_limiter(rate_limiter),
+                )
+            }
+            EmailClientKind::File => {
+                let file = self.file.expect("Missing email_client.file settings");
+
+                Arc::new(
+                    FileEmailSender::new(file.directory, sender_email)
+                        .expect("Failed to create the email directory"),
+                )
+            }
+        }
+    }
 }
 
 pub fn get_configuration() -> Result<Settings, config::ConfigError> {
@@ -0,0 +1,224 @@
+use std::future::{ready, Ready};
+use std::pin::Pin;
+
+use actix_web::body::MessageBody;
+use actix_web::cookie::{Cookie, CookieJar, Key, SameSite};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::error::{InternalError, PayloadError};
+use actix_web::http::{header, Method};
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use actix_web_lab::middleware::Next;
+use futures_util::Stream;
+use rand::distributions::Alphanumeric;
+use rand::{rngs::OsRng, Rng};
+
+use crate::utils::e500;
+
+const CSRF_COOKIE: &str = "csrf_token";
+/// htmx sends it through `hx-headers` on the body
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+/// plain forms send it as a hidden input
+pub const CSRF_FORM_FIELD: &str = "csrf_token";
+
+/// Endpoints that don't rely on cookies, so there is nothing to forge:
+/// the API authenticates every request itself, signing up goes through double opt-in,
+/// unsubscribing takes the signed token from the email
+const EXEMPT_PATHS: [&str; 3] = [
+    "/newsletters",
+    "/subscriptions",
+    "/subscriptions/unsubscribe",
+];
+
+/// Key for the signed CSRF cookie, derived from the same secret as the session cookie
+#[derive(Clone)]
+pub struct CsrfKey(pub Key);
+
+/// Token of the current client, put it into every form and htmx request
+#[derive(Clone)]
+pub struct CsrfToken(String);
+
+impl CsrfToken {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromRequest for CsrfToken {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<CsrfToken, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<CsrfToken>()
+                .cloned()
+                .ok_or_else(|| e500("No CSRF token, is protect_against_csrf in place?")),
+        )
+    }
+}
+
+/// Signed double-submit cookie: every unsafe request has to echo the value of the
+/// cookie in a header or form field, which other origins can neither read nor forge
+pub async fn protect_against_csrf(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let key = req
+        .app_data::<web::Data<CsrfKey>>()
+        .cloned()
+        .ok_or_else(|| e500("No CSRF key in the application data."))?;
+
+    let existing_token = req
+        .cookie(CSRF_COOKIE)
+        .and_then(|cookie| verify_cookie(&key.0, cookie));
+
+    if needs_protection(&req) {
+        let submitted_token = submitted_token(&mut req).await?;
+        let valid = match (&existing_token, &submitted_token) {
+            (Some(expected), Some(submitted)) => constant_time_eq(expected, submitted),
+            _ => false,
+        };
+
+        if !valid {
+            tracing::warn!(
+                path = req.path(),
+                has_cookie = existing_token.is_some(),
+                has_token = submitted_token.is_some(),
+                "Rejected a request with a missing or mismatched CSRF token."
+            );
+            let response = HttpResponse::Forbidden().body("CSRF token missing or invalid.");
+            let e = anyhow::anyhow!("CSRF token missing or invalid");
+            return Err(InternalError::from_response(e, response).into());
+        }
+    }
+
+    let new_token = existing_token.is_none().then(generate_token);
+    let token = existing_token.or_else(|| new_token.clone()).unwrap();
+    req.extensions_mut().insert(CsrfToken(token));
+
+    let mut response = next.call(req).await?;
+    if let Some(token) = new_token {
+        response
+            .response_mut()
+            .add_cookie(&signed_cookie(&key.0, token))
+            .map_err(e500)?;
+    }
+
+    Ok(response)
+}
+
+fn needs_protection(req: &ServiceRequest) -> bool {
+    let safe_method = matches!(
+        *req.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    );
+
+    !safe_method && !EXEMPT_PATHS.contains(&req.path())
+}
+
+/// The header wins, form bodies are only read when there is none
+async fn submitted_token(req: &mut ServiceRequest) -> Result<Option<String>, actix_web::Error> {
+    if let Some(value) = req.headers().get(CSRF_HEADER) {
+        return Ok(value.to_str().ok().map(str::to_string));
+    }
+
+    let is_form = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+    if !is_form {
+        return Ok(None);
+    }
+
+    let body = req.extract::<web::Bytes>().await?;
+    let token = serde_urlencoded::from_bytes::<Vec<(String, String)>>(&body)
+        .ok()
+        .and_then(|fields| {
+            fields
+                .into_iter()
+                .find(|(name, _)| name == CSRF_FORM_FIELD)
+                .map(|(_, value)| value)
+        });
+
+    // put the body back for the handler
+    let stream: Pin<Box<dyn Stream<Item = Result<web::Bytes, PayloadError>>>> =
+        Box::pin(futures_util::stream::once(async move { Ok(body) }));
+    req.set_payload(Payload::Stream { payload: stream });
+
+    Ok(token)
+}
+
+fn signed_cookie(key: &Key, token: String) -> Cookie<'static> {
+    let cookie = Cookie::build(CSRF_COOKIE, token)
+        .path("/")
+        .secure(true)
+        .http_only(true)
+        // Lax, so arriving from a link in an email keeps the token of other open tabs valid
+        .same_site(SameSite::Lax)
+        .finish();
+
+    let mut jar = CookieJar::new();
+    jar.signed_mut(key).add(cookie);
+    jar.get(CSRF_COOKIE)
+        .expect("The cookie was just added")
+        .clone()
+        .into_owned()
+}
+
+fn verify_cookie(key: &Key, cookie: Cookie<'static>) -> Option<String> {
+    let mut jar = CookieJar::new();
+    jar.add_original(cookie);
+    jar.signed(key)
+        .get(CSRF_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+}
+
+// same recipe as the session keys
+fn generate_token() -> String {
+    std::iter::repeat_with(|| OsRng.sample(Alphanumeric))
+        .map(char::from)
+        .take(32)
+        .collect()
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_cookies_can_be_verified() {
+        let key = Key::generate();
+
+        let cookie = signed_cookie(&key, "token".into());
+
+        assert_ne!(cookie.value(), "token");
+        assert_eq!(verify_cookie(&key, cookie), Some("token".into()));
+    }
+
+    #[test]
+    fn unsigned_or_foreign_cookies_are_rejected() {
+        let key = Key::generate();
+
+        let unsigned = Cookie::new(CSRF_COOKIE, "token");
+        let foreign = signed_cookie(&Key::generate(), "token".into());
+
+        assert_eq!(verify_cookie(&key, unsigned), None);
+        assert_eq!(verify_cookie(&key, foreign), None);
+    }
+
+    #[test]
+    fn tokens_are_compared_in_full() {
+        assert!(constant_time_eq("abc", "abc"));
+        assert!(!constant_time_eq("abc", "abd"));
+        assert!(!constant_time_eq("abc", "ab"));
+    }
+}
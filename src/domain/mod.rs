@@ -1,8 +1,9 @@
-
+mod new_password;
 mod new_subscriber;
-mod subscriber_name;
 mod subscriber_email;
+mod subscriber_name;
 
+pub use new_password::NewPassword;
 pub use new_subscriber::NewSubscriber;
-pub use subscriber_name::SubscriberName;
 pub use subscriber_email::SubscriberEmail;
+pub use subscriber_name::SubscriberName;
@@ -0,0 +1,90 @@
+use secrecy::{ExposeSecret, Secret};
+use unicode_segmentation::UnicodeSegmentation;
+
+#[derive(Debug)]
+pub struct NewPassword(Secret<String>);
+
+impl NewPassword {
+    /// returns a new instance of NewPassword when the input satisfies the password policy
+    pub fn parse(password: Secret<String>) -> Result<NewPassword, String> {
+        let candidate = password.expose_secret();
+        let length = candidate.graphemes(true).count();
+
+        if length < 12 {
+            return Err("The new password must be at least 12 characters long.".into());
+        }
+        if length > 128 {
+            return Err("The new password must be at most 128 characters long.".into());
+        }
+
+        // at least three out of four character classes
+        let character_classes = [
+            candidate.chars().any(|c| c.is_lowercase()),
+            candidate.chars().any(|c| c.is_uppercase()),
+            candidate.chars().any(|c| c.is_numeric()),
+            candidate.chars().any(|c| !c.is_alphanumeric()),
+        ]
+        .into_iter()
+        .filter(|present| *present)
+        .count();
+
+        if character_classes < 3 {
+            return Err(
+                "The new password must mix at least three of: lowercase letters, \
+                uppercase letters, digits and symbols."
+                    .into(),
+            );
+        }
+
+        Ok(Self(password))
+    }
+}
+
+impl ExposeSecret<String> for NewPassword {
+    fn expose_secret(&self) -> &String {
+        self.0.expose_secret()
+    }
+}
+
+impl From<NewPassword> for Secret<String> {
+    fn from(password: NewPassword) -> Self {
+        password.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::NewPassword;
+    use claims::{assert_err, assert_ok};
+    use secrecy::Secret;
+
+    fn parse(password: &str) -> Result<NewPassword, String> {
+        NewPassword::parse(Secret::new(password.to_string()))
+    }
+
+    #[test]
+    fn a_long_password_with_mixed_characters_is_valid() {
+        assert_ok!(parse("correct-Horse-battery"));
+    }
+
+    #[test]
+    fn a_password_shorter_than_12_graphemes_is_rejected() {
+        assert_err!(parse("sh0rt-Pass"));
+    }
+
+    #[test]
+    fn a_password_longer_than_128_graphemes_is_rejected() {
+        let password = format!("aA1-{}", "a".repeat(125));
+        assert_err!(parse(&password));
+    }
+
+    #[test]
+    fn a_password_with_a_single_character_class_is_rejected() {
+        assert_err!(parse("correcthorsebatterystaple"));
+    }
+
+    #[test]
+    fn a_password_with_two_character_classes_is_rejected() {
+        assert_err!(parse("correcthorsebattery42"));
+    }
+}
@@ -1,4 +1,3 @@
-
 use unicode_segmentation::UnicodeSegmentation;
 
 #[derive(Debug)]
@@ -9,8 +8,12 @@ impl SubscriberName {
     pub fn parse(name: String) -> Result<SubscriberName, String> {
         let is_empty_or_whitespace = name.trim().is_empty();
         let is_too_long = name.graphemes(true).count() > 256;
-        let forbidden_characters = ['/', '(', ')', '"', '<', '>', '\\', '&', ':', ';', '@', ',', '.'];
-        let contains_forbidden_characters = name.chars().any(|char| forbidden_characters.contains(&char));
+        let forbidden_characters = [
+            '/', '(', ')', '"', '<', '>', '\\', '&', ':', ';', '@', ',', '.',
+        ];
+        let contains_forbidden_characters = name
+            .chars()
+            .any(|char| forbidden_characters.contains(&char));
 
         if is_empty_or_whitespace || is_too_long || contains_forbidden_characters {
             Err(format!("Invalid subscriber name: {}", name))
@@ -26,11 +29,10 @@ impl AsRef<str> for SubscriberName {
     }
 }
 
-
 #[cfg(test)]
 mod tests {
     use crate::domain::SubscriberName;
-    use claims::{assert_ok, assert_err};
+    use claims::{assert_err, assert_ok};
 
     #[test]
     fn a_256_grapheme_long_name_is_valid() {
@@ -58,9 +60,11 @@ mod tests {
 
     #[test]
     fn name_with_forbidden_characters_is_rejected() {
-        for name in &['/', '(', ')', '"', '<', '>', '\\', '&', ':', ';', '@', ',', '.'] {
+        for name in &[
+            '/', '(', ')', '"', '<', '>', '\\', '&', ':', ';', '@', ',', '.',
+        ] {
             let name = name.to_string();
             assert_err!(SubscriberName::parse(name));
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,199 @@
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use lettre::{AsyncFileTransport, AsyncTransport, Tokio1Executor};
+use uuid::Uuid;
+
+use super::{build_message, Email, EmailSender, SendEmailError};
+use crate::domain::SubscriberEmail;
+
+/// Writes every email as `<id>.eml` into a directory instead of sending it, for local development
+pub struct FileEmailSender {
+    sender: SubscriberEmail,
+    directory: PathBuf,
+    transport: AsyncFileTransport<Tokio1Executor>,
+}
+
+impl FileEmailSender {
+    pub fn new(directory: impl AsRef<Path>, sender: SubscriberEmail) -> std::io::Result<Self> {
+        let directory = directory.as_ref().to_owned();
+        std::fs::create_dir_all(&directory)?;
+
+        Ok(Self {
+            sender,
+            transport: AsyncFileTransport::new(&directory),
+            directory,
+        })
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+}
+
+#[async_trait]
+impl EmailSender for FileEmailSender {
+    /// The id is the name of the written file, without `.eml`
+    async fn send(&self, email: &Email<'_>) -> Result<Option<String>, SendEmailError> {
+        let message_id = format!("<{}@localhost>", Uuid::new_v4());
+        let message =
+            build_message(message_id, &self.sender, email).map_err(SendEmailError::Rejected)?;
+
+        let file_id =
+            self.transport
+                .send(message)
+                .await
+                .map_err(|e| SendEmailError::Unavailable {
+                    attempts: 1,
+                    source: e.into(),
+                })?;
+
+        Ok(Some(file_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{Email, EmailSender, FileEmailSender, SendEmailError};
+    use claims::assert_matches;
+    use uuid::Uuid;
+
+    fn email(address: &str) -> SubscriberEmail {
+        SubscriberEmail::parse(address.to_string()).unwrap()
+    }
+
+    fn email_directory() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("rust2prod-emails-{}", Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn send_email_writes_an_eml_file() {
+        // arrange
+        let directory = email_directory();
+        let email_sender = FileEmailSender::new(&directory, email("sender@example.com")).unwrap();
+
+        // act
+        let file_id = email_sender
+            .send_email(
+                &email("ursula@example.com"),
+                "Welcome!",
+                "<p>Hello <b>there</b></p>",
+                "Hello there",
+            )
+            .await
+            .unwrap()
+            .unwrap();
+
+        // assert
+        let eml = std::fs::read_to_string(directory.join(format!("{}.eml", file_id))).unwrap();
+        assert!(eml.contains("From: sender@example.com"));
+        assert!(eml.contains("To: ursula@example.com"));
+        assert!(eml.contains("Subject: Welcome!"));
+        assert!(eml.contains("Content-Type: multipart/alternative"));
+        assert!(eml.contains("Hello there"));
+        assert!(eml.contains("<p>Hello <b>there</b></p>"));
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn extra_headers_end_up_in_the_eml_file() {
+        // arrange
+        let directory = email_directory();
+        let email_sender = FileEmailSender::new(&directory, email("sender@example.com")).unwrap();
+
+        // act
+        let file_id = email_sender
+            .send(&Email {
+                to: &email("ursula@example.com"),
+                subject: "Newsletter",
+                html_content: "<p>Content</p>",
+                text_content: "Content",
+                headers: &[
+                    (
+                        "List-Unsubscribe",
+                        "<https://example.com/unsubscribe>".into(),
+                    ),
+                    ("List-Unsubscribe-Post", "List-Unsubscribe=One-Click".into()),
+                ],
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        // assert
+        let eml = std::fs::read_to_string(directory.join(format!("{}.eml", file_id))).unwrap();
+        assert!(eml.contains("List-Unsubscribe: <https://example.com/unsubscribe>"));
+        assert!(eml.contains("List-Unsubscribe-Post: List-Unsubscribe=One-Click"));
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_directory_is_created_when_missing() {
+        // arrange
+        let directory = email_directory().join("nested");
+
+        // act
+        let email_sender = FileEmailSender::new(&directory, email("sender@example.com")).unwrap();
+        email_sender
+            .send_email(&email("ursula@example.com"), "Subject", "<p>Hi</p>", "Hi")
+            .await
+            .unwrap();
+
+        // assert
+        assert_eq!(
+            std::fs::read_dir(email_sender.directory()).unwrap().count(),
+            1
+        );
+
+        std::fs::remove_dir_all(directory.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_vanished_directory_is_reported_as_unavailable() {
+        // arrange
+        let directory = email_directory();
+        let email_sender = FileEmailSender::new(&directory, email("sender@example.com")).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        // act
+        let outcome = email_sender
+            .send_email(&email("ursula@example.com"), "Subject", "<p>Hi</p>", "Hi")
+            .await;
+
+        // assert
+        assert_matches!(outcome, Err(SendEmailError::Unavailable { .. }));
+    }
+
+    #[tokio::test]
+    async fn send_batch_writes_one_file_per_email() {
+        // arrange
+        let directory = email_directory();
+        let email_sender = FileEmailSender::new(&directory, email("sender@example.com")).unwrap();
+        let recipients = [email("ursula@example.com"), email("le_guin@example.com")];
+        let batch = recipients
+            .iter()
+            .map(|to| Email {
+                to,
+                subject: "Newsletter",
+                html_content: "<p>Content</p>",
+                text_content: "Content",
+                headers: &[],
+            })
+            .collect::<Vec<_>>();
+
+        // act
+        let results = email_sender.send_batch(&batch).await;
+
+        // assert
+        for (to, result) in recipients.iter().zip(results) {
+            let file_id = result.unwrap().unwrap();
+            let eml = std::fs::read_to_string(directory.join(format!("{}.eml", file_id))).unwrap();
+            assert!(eml.contains(&format!("To: {}", to.as_ref())));
+        }
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,842 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use secrecy::{ExposeSecret, Secret};
+use serde::Serialize;
+
+use super::{
+    send_with_retries, AttemptError, Email, EmailSender, RateLimiter, RetryPolicy, SendEmailError,
+    MAX_BATCH_SIZE,
+};
+use crate::domain::SubscriberEmail;
+
+/// Sends through a Resend style HTTP API, `POST {base_url}/emails` and `POST {base_url}/emails/batch`
+pub struct HttpEmailSender {
+    sender: SubscriberEmail,
+    http_client: reqwest::Client,
+    base_url: reqwest::Url,
+    authorization_token: Secret<String>,
+    retry_policy: RetryPolicy,
+    rate_limiter: RateLimiter,
+}
+
+#[derive(Serialize)]
+struct SendEmailRequest<'a> {
+    from: &'a str,
+    to: &'a str,
+    subject: &'a str,
+    text: &'a str,
+    html: &'a str,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    headers: HashMap<&'a str, &'a str>,
+}
+
+#[derive(serde::Deserialize)]
+struct SendEmailResponse {
+    id: String,
+}
+
+/// With permissive validation the valid emails of a batch are sent, `data` holds their ids
+/// in order, `errors` points at the invalid ones
+#[derive(serde::Deserialize)]
+struct SendBatchResponse {
+    data: Vec<SendEmailResponse>,
+    #[serde(default)]
+    errors: Vec<BatchError>,
+}
+
+#[derive(serde::Deserialize)]
+struct BatchError {
+    index: usize,
+    message: String,
+}
+
+impl HttpEmailSender {
+    pub fn new(
+        base_url: String,
+        sender: SubscriberEmail,
+        authorization_token: Secret<String>,
+        timeout: std::time::Duration,
+        retry_policy: RetryPolicy,
+    ) -> Self {
+        let base_url = reqwest::Url::parse(&base_url).expect("Invalid base URL");
+        let http_client = reqwest::Client::builder().timeout(timeout).build().unwrap();
+
+        Self {
+            sender,
+            http_client,
+            base_url,
+            authorization_token,
+            retry_policy,
+            rate_limiter: RateLimiter::unlimited(),
+        }
+    }
+
+    pub fn with_rate_limiter(self, rate_limiter: RateLimiter) -> Self {
+        Self {
+            rate_limiter,
+            ..self
+        }
+    }
+
+    fn request_body<'a>(&'a self, email: &Email<'a>) -> SendEmailRequest<'a> {
+        SendEmailRequest {
+            from: self.sender.as_ref(),
+            to: email.to.as_ref(),
+            subject: email.subject,
+            text: email.text_content,
+            html: email.html_content,
+            headers: email
+                .headers
+                .iter()
+                .map(|(name, value)| (*name, value.as_str()))
+                .collect(),
+        }
+    }
+
+    /// Sends the request once and sorts out which failures are worth another try
+    async fn try_send(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, AttemptError> {
+        let response = request
+            .header(
+                "Authorization",
+                format!("Bearer {}", self.authorization_token.expose_secret()),
+            )
+            .send()
+            .await
+            .map_err(|error| {
+                // no response at all, timeouts and connection problems are worth another try
+                if error.is_builder() {
+                    AttemptError::Permanent(error.into())
+                } else {
+                    AttemptError::Transient {
+                        error: error.into(),
+                        retry_after: None,
+                    }
+                }
+            })?;
+
+        let retry_after = retry_after(response.headers());
+        let status = response.status();
+
+        response.error_for_status().map_err(|error| {
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                AttemptError::RateLimited {
+                    error: error.into(),
+                    retry_after,
+                }
+            } else if status.is_server_error() {
+                AttemptError::Transient {
+                    error: error.into(),
+                    retry_after,
+                }
+            } else {
+                AttemptError::Permanent(error.into())
+            }
+        })
+    }
+}
+
+#[async_trait]
+impl EmailSender for HttpEmailSender {
+    async fn send(&self, email: &Email<'_>) -> Result<Option<String>, SendEmailError> {
+        let url = self.base_url.join("emails").unwrap();
+        let request_body = self.request_body(email);
+
+        send_with_retries(&self.retry_policy, &self.rate_limiter, 1, || async {
+            let response = self
+                .try_send(self.http_client.post(url.clone()).json(&request_body))
+                .await?;
+
+            // the email is on its way at this point, a body we don't understand doesn't change that
+            Ok(response
+                .json::<SendEmailResponse>()
+                .await
+                .ok()
+                .map(|body| body.id))
+        })
+        .await
+    }
+
+    fn batch_size(&self) -> usize {
+        MAX_BATCH_SIZE
+    }
+
+    /// Splits `emails` into requests of at most `MAX_BATCH_SIZE`, a failing request fails
+    /// all of its emails, an email rejected within a request fails on its own
+    async fn send_batch(
+        &self,
+        emails: &[Email<'_>],
+    ) -> Vec<Result<Option<String>, SendEmailError>> {
+        let url = self.base_url.join("emails/batch").unwrap();
+        let mut results = Vec::with_capacity(emails.len());
+
+        for chunk in emails.chunks(MAX_BATCH_SIZE) {
+            let request_body = chunk
+                .iter()
+                .map(|email| self.request_body(email))
+                .collect::<Vec<_>>();
+
+            let outcome = send_with_retries(
+                &self.retry_policy,
+                &self.rate_limiter,
+                chunk.len(),
+                || async {
+                    let response = self
+                        .try_send(
+                            self.http_client
+                                .post(url.clone())
+                                .header("x-batch-validation", "permissive")
+                                .json(&request_body),
+                        )
+                        .await?;
+
+                    Ok(response.json::<SendBatchResponse>().await.ok())
+                },
+            )
+            .await;
+
+            match outcome {
+                Ok(response) => results.extend(batch_results(chunk.len(), response)),
+                Err(error) => results.extend((0..chunk.len()).map(|_| Err(copy_error(&error)))),
+            }
+        }
+
+        results
+    }
+}
+
+/// Maps the per email outcome of a batch request back to the position of the email
+fn batch_results(
+    n_emails: usize,
+    response: Option<SendBatchResponse>,
+) -> Vec<Result<Option<String>, SendEmailError>> {
+    // the emails are on their way at this point, a body we don't understand doesn't change that
+    let Some(response) = response else {
+        return (0..n_emails).map(|_| Ok(None)).collect();
+    };
+
+    let mut message_ids = response.data.into_iter().map(|body| body.id);
+    let mut errors = response
+        .errors
+        .into_iter()
+        .map(|error| (error.index, error.message))
+        .collect::<HashMap<_, _>>();
+
+    (0..n_emails)
+        .map(|index| match errors.remove(&index) {
+            Some(message) => Err(SendEmailError::Rejected(anyhow::anyhow!(message))),
+            None => Ok(message_ids.next()),
+        })
+        .collect()
+}
+
+/// Every email of a failed batch request gets the error, `anyhow::Error` can't be cloned
+fn copy_error(error: &SendEmailError) -> SendEmailError {
+    match error {
+        SendEmailError::Rejected(source) => {
+            SendEmailError::Rejected(anyhow::anyhow!("{:#}", source))
+        }
+        SendEmailError::Unavailable { attempts, source } => SendEmailError::Unavailable {
+            attempts: *attempts,
+            source: anyhow::anyhow!("{:#}", source),
+        },
+    }
+}
+
+/// `Retry-After` is either a number of seconds or an HTTP date
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    // a date in the past means right away
+    Some(
+        (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{
+        Email, EmailSender, HttpEmailSender, RateLimiter, RetryPolicy, SendEmailError,
+    };
+    use claims::{assert_err, assert_matches, assert_ok};
+    use fake::faker::internet::en::SafeEmail;
+    use fake::faker::lorem::en::{Paragraph, Sentence};
+    use fake::{Fake, Faker};
+    use secrecy::Secret;
+    use std::time::{Duration, Instant};
+    use wiremock::matchers::{any, body_partial_json, header, header_exists, method, path};
+    use wiremock::{Mock, MockServer, Request};
+
+    struct SendEmailBodyMatcher;
+
+    impl wiremock::Match for SendEmailBodyMatcher {
+        fn matches(&self, request: &Request) -> bool {
+            let result: Result<serde_json::Value, _> = serde_json::from_slice(&request.body);
+
+            if let Ok(body) = result {
+                body.get("from").is_some()
+                    && body.get("to").is_some()
+                    && body.get("subject").is_some()
+                    && body.get("text").is_some()
+                    && body.get("html").is_some()
+            } else {
+                false
+            }
+        }
+    }
+
+    /// Answers a batch like Resend does with permissive validation, recipients at
+    /// `invalid.example.com` are rejected, the others get `id-<recipient>`
+    struct BatchResponder;
+
+    impl wiremock::Respond for BatchResponder {
+        fn respond(&self, request: &Request) -> wiremock::ResponseTemplate {
+            let emails: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+            let mut data = Vec::new();
+            let mut errors = Vec::new();
+            for (index, email) in emails.iter().enumerate() {
+                let to = email["to"].as_str().unwrap();
+                if to.ends_with("@invalid.example.com") {
+                    errors.push(
+                        serde_json::json!({ "index": index, "message": "Invalid `to` field." }),
+                    );
+                } else {
+                    data.push(serde_json::json!({ "id": format!("id-{}", to) }));
+                }
+            }
+
+            wiremock::ResponseTemplate::new(200)
+                .set_body_json(serde_json::json!({ "data": data, "errors": errors }))
+        }
+    }
+
+    fn subject() -> String {
+        Sentence(1..2).fake()
+    }
+
+    fn content() -> String {
+        Paragraph(1..10).fake()
+    }
+
+    fn email() -> SubscriberEmail {
+        SubscriberEmail::parse(SafeEmail().fake()).unwrap()
+    }
+
+    fn email_client(base_url: String) -> HttpEmailSender {
+        HttpEmailSender::new(
+            base_url,
+            email(),
+            Secret::new(Faker.fake()),
+            std::time::Duration::from_millis(200),
+            retry_policy(),
+        )
+    }
+
+    fn retry_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_secs(2),
+        }
+    }
+
+    #[tokio::test]
+    async fn send_email_sends_expected_request() {
+        // arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(header_exists("Authorization"))
+            .and(method("POST"))
+            .and(path("/emails"))
+            .and(header("Content-Type", "application/json"))
+            .and(SendEmailBodyMatcher)
+            .respond_with(wiremock::ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // act
+        let result = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // assert
+        assert_ok!(result);
+    }
+
+    #[tokio::test]
+    async fn extra_headers_are_passed_on_in_the_request_body() {
+        // arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(method("POST"))
+            .and(path("/emails"))
+            .and(body_partial_json(serde_json::json!({
+                "headers": { "List-Unsubscribe": "<https://example.com/unsubscribe>" }
+            })))
+            .respond_with(wiremock::ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // act
+        let result = email_client
+            .send(&Email {
+                to: &email(),
+                subject: &subject(),
+                html_content: &content(),
+                text_content: &content(),
+                headers: &[(
+                    "List-Unsubscribe",
+                    "<https://example.com/unsubscribe>".into(),
+                )],
+            })
+            .await;
+
+        // assert
+        assert_ok!(result);
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_when_server_returns_500() {
+        // arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        // every attempt fails
+        Mock::given(any())
+            .respond_with(wiremock::ResponseTemplate::new(500))
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+
+        // act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // assert
+        assert_matches!(
+            outcome,
+            Err(SendEmailError::Unavailable { attempts: 3, .. })
+        );
+    }
+
+    #[tokio::test]
+    async fn send_email_times_out_if_server_takes_too_long() {
+        // arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        let response =
+            wiremock::ResponseTemplate::new(200).set_delay(std::time::Duration::from_secs(180));
+
+        Mock::given(any())
+            .respond_with(response)
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+
+        // act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // assert
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_retries_after_a_server_error() {
+        // arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(wiremock::ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(wiremock::ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // assert
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_retries_after_a_timeout() {
+        // arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(wiremock::ResponseTemplate::new(200).set_delay(Duration::from_secs(180)))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(wiremock::ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // assert
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_honors_retry_after_when_rate_limited() {
+        // arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(wiremock::ResponseTemplate::new(429).insert_header("Retry-After", "1"))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(wiremock::ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // act
+        let started = Instant::now();
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // assert - way longer than the 10ms backoff
+        assert_ok!(outcome);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn being_rate_limited_slows_the_sender_down() {
+        // arrange
+        let mock_server = MockServer::start().await;
+        let email_client =
+            email_client(mock_server.uri()).with_rate_limiter(RateLimiter::new(100.0, 10));
+
+        Mock::given(any())
+            .respond_with(wiremock::ResponseTemplate::new(429))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(wiremock::ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // assert - halved by the 429, only partly recovered by the success
+        assert_ok!(outcome);
+        assert!(email_client.rate_limiter.messages_per_second() < 100.0);
+    }
+
+    #[tokio::test]
+    async fn batches_are_held_back_by_the_rate_limit() {
+        // arrange
+        let mock_server = MockServer::start().await;
+        let email_client =
+            email_client(mock_server.uri()).with_rate_limiter(RateLimiter::new(100.0, 100));
+        let addresses = (0..120)
+            .map(|i| format!("subscriber-{}@example.com", i))
+            .collect::<Vec<_>>();
+        let recipients = recipients(&addresses.iter().map(String::as_str).collect::<Vec<_>>());
+
+        Mock::given(path("/emails/batch"))
+            .respond_with(BatchResponder)
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        // act
+        let started = Instant::now();
+        let results = email_client.send_batch(&batch(&recipients)).await;
+
+        // assert - the burst covers the first 100, the other 20 wait for their tokens
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert!(started.elapsed() >= Duration::from_millis(190));
+    }
+
+    #[tokio::test]
+    async fn send_email_gives_up_when_retry_after_exceeds_the_max_delay() {
+        // arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(wiremock::ResponseTemplate::new(429).insert_header("Retry-After", "3600"))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // assert
+        assert_matches!(
+            outcome,
+            Err(SendEmailError::Unavailable { attempts: 1, .. })
+        );
+    }
+
+    #[tokio::test]
+    async fn send_email_does_not_retry_client_errors() {
+        // arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(wiremock::ResponseTemplate::new(422))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // assert
+        assert_matches!(outcome, Err(SendEmailError::Rejected(_)));
+    }
+
+    #[tokio::test]
+    async fn send_email_returns_the_provider_message_id() {
+        // arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(
+                wiremock::ResponseTemplate::new(200).set_body_json(
+                    serde_json::json!({ "id": "4ef9a417-02e9-4d39-ad75-9611e0fcc33c" }),
+                ),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // assert
+        assert_eq!(
+            outcome.unwrap().as_deref(),
+            Some("4ef9a417-02e9-4d39-ad75-9611e0fcc33c")
+        );
+    }
+
+    fn recipients(addresses: &[&str]) -> Vec<SubscriberEmail> {
+        addresses
+            .iter()
+            .map(|address| SubscriberEmail::parse(address.to_string()).unwrap())
+            .collect()
+    }
+
+    fn batch<'a>(recipients: &'a [SubscriberEmail]) -> Vec<Email<'a>> {
+        recipients
+            .iter()
+            .map(|to| Email {
+                to,
+                subject: "Newsletter",
+                html_content: "<p>Content</p>",
+                text_content: "Content",
+                headers: &[],
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn send_batch_splits_the_emails_into_requests_of_at_most_100() {
+        // arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let addresses = (0..250)
+            .map(|i| format!("subscriber-{}@example.com", i))
+            .collect::<Vec<_>>();
+        let recipients = recipients(&addresses.iter().map(String::as_str).collect::<Vec<_>>());
+
+        Mock::given(method("POST"))
+            .and(path("/emails/batch"))
+            .and(header("x-batch-validation", "permissive"))
+            .respond_with(BatchResponder)
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+
+        // act
+        let results = email_client.send_batch(&batch(&recipients)).await;
+
+        // assert
+        let batch_sizes = mock_server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .map(|request| {
+                serde_json::from_slice::<Vec<serde_json::Value>>(&request.body)
+                    .unwrap()
+                    .len()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(batch_sizes, vec![100, 100, 50]);
+
+        assert_eq!(results.len(), 250);
+        for (address, result) in addresses.iter().zip(results) {
+            assert_eq!(result.unwrap(), Some(format!("id-{}", address)));
+        }
+    }
+
+    #[tokio::test]
+    async fn send_batch_maps_partial_failures_back_to_their_emails() {
+        // arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let recipients = recipients(&[
+            "ursula@example.com",
+            "nobody@invalid.example.com",
+            "le_guin@example.com",
+        ]);
+
+        Mock::given(path("/emails/batch"))
+            .respond_with(BatchResponder)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // act
+        let mut results = email_client
+            .send_batch(&batch(&recipients))
+            .await
+            .into_iter();
+
+        // assert
+        assert_eq!(
+            results.next().unwrap().unwrap().as_deref(),
+            Some("id-ursula@example.com")
+        );
+        assert_matches!(results.next().unwrap(), Err(SendEmailError::Rejected(_)));
+        assert_eq!(
+            results.next().unwrap().unwrap().as_deref(),
+            Some("id-le_guin@example.com")
+        );
+    }
+
+    #[tokio::test]
+    async fn send_batch_retries_the_request_after_a_server_error() {
+        // arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let recipients = recipients(&["ursula@example.com", "le_guin@example.com"]);
+
+        Mock::given(any())
+            .respond_with(wiremock::ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(BatchResponder)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // act
+        let results = email_client.send_batch(&batch(&recipients)).await;
+
+        // assert
+        assert!(results.iter().all(|result| result.is_ok()));
+    }
+
+    #[tokio::test]
+    async fn a_failing_batch_request_fails_each_of_its_emails() {
+        // arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let recipients = recipients(&["ursula@example.com", "le_guin@example.com"]);
+
+        Mock::given(any())
+            .respond_with(wiremock::ResponseTemplate::new(500))
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+
+        // act
+        let results = email_client.send_batch(&batch(&recipients)).await;
+
+        // assert
+        assert_eq!(results.len(), 2);
+        for result in results {
+            assert_matches!(result, Err(SendEmailError::Unavailable { attempts: 3, .. }));
+        }
+    }
+
+    #[tokio::test]
+    async fn a_rejected_batch_request_is_not_retried() {
+        // arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let recipients = recipients(&["ursula@example.com", "le_guin@example.com"]);
+
+        Mock::given(any())
+            .respond_with(wiremock::ResponseTemplate::new(422))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // act
+        let results = email_client.send_batch(&batch(&recipients)).await;
+
+        // assert
+        for result in results {
+            assert_matches!(result, Err(SendEmailError::Rejected(_)));
+        }
+    }
+}
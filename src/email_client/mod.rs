@@ -0,0 +1,257 @@
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use lettre::message::header::{HeaderName, HeaderValue};
+use lettre::message::MultiPart;
+use lettre::Message;
+use rand::Rng;
+
+use crate::domain::SubscriberEmail;
+use crate::errors::error_chain_fmt;
+
+mod file;
+mod http;
+mod rate_limit;
+mod smtp;
+
+pub use file::FileEmailSender;
+pub use http::HttpEmailSender;
+pub use rate_limit::RateLimiter;
+pub use smtp::{SmtpEmailSender, SmtpTls};
+
+/// Resend takes at most 100 emails per batch request
+pub const MAX_BATCH_SIZE: usize = 100;
+
+/// Delivers emails on our behalf, the backend is picked with `email_client.kind`
+#[async_trait]
+pub trait EmailSender: Send + Sync {
+    /// Returns the id the backend assigned to the email, if it told us
+    async fn send(&self, email: &Email<'_>) -> Result<Option<String>, SendEmailError>;
+
+    /// `send` without any extra headers
+    async fn send_email(
+        &self,
+        to: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+    ) -> Result<Option<String>, SendEmailError> {
+        self.send(&Email {
+            to,
+            subject,
+            html_content,
+            text_content,
+            headers: &[],
+        })
+        .await
+    }
+
+    /// How many emails `send_batch` takes in one request, callers with more to send
+    /// should rather spread them over concurrent batches of this size
+    fn batch_size(&self) -> usize {
+        1
+    }
+
+    /// One result per email, in the order of `emails`, so a partial failure can be traced
+    /// back to the recipient. Backends without a batch API send one email after the other.
+    async fn send_batch(
+        &self,
+        emails: &[Email<'_>],
+    ) -> Vec<Result<Option<String>, SendEmailError>> {
+        let mut results = Vec::with_capacity(emails.len());
+        for email in emails {
+            results.push(self.send(email).await);
+        }
+
+        results
+    }
+}
+
+/// A single email
+pub struct Email<'a> {
+    pub to: &'a SubscriberEmail,
+    pub subject: &'a str,
+    pub html_content: &'a str,
+    pub text_content: &'a str,
+    /// Extra headers as name and value, e.g. `List-Unsubscribe`
+    pub headers: &'a [(&'a str, String)],
+}
+
+/// How often and how long to retry transient failures (429, 5xx, timeouts)
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Includes the first attempt, 1 disables retries
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    /// Upper bound for a single delay, also for the one asked for in `Retry-After`
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with (equal) jitter, so failed sends don't come back in lockstep.
+    /// A `Retry-After` from the provider wins, unless it asks for more than `max_delay`.
+    fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        if let Some(retry_after) = retry_after {
+            return (retry_after <= self.max_delay).then_some(retry_after);
+        }
+
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        let jitter = backoff.mul_f64(rand::thread_rng().gen_range(0.0..=0.5));
+
+        Some(backoff / 2 + jitter)
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum SendEmailError {
+    /// The provider refused the email, sending it again won't help
+    #[error("The email provider rejected the email.")]
+    Rejected(#[source] anyhow::Error),
+    #[error("Failed to send the email after {attempts} attempt(s).")]
+    Unavailable {
+        attempts: u32,
+        #[source]
+        source: anyhow::Error,
+    },
+}
+
+impl std::fmt::Debug for SendEmailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+/// Outcome of a single failed attempt
+enum AttemptError {
+    Permanent(anyhow::Error),
+    Transient {
+        error: anyhow::Error,
+        retry_after: Option<Duration>,
+    },
+    /// Transient as well, but we have to slow down in general
+    RateLimited {
+        error: anyhow::Error,
+        retry_after: Option<Duration>,
+    },
+}
+
+/// Runs `try_send` until it succeeds, fails permanently or `retry_policy` gives up.
+/// Every attempt waits for `rate_limiter` to let `n_messages` through.
+async fn send_with_retries<T, F, Fut>(
+    retry_policy: &RetryPolicy,
+    rate_limiter: &RateLimiter,
+    n_messages: usize,
+    mut try_send: F,
+) -> Result<T, SendEmailError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, AttemptError>>,
+{
+    let mut attempt = 1;
+    loop {
+        rate_limiter.acquire(n_messages).await;
+
+        let (error, retry_after) = match try_send().await {
+            Ok(sent) => {
+                rate_limiter.recover();
+                return Ok(sent);
+            }
+            Err(AttemptError::Permanent(error)) => return Err(SendEmailError::Rejected(error)),
+            Err(AttemptError::Transient { error, retry_after }) => (error, retry_after),
+            Err(AttemptError::RateLimited { error, retry_after }) => {
+                rate_limiter.throttle();
+                (error, retry_after)
+            }
+        };
+
+        let delay = match retry_policy.delay(attempt, retry_after) {
+            Some(delay) if attempt < retry_policy.max_attempts => delay,
+            _ => {
+                return Err(SendEmailError::Unavailable {
+                    attempts: attempt,
+                    source: error,
+                })
+            }
+        };
+
+        tracing::warn!(
+            error.cause_chain = ?error,
+            attempt,
+            delay_ms = delay.as_millis() as u64,
+            "Transient failure while sending an email, retrying."
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// The MIME message the SMTP and file backends hand over, plain text with an HTML alternative
+fn build_message(
+    message_id: String,
+    sender: &SubscriberEmail,
+    email: &Email<'_>,
+) -> Result<Message, anyhow::Error> {
+    let mut builder = Message::builder()
+        .message_id(Some(message_id))
+        .from(sender.as_ref().parse().context("Invalid sender address.")?)
+        .to(email
+            .to
+            .as_ref()
+            .parse()
+            .context("Invalid recipient address.")?)
+        .subject(email.subject);
+    for (name, value) in email.headers {
+        let name = HeaderName::new_from_ascii(name.to_string())
+            .with_context(|| format!("Invalid header name `{}`.", name))?;
+        builder = builder.raw_header(HeaderValue::new(name, value.clone()));
+    }
+
+    builder
+        .multipart(MultiPart::alternative_plain_html(
+            email.text_content.to_owned(),
+            email.html_content.to_owned(),
+        ))
+        .context("Failed to build the email.")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::email_client::RetryPolicy;
+    use std::time::Duration;
+
+    #[test]
+    fn retry_delays_grow_exponentially_up_to_the_cap() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+        };
+
+        for (attempt, backoff) in [(1, 100), (2, 200), (3, 400), (4, 800), (5, 1000), (9, 1000)] {
+            let delay = policy.delay(attempt, None).unwrap();
+            // equal jitter, between half and all of the backoff
+            assert!(delay >= Duration::from_millis(backoff / 2));
+            assert!(delay <= Duration::from_millis(backoff));
+        }
+    }
+
+    #[test]
+    fn retry_after_takes_precedence_up_to_the_cap() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_secs(2),
+        };
+
+        assert_eq!(
+            policy.delay(1, Some(Duration::from_secs(1))),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(policy.delay(1, Some(Duration::from_secs(3))), None);
+    }
+}
@@ -0,0 +1,203 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The rate never drops below this share of the configured one, however often we are throttled
+const MIN_RATE_FRACTION: f64 = 0.05;
+/// Share of the configured rate won back with every request that went through
+const RECOVERY_FRACTION: f64 = 0.1;
+
+/// Token bucket in front of the email provider, in messages per second.
+///
+/// Halves its rate whenever the provider says we are too fast (429) and creeps back
+/// up to the configured rate while requests go through.
+pub struct RateLimiter {
+    max_rate: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    /// A rate of zero or less (or NaN) means no limit, there is nothing sensible to wait for
+    pub fn new(messages_per_second: f64, burst: u32) -> Self {
+        let messages_per_second = if messages_per_second > 0.0 {
+            messages_per_second
+        } else {
+            f64::INFINITY
+        };
+        let burst = f64::from(burst.max(1));
+
+        Self {
+            max_rate: messages_per_second,
+            burst,
+            bucket: Mutex::new(Bucket {
+                rate: messages_per_second,
+                tokens: burst,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(f64::INFINITY, 1)
+    }
+
+    /// Current rate in messages per second
+    pub fn messages_per_second(&self) -> f64 {
+        self.bucket.lock().unwrap().rate
+    }
+
+    /// Waits until `n_messages` may be sent.
+    ///
+    /// The tokens are taken right away and a deficit is paid back by waiting, so a batch
+    /// larger than the burst still goes through and concurrent callers queue up in order.
+    pub async fn acquire(&self, n_messages: usize) {
+        if self.max_rate.is_infinite() {
+            return;
+        }
+
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            bucket.refill(self.burst);
+            bucket.tokens -= n_messages as f64;
+
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / bucket.rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// The provider told us to slow down
+    pub fn throttle(&self) {
+        if self.max_rate.is_infinite() {
+            return;
+        }
+
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(self.burst);
+        bucket.rate = (bucket.rate / 2.0).max(self.max_rate * MIN_RATE_FRACTION);
+        // no more bursting until we are back in line
+        bucket.tokens = bucket.tokens.min(0.0);
+
+        tracing::warn!(
+            messages_per_second = bucket.rate,
+            "The email provider is rate limiting us, slowing down."
+        );
+    }
+
+    /// A request went through, speed up again
+    pub fn recover(&self) {
+        if self.max_rate.is_infinite() {
+            return;
+        }
+
+        let mut bucket = self.bucket.lock().unwrap();
+        if bucket.rate < self.max_rate {
+            bucket.refill(self.burst);
+            bucket.rate = (bucket.rate + self.max_rate * RECOVERY_FRACTION).min(self.max_rate);
+        }
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, burst: f64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(burst);
+        self.refilled_at = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::email_client::RateLimiter;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn the_burst_goes_through_without_waiting() {
+        let rate_limiter = RateLimiter::new(1.0, 10);
+
+        let started = Instant::now();
+        rate_limiter.acquire(10).await;
+
+        assert!(started.elapsed() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn messages_beyond_the_burst_wait_for_their_tokens() {
+        let rate_limiter = RateLimiter::new(100.0, 10);
+
+        let started = Instant::now();
+        rate_limiter.acquire(10).await;
+        rate_limiter.acquire(20).await;
+
+        // 20 more messages at 100 per second
+        assert!(started.elapsed() >= Duration::from_millis(190));
+    }
+
+    #[tokio::test]
+    async fn unlimited_never_waits() {
+        let rate_limiter = RateLimiter::unlimited();
+
+        let started = Instant::now();
+        rate_limiter.acquire(1_000_000).await;
+        rate_limiter.throttle();
+
+        assert!(started.elapsed() < Duration::from_millis(50));
+        assert!(rate_limiter.messages_per_second().is_infinite());
+    }
+
+    #[tokio::test]
+    async fn a_rate_of_zero_or_less_is_unlimited() {
+        for messages_per_second in [0.0, -1.0, f64::NAN] {
+            let rate_limiter = RateLimiter::new(messages_per_second, 10);
+
+            let started = Instant::now();
+            rate_limiter.acquire(1_000).await;
+            rate_limiter.throttle();
+
+            assert!(started.elapsed() < Duration::from_millis(50));
+            assert!(rate_limiter.messages_per_second().is_infinite());
+        }
+    }
+
+    #[test]
+    fn throttling_halves_the_rate_down_to_a_floor() {
+        let rate_limiter = RateLimiter::new(100.0, 10);
+
+        rate_limiter.throttle();
+        assert_eq!(rate_limiter.messages_per_second(), 50.0);
+        rate_limiter.throttle();
+        assert_eq!(rate_limiter.messages_per_second(), 25.0);
+
+        for _ in 0..10 {
+            rate_limiter.throttle();
+        }
+        assert_eq!(rate_limiter.messages_per_second(), 5.0);
+    }
+
+    #[test]
+    fn the_rate_recovers_up_to_the_configured_one() {
+        let rate_limiter = RateLimiter::new(100.0, 10);
+        rate_limiter.throttle();
+
+        rate_limiter.recover();
+        assert_eq!(rate_limiter.messages_per_second(), 60.0);
+
+        for _ in 0..10 {
+            rate_limiter.recover();
+        }
+        assert_eq!(rate_limiter.messages_per_second(), 100.0);
+    }
+}
@@ -0,0 +1,359 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use secrecy::{ExposeSecret, Secret};
+use uuid::Uuid;
+
+use super::{
+    build_message, send_with_retries, AttemptError, Email, EmailSender, RateLimiter, RetryPolicy,
+    SendEmailError,
+};
+use crate::domain::SubscriberEmail;
+
+/// How the connection to the SMTP server is secured
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Upgrade with `STARTTLS` before authenticating, a server that doesn't offer it is an error
+    Starttls,
+    /// Plaintext, only for a relay or a stand-in on the same machine
+    None,
+}
+
+/// Sends through an SMTP server, e.g. a provider's submission port
+pub struct SmtpEmailSender {
+    sender: SubscriberEmail,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    retry_policy: RetryPolicy,
+    rate_limiter: RateLimiter,
+}
+
+impl SmtpEmailSender {
+    pub fn new(
+        host: &str,
+        port: u16,
+        tls: SmtpTls,
+        credentials: Option<(String, Secret<String>)>,
+        sender: SubscriberEmail,
+        timeout: Duration,
+        retry_policy: RetryPolicy,
+    ) -> Result<Self, anyhow::Error> {
+        let tls = match tls {
+            SmtpTls::Starttls => Tls::Required(TlsParameters::new(host.into())?),
+            SmtpTls::None => Tls::None,
+        };
+        let mut transport = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+            .port(port)
+            .tls(tls)
+            .timeout(Some(timeout));
+        if let Some((username, password)) = credentials {
+            transport = transport.credentials(Credentials::new(
+                username,
+                password.expose_secret().to_owned(),
+            ));
+        }
+
+        Ok(Self {
+            sender,
+            transport: transport.build(),
+            retry_policy,
+            rate_limiter: RateLimiter::unlimited(),
+        })
+    }
+
+    pub fn with_rate_limiter(self, rate_limiter: RateLimiter) -> Self {
+        Self {
+            rate_limiter,
+            ..self
+        }
+    }
+
+    async fn try_send(&self, message: &lettre::Message) -> Result<(), AttemptError> {
+        self.transport
+            .send(message.clone())
+            .await
+            .map_err(|error| {
+                // 5xx replies are final, 4xx replies and connection problems are worth another try
+                if error.is_permanent() {
+                    AttemptError::Permanent(error.into())
+                } else {
+                    AttemptError::Transient {
+                        error: error.into(),
+                        retry_after: None,
+                    }
+                }
+            })?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl EmailSender for SmtpEmailSender {
+    /// SMTP doesn't hand out ids, the `Message-ID` we generate stands in for it
+    async fn send(&self, email: &Email<'_>) -> Result<Option<String>, SendEmailError> {
+        let domain = self
+            .sender
+            .as_ref()
+            .rsplit_once('@')
+            .map_or("localhost", |(_, domain)| domain);
+        let message_id = format!("<{}@{}>", Uuid::new_v4(), domain);
+        let message = build_message(message_id.clone(), &self.sender, email)
+            .map_err(SendEmailError::Rejected)?;
+
+        send_with_retries(&self.retry_policy, &self.rate_limiter, 1, || async {
+            self.try_send(&message)
+                .await
+                .map(|_| Some(message_id.clone()))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{EmailSender, RetryPolicy, SendEmailError, SmtpEmailSender, SmtpTls};
+    use base64::Engine;
+    use claims::{assert_matches, assert_ok};
+    use secrecy::Secret;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// What a client told the stand-in during one session
+    #[derive(Default, Clone, Debug)]
+    struct Session {
+        auth: Option<String>,
+        mail_from: Option<String>,
+        rcpt_to: Vec<String>,
+        data: String,
+    }
+
+    /// A minimal local SMTP server, just enough of RFC 5321 for lettre to deliver a message
+    struct SmtpStandIn {
+        port: u16,
+        sessions: Arc<Mutex<Vec<Session>>>,
+    }
+
+    impl SmtpStandIn {
+        /// `rcpt_replies` are used up one per `RCPT TO`, accepting once they are gone
+        async fn start(rcpt_replies: Vec<&'static str>) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let sessions = Arc::new(Mutex::new(Vec::new()));
+            let rcpt_replies = Arc::new(Mutex::new(VecDeque::from(rcpt_replies)));
+
+            let recorded_sessions = sessions.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(serve(
+                        stream,
+                        recorded_sessions.clone(),
+                        rcpt_replies.clone(),
+                    ));
+                }
+            });
+
+            Self { port, sessions }
+        }
+
+        fn sessions(&self) -> Vec<Session> {
+            self.sessions.lock().unwrap().clone()
+        }
+    }
+
+    /// Records what the client sends before replying, so the client never sees a reply
+    /// for something that isn't recorded yet
+    async fn serve(
+        stream: tokio::net::TcpStream,
+        sessions: Arc<Mutex<Vec<Session>>>,
+        rcpt_replies: Arc<Mutex<VecDeque<&'static str>>>,
+    ) {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let index = {
+            let mut sessions = sessions.lock().unwrap();
+            sessions.push(Session::default());
+            sessions.len() - 1
+        };
+        let record = |update: &dyn Fn(&mut Session)| update(&mut sessions.lock().unwrap()[index]);
+
+        writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let command = line.to_uppercase();
+            let reply = if command.starts_with("EHLO") {
+                "250-localhost\r\n250-AUTH PLAIN LOGIN\r\n250 8BITMIME\r\n"
+            } else if let Some(credentials) = line.strip_prefix("AUTH PLAIN ") {
+                let decoded = base64::engine::general_purpose::STANDARD
+                    .decode(credentials)
+                    .unwrap();
+                let credentials = String::from_utf8(decoded).unwrap();
+                record(&|session| session.auth = Some(credentials.clone()));
+                "235 2.7.0 Authentication successful\r\n"
+            } else if command.starts_with("MAIL FROM:") {
+                // parameters like `BODY=8BITMIME` are of no interest
+                let address = line[10..].split_whitespace().next().map(str::to_string);
+                record(&|session| session.mail_from = address.clone());
+                "250 2.1.0 Ok\r\n"
+            } else if command.starts_with("RCPT TO:") {
+                record(&|session| session.rcpt_to.push(line[8..].to_string()));
+                rcpt_replies
+                    .lock()
+                    .unwrap()
+                    .pop_front()
+                    .unwrap_or("250 2.1.5 Ok\r\n")
+            } else if command == "DATA" {
+                writer
+                    .write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n")
+                    .await
+                    .unwrap();
+                let mut data = String::new();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    if line == "." {
+                        break;
+                    }
+                    data.push_str(&line);
+                    data.push('\n');
+                }
+                record(&|session| session.data = data.clone());
+                "250 2.0.0 Ok: queued\r\n"
+            } else if command == "QUIT" {
+                "221 2.0.0 Bye\r\n"
+            } else if command == "RSET" || command == "NOOP" {
+                "250 2.0.0 Ok\r\n"
+            } else {
+                "502 5.5.2 Command not recognized\r\n"
+            };
+            if writer.write_all(reply.as_bytes()).await.is_err() || command == "QUIT" {
+                break;
+            }
+        }
+    }
+
+    fn email(address: &str) -> SubscriberEmail {
+        SubscriberEmail::parse(address.to_string()).unwrap()
+    }
+
+    fn email_sender(port: u16, tls: SmtpTls) -> SmtpEmailSender {
+        SmtpEmailSender::new(
+            "127.0.0.1",
+            port,
+            tls,
+            Some(("smtp-user".into(), Secret::new("smtp-password".into()))),
+            email("sender@example.com"),
+            Duration::from_secs(2),
+            RetryPolicy {
+                max_attempts: 3,
+                base_delay: Duration::from_millis(10),
+                max_delay: Duration::from_secs(2),
+            },
+        )
+        .unwrap()
+    }
+
+    async fn send(email_sender: &SmtpEmailSender) -> Result<Option<String>, SendEmailError> {
+        email_sender
+            .send_email(
+                &email("ursula@example.com"),
+                "Welcome!",
+                "<p>Hello there</p>",
+                "Hello there",
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn send_email_authenticates_and_delivers_the_message() {
+        // arrange
+        let smtp_server = SmtpStandIn::start(vec![]).await;
+        let email_sender = email_sender(smtp_server.port, SmtpTls::None);
+
+        // act
+        let message_id = send(&email_sender).await.unwrap().unwrap();
+
+        // assert
+        let sessions = smtp_server.sessions();
+        assert_eq!(sessions.len(), 1);
+        let session = &sessions[0];
+        assert_eq!(session.auth.as_deref(), Some("\0smtp-user\0smtp-password"));
+        assert_eq!(session.mail_from.as_deref(), Some("<sender@example.com>"));
+        assert_eq!(session.rcpt_to, vec!["<ursula@example.com>"]);
+        assert!(session.data.contains("Subject: Welcome!"));
+        assert!(session
+            .data
+            .contains(&format!("Message-ID: {}", message_id)));
+        assert!(message_id.ends_with("@example.com>"));
+    }
+
+    #[tokio::test]
+    async fn send_email_retries_a_transient_rejection() {
+        // arrange
+        let smtp_server = SmtpStandIn::start(vec!["451 4.3.0 Try again later\r\n"]).await;
+        let email_sender = email_sender(smtp_server.port, SmtpTls::None);
+
+        // act
+        let outcome = send(&email_sender).await;
+
+        // assert
+        assert_ok!(outcome);
+        let sessions = smtp_server.sessions();
+        assert_eq!(sessions.len(), 2);
+        assert!(!sessions[1].data.is_empty());
+    }
+
+    #[tokio::test]
+    async fn send_email_does_not_retry_a_permanent_rejection() {
+        // arrange
+        let smtp_server = SmtpStandIn::start(vec!["550 5.1.1 No such user\r\n"]).await;
+        let email_sender = email_sender(smtp_server.port, SmtpTls::None);
+
+        // act
+        let outcome = send(&email_sender).await;
+
+        // assert
+        assert_matches!(outcome, Err(SendEmailError::Rejected(_)));
+        assert_eq!(smtp_server.sessions().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn credentials_are_not_sent_when_the_server_does_not_offer_starttls() {
+        // arrange
+        let smtp_server = SmtpStandIn::start(vec![]).await;
+        let email_sender = email_sender(smtp_server.port, SmtpTls::Starttls);
+
+        // act
+        let outcome = send(&email_sender).await;
+
+        // assert
+        assert_matches!(outcome, Err(SendEmailError::Unavailable { .. }));
+        for session in smtp_server.sessions() {
+            assert!(session.auth.is_none());
+            assert!(session.mail_from.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn send_email_gives_up_when_the_server_is_unreachable() {
+        // arrange - nothing listens on the port anymore
+        let port = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let email_sender = email_sender(port, SmtpTls::None);
+
+        // act
+        let outcome = send(&email_sender).await;
+
+        // assert
+        assert_matches!(
+            outcome,
+            Err(SendEmailError::Unavailable { attempts: 3, .. })
+        );
+    }
+}
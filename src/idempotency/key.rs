@@ -0,0 +1,49 @@
+/// Client supplied key that identifies retries of the same request
+#[derive(Debug)]
+pub struct IdempotencyKey(String);
+
+impl IdempotencyKey {
+    /// returns a new instance of IdempotencyKey when the header value is usable as key
+    pub fn parse(key: String) -> Result<IdempotencyKey, String> {
+        if key.is_empty() {
+            return Err("The idempotency key cannot be empty.".into());
+        }
+        // enough for a UUID or similar, but nobody gets to store megabytes of keys
+        let max_length = 50;
+        if key.len() > max_length {
+            return Err(format!(
+                "The idempotency key must be shorter than {} characters.",
+                max_length
+            ));
+        }
+
+        Ok(Self(key))
+    }
+}
+
+impl AsRef<str> for IdempotencyKey {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::idempotency::IdempotencyKey;
+    use claims::{assert_err, assert_ok};
+
+    #[test]
+    fn a_uuid_is_a_valid_key() {
+        assert_ok!(IdempotencyKey::parse(uuid::Uuid::new_v4().to_string()));
+    }
+
+    #[test]
+    fn an_empty_key_is_rejected() {
+        assert_err!(IdempotencyKey::parse("".into()));
+    }
+
+    #[test]
+    fn a_key_longer_than_50_characters_is_rejected() {
+        assert_err!(IdempotencyKey::parse("a".repeat(51)));
+    }
+}
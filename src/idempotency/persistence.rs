@@ -0,0 +1,150 @@
+use actix_web::body::to_bytes;
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use anyhow::Context;
+use sqlx::postgres::{PgHasArrayType, PgTypeInfo};
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use super::IdempotencyKey;
+
+#[derive(Debug, sqlx::Type)]
+#[sqlx(type_name = "header_pair")]
+struct HeaderPairRecord {
+    name: String,
+    value: Vec<u8>,
+}
+
+impl PgHasArrayType for HeaderPairRecord {
+    fn array_type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("_header_pair")
+    }
+}
+
+pub enum NextAction {
+    /// First time the key is seen, the transaction holds the row until the response is saved
+    StartProcessing(Box<Transaction<'static, Postgres>>),
+    ReturnSavedResponse(HttpResponse),
+    /// Another request with the same key has not finished yet
+    Conflict,
+}
+
+/// Claims the key for this request.
+///
+/// A concurrent request with the same key blocks on the insert until the first one
+/// has committed its response, and then gets that response instead of doing the work again.
+#[tracing::instrument(skip(pool, idempotency_key))]
+pub async fn try_processing(
+    pool: &PgPool,
+    idempotency_key: &IdempotencyKey,
+    user_id: Uuid,
+) -> Result<NextAction, anyhow::Error> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to start a transaction.")?;
+
+    let n_inserted_rows = transaction
+        .execute(sqlx::query!(
+            r#"
+            INSERT INTO idempotency (user_id, idempotency_key, created_at)
+            VALUES ($1, $2, now())
+            ON CONFLICT DO NOTHING
+            "#,
+            user_id,
+            idempotency_key.as_ref()
+        ))
+        .await
+        .context("Failed to insert the idempotency key.")?
+        .rows_affected();
+
+    if n_inserted_rows > 0 {
+        return Ok(NextAction::StartProcessing(Box::new(transaction)));
+    }
+
+    match get_saved_response(pool, idempotency_key, user_id).await? {
+        Some(saved_response) => Ok(NextAction::ReturnSavedResponse(saved_response)),
+        None => Ok(NextAction::Conflict),
+    }
+}
+
+async fn get_saved_response(
+    pool: &PgPool,
+    idempotency_key: &IdempotencyKey,
+    user_id: Uuid,
+) -> Result<Option<HttpResponse>, anyhow::Error> {
+    let saved_response = sqlx::query!(
+        r#"
+        SELECT
+            response_status_code AS "response_status_code!",
+            response_headers AS "response_headers!: Vec<HeaderPairRecord>",
+            response_body AS "response_body!"
+        FROM idempotency
+        WHERE user_id = $1 AND idempotency_key = $2 AND response_status_code IS NOT NULL
+        "#,
+        user_id,
+        idempotency_key.as_ref()
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to load the saved response.")?;
+
+    let Some(row) = saved_response else {
+        return Ok(None);
+    };
+
+    let status_code = StatusCode::from_u16(row.response_status_code.try_into()?)?;
+    let mut response = HttpResponse::build(status_code);
+    for HeaderPairRecord { name, value } in row.response_headers {
+        response.append_header((name, value));
+    }
+
+    Ok(Some(response.body(row.response_body)))
+}
+
+/// Stores the response next to the key and commits the work done in `transaction`
+#[tracing::instrument(skip(transaction, idempotency_key, http_response))]
+pub async fn save_response(
+    mut transaction: Transaction<'static, Postgres>,
+    idempotency_key: &IdempotencyKey,
+    user_id: Uuid,
+    http_response: HttpResponse,
+) -> Result<HttpResponse, anyhow::Error> {
+    let (response_head, body) = http_response.into_parts();
+    // `MessageBody::Error` is not `Send` + `Sync`, so it doesn't fit into anyhow
+    let body = to_bytes(body).await.map_err(|e| anyhow::anyhow!("{}", e))?;
+    let status_code = response_head.status().as_u16() as i16;
+    let headers = response_head
+        .headers()
+        .iter()
+        .map(|(name, value)| HeaderPairRecord {
+            name: name.as_str().to_owned(),
+            value: value.as_bytes().to_owned(),
+        })
+        .collect::<Vec<_>>();
+
+    transaction
+        .execute(sqlx::query_unchecked!(
+            r#"
+            UPDATE idempotency
+            SET
+                response_status_code = $3,
+                response_headers = $4,
+                response_body = $5
+            WHERE user_id = $1 AND idempotency_key = $2
+            "#,
+            user_id,
+            idempotency_key.as_ref(),
+            status_code,
+            headers,
+            body.as_ref()
+        ))
+        .await
+        .context("Failed to save the response.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the idempotency transaction.")?;
+
+    Ok(response_head.set_body(body).map_into_boxed_body())
+}
@@ -0,0 +1,438 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use secrecy::Secret;
+use sqlx::{PgPool, Postgres, Transaction};
+use tracing::{field::display, Span};
+use uuid::Uuid;
+
+use crate::domain::SubscriberEmail;
+use crate::email_client::{Email, EmailSender, SendEmailError};
+use crate::routes::{preferences_link, unsubscribe_link};
+
+/// How long to wait before looking at the queue again when it was empty
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Back off a bit when the database is unavailable instead of spinning
+const ERROR_BACKOFF: Duration = Duration::from_secs(1);
+/// How often the throughput of all delivery loops together is logged, when there was any
+const THROUGHPUT_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+pub enum ExecutionOutcome {
+    TaskCompleted { n_emails: usize },
+    EmptyQueue,
+}
+
+/// Works through `issue_delivery_queue`, a batch of emails of the same issue at a time.
+///
+/// Each task is locked (`FOR UPDATE SKIP LOCKED`) for as long as it is being worked on,
+/// so any number of workers can run side by side without sending an email twice.
+/// `concurrency` delivery loops share the email client and with it its rate limit.
+pub struct IssueDeliveryWorker {
+    pool: PgPool,
+    email_client: Arc<dyn EmailSender>,
+    concurrency: usize,
+    base_url: String,
+    hmac_secret: Secret<String>,
+}
+
+impl IssueDeliveryWorker {
+    /// `base_url` and `hmac_secret` make up the unsubscribe link of every email
+    pub fn new(
+        pool: PgPool,
+        email_client: Arc<dyn EmailSender>,
+        concurrency: usize,
+        base_url: String,
+        hmac_secret: Secret<String>,
+    ) -> Self {
+        Self {
+            pool,
+            email_client,
+            concurrency: concurrency.max(1),
+            base_url,
+            hmac_secret,
+        }
+    }
+
+    pub async fn run_until_stopped(self) {
+        let n_delivered = AtomicUsize::new(0);
+        let delivery_loops = (0..self.concurrency).map(|_| self.deliver(&n_delivered));
+
+        tokio::join!(
+            futures_util::future::join_all(delivery_loops),
+            report_throughput(&n_delivered)
+        );
+    }
+
+    async fn deliver(&self, n_delivered: &AtomicUsize) {
+        loop {
+            let outcome = try_execute_task(
+                &self.pool,
+                self.email_client.as_ref(),
+                &self.base_url,
+                &self.hmac_secret,
+            )
+            .await;
+            match outcome {
+                Ok(ExecutionOutcome::TaskCompleted { n_emails }) => {
+                    n_delivered.fetch_add(n_emails, Ordering::Relaxed);
+                }
+                Ok(ExecutionOutcome::EmptyQueue) => tokio::time::sleep(POLL_INTERVAL).await,
+                Err(e) => {
+                    tracing::error!(error.cause_chain = ?e, "Failed to execute an issue delivery task.");
+                    tokio::time::sleep(ERROR_BACKOFF).await;
+                }
+            }
+        }
+    }
+}
+
+/// Emails handed to the provider per second, across all delivery loops
+async fn report_throughput(n_delivered: &AtomicUsize) {
+    let mut interval = tokio::time::interval(THROUGHPUT_REPORT_INTERVAL);
+    // the first tick completes right away
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        let n_emails = n_delivered.swap(0, Ordering::Relaxed);
+        if n_emails > 0 {
+            tracing::info!(
+                n_emails,
+                emails_per_second = n_emails as f64 / THROUGHPUT_REPORT_INTERVAL.as_secs_f64(),
+                "Issue delivery throughput."
+            );
+        }
+    }
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(newsletter_issue_id=tracing::field::Empty, n_tasks=tracing::field::Empty)
+)]
+pub async fn try_execute_task(
+    pool: &PgPool,
+    email_client: &dyn EmailSender,
+    base_url: &str,
+    hmac_secret: &Secret<String>,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    let started = Instant::now();
+    let Some((transaction, issue_id, tasks)) =
+        dequeue_tasks(pool, email_client.batch_size()).await?
+    else {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    };
+    Span::current()
+        .record("newsletter_issue_id", display(issue_id))
+        .record("n_tasks", tasks.len());
+
+    let mut outcomes = Vec::with_capacity(tasks.len());
+    let mut recipients = Vec::with_capacity(tasks.len());
+    for Task {
+        subscriber_email: email,
+        subscriber_id,
+        subscriber_status,
+    } in tasks
+    {
+        // nothing wrong with the address, it just isn't the subscriber's anymore
+        let Some(subscriber_id) = subscriber_id else {
+            let reason =
+                "The subscriber no longer exists or changed their email address.".to_string();
+            outcomes.push((email, DeliveryOutcome::Skipped { reason }));
+            continue;
+        };
+        // the list was copied when the issue was published, who left since must not get it
+        if subscriber_status.as_deref() != Some("confirmed") {
+            let reason = "The subscriber is no longer confirmed.".to_string();
+            outcomes.push((email, DeliveryOutcome::Skipped { reason }));
+            continue;
+        }
+        match SubscriberEmail::parse(email.clone()) {
+            Ok(subscriber_email) => {
+                let content = EmailContent::new(base_url, hmac_secret, subscriber_id);
+                recipients.push((email, subscriber_email, content));
+            }
+            Err(error) => {
+                tracing::warn!(
+                    error.cause_chain = ?error,
+                    subscriber_email = %email,
+                    "Skipping a confirmed subscriber. Their stored contact details are invalid."
+                );
+                outcomes.push((email, DeliveryOutcome::Bounced { error }));
+            }
+        }
+    }
+
+    if !recipients.is_empty() {
+        let issue = get_issue(pool, issue_id).await?;
+        let contents = recipients
+            .iter()
+            .map(|(_, _, content)| content.render(&issue))
+            .collect::<Vec<_>>();
+        let batch = recipients
+            .iter()
+            .zip(&contents)
+            .map(|((_, to, content), (html_content, text_content))| Email {
+                to,
+                subject: &issue.title,
+                html_content,
+                text_content,
+                headers: &content.headers,
+            })
+            .collect::<Vec<_>>();
+        let results = email_client.send_batch(&batch).await;
+
+        for ((email, _, _), result) in recipients.into_iter().zip(results) {
+            let outcome = match result {
+                Ok(provider_message_id) => DeliveryOutcome::Sent {
+                    provider_message_id,
+                },
+                Err(e) => {
+                    // recorded and skipped, a failing address must not hold up the rest of the queue
+                    tracing::error!(
+                        error.cause_chain = ?e,
+                        subscriber_email = %email,
+                        "Failed to deliver issue to a confirmed subscriber. Skipping."
+                    );
+                    let error = format!("{:?}", e);
+                    match e {
+                        SendEmailError::Rejected(_) => DeliveryOutcome::Bounced { error },
+                        SendEmailError::Unavailable { .. } => DeliveryOutcome::Failed { error },
+                    }
+                }
+            };
+            outcomes.push((email, outcome));
+        }
+    }
+
+    let n_emails = outcomes.len();
+    let n_sent = outcomes
+        .iter()
+        .filter(|(_, outcome)| matches!(outcome, DeliveryOutcome::Sent { .. }))
+        .count();
+    let n_skipped = outcomes
+        .iter()
+        .filter(|(_, outcome)| matches!(outcome, DeliveryOutcome::Skipped { .. }))
+        .count();
+    complete_tasks(transaction, issue_id, outcomes).await?;
+
+    let elapsed = started.elapsed();
+    tracing::info!(
+        n_sent,
+        n_skipped,
+        n_failed = n_emails - n_sent - n_skipped,
+        elapsed_ms = elapsed.as_millis() as u64,
+        emails_per_second = n_emails as f64 / elapsed.as_secs_f64(),
+        "Completed issue delivery tasks."
+    );
+
+    Ok(ExecutionOutcome::TaskCompleted { n_emails })
+}
+
+/// The per subscriber part of an issue: links to the preference center and to unsubscribe
+/// in the footer, the latter also as `List-Unsubscribe` header so mail clients can offer
+/// one-click unsubscribe (RFC 8058)
+struct EmailContent {
+    preferences_link: String,
+    unsubscribe_link: String,
+    headers: Vec<(&'static str, String)>,
+}
+
+impl EmailContent {
+    fn new(base_url: &str, hmac_secret: &Secret<String>, subscriber_id: Uuid) -> Self {
+        let unsubscribe_link = unsubscribe_link(base_url, hmac_secret, subscriber_id);
+        let headers = vec![
+            ("List-Unsubscribe", format!("<{}>", unsubscribe_link)),
+            ("List-Unsubscribe-Post", "List-Unsubscribe=One-Click".into()),
+        ];
+
+        Self {
+            preferences_link: preferences_link(base_url, hmac_secret, subscriber_id),
+            unsubscribe_link,
+            headers,
+        }
+    }
+
+    /// HTML and plain text content of `issue` with the footer
+    fn render(&self, issue: &NewsletterIssue) -> (String, String) {
+        let html_content = format!(
+            "{}<hr /><p>You are receiving this email because you subscribed to our newsletter. \
+            <a href=\"{}\">Manage your preferences</a> or <a href=\"{}\">unsubscribe</a>.</p>",
+            issue.html_content, self.preferences_link, self.unsubscribe_link
+        );
+        let text_content = format!(
+            "{}\n\n--\nYou are receiving this email because you subscribed to our newsletter.\n\
+            Manage your preferences: {}\n\
+            Unsubscribe: {}",
+            issue.text_content, self.preferences_link, self.unsubscribe_link
+        );
+
+        (html_content, text_content)
+    }
+}
+
+/// What happened to a single email, see the `deliveries` table
+enum DeliveryOutcome {
+    Sent { provider_message_id: Option<String> },
+    Failed { error: String },
+    Bounced { error: String },
+    Skipped { reason: String },
+}
+
+impl DeliveryOutcome {
+    fn status(&self) -> &'static str {
+        match self {
+            Self::Sent { .. } => "sent",
+            Self::Failed { .. } => "failed",
+            Self::Bounced { .. } => "bounced",
+            Self::Skipped { .. } => "skipped",
+        }
+    }
+}
+
+type PgTransaction = Transaction<'static, Postgres>;
+
+struct Task {
+    subscriber_email: String,
+    /// `None` when the subscriber is gone or changed their address since the issue was published
+    subscriber_id: Option<Uuid>,
+    subscriber_status: Option<String>,
+}
+
+/// Up to `batch_size` tasks, all of the same issue so they can share one batch request.
+/// The returned transaction holds the row locks until the tasks are deleted.
+#[tracing::instrument(skip_all)]
+async fn dequeue_tasks(
+    pool: &PgPool,
+    batch_size: usize,
+) -> Result<Option<(PgTransaction, Uuid, Vec<Task>)>, anyhow::Error> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to start a transaction.")?;
+
+    let tasks = sqlx::query!(
+        r#"
+        SELECT
+            q.newsletter_issue_id,
+            q.subscriber_email,
+            s.id AS "subscriber_id?",
+            s.status AS "subscriber_status?"
+        FROM issue_delivery_queue q
+        LEFT JOIN subscriptions s ON s.email = q.subscriber_email
+        WHERE q.newsletter_issue_id = (
+            SELECT newsletter_issue_id
+            FROM issue_delivery_queue
+            FOR UPDATE
+            SKIP LOCKED
+            LIMIT 1
+        )
+        FOR UPDATE OF q
+        SKIP LOCKED
+        LIMIT $1
+        "#,
+        batch_size as i64
+    )
+    .fetch_all(&mut *transaction)
+    .await
+    .context("Failed to dequeue issue delivery tasks.")?;
+
+    let Some(issue_id) = tasks.first().map(|task| task.newsletter_issue_id) else {
+        return Ok(None);
+    };
+    let tasks = tasks
+        .into_iter()
+        .map(|task| Task {
+            subscriber_email: task.subscriber_email,
+            subscriber_id: task.subscriber_id,
+            subscriber_status: task.subscriber_status,
+        })
+        .collect();
+
+    Ok(Some((transaction, issue_id, tasks)))
+}
+
+/// Records the outcomes and removes the tasks, releasing the locks taken in `dequeue_tasks`
+#[tracing::instrument(skip_all)]
+async fn complete_tasks(
+    mut transaction: PgTransaction,
+    issue_id: Uuid,
+    outcomes: Vec<(String, DeliveryOutcome)>,
+) -> Result<(), anyhow::Error> {
+    for (email, outcome) in outcomes {
+        let status = outcome.status();
+        let (last_error, provider_message_id) = match outcome {
+            DeliveryOutcome::Sent {
+                provider_message_id,
+            } => (None, provider_message_id),
+            DeliveryOutcome::Failed { error }
+            | DeliveryOutcome::Bounced { error }
+            | DeliveryOutcome::Skipped { reason: error } => (Some(error), None),
+        };
+
+        sqlx::query!(
+            r#"
+            UPDATE deliveries
+            SET
+                status = $3,
+                n_attempts = n_attempts + 1,
+                last_error = COALESCE($4, last_error),
+                provider_message_id = COALESCE($5, provider_message_id),
+                updated_at = now()
+            WHERE newsletter_issue_id = $1 AND subscriber_email = $2
+            "#,
+            issue_id,
+            email,
+            status,
+            last_error,
+            provider_message_id
+        )
+        .execute(&mut *transaction)
+        .await
+        .context("Failed to record the delivery outcome.")?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM issue_delivery_queue
+            WHERE newsletter_issue_id = $1 AND subscriber_email = $2
+            "#,
+            issue_id,
+            email
+        )
+        .execute(&mut *transaction)
+        .await
+        .context("Failed to delete a completed issue delivery task.")?;
+    }
+
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the issue delivery transaction.")?;
+
+    Ok(())
+}
+
+struct NewsletterIssue {
+    title: String,
+    text_content: String,
+    html_content: String,
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_issue(pool: &PgPool, issue_id: Uuid) -> Result<NewsletterIssue, anyhow::Error> {
+    let issue = sqlx::query_as!(
+        NewsletterIssue,
+        r#"
+        SELECT title, text_content, html_content
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1
+        "#,
+        issue_id
+    )
+    .fetch_one(pool)
+    .await
+    .context("Failed to load the newsletter issue.")?;
+
+    Ok(issue)
+}
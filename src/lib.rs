@@ -1,8 +1,17 @@
 pub mod authentication;
+pub mod cli;
 pub mod configuration;
+pub mod csrf;
 pub mod domain;
 pub mod email_client;
 pub mod errors;
+pub mod idempotency;
+pub mod issue_delivery_worker;
 pub mod routes;
+pub mod session_state;
+pub mod session_store;
 pub mod startup;
+pub mod subscriber_token;
 pub mod telemetry;
+pub mod topics;
+pub mod utils;
@@ -1,18 +1,24 @@
-use rust2prod::{configuration::get_configuration};
-use rust2prod::startup::{Application};
+use clap::Parser;
+use rust2prod::cli::{run, Cli, Command};
+use rust2prod::configuration::get_configuration;
 use rust2prod::telemetry::{get_subscriber, init_subscriber_once};
 
 #[tokio::main]
-async fn main() -> Result<(), std::io::Error> {
+async fn main() -> Result<(), anyhow::Error> {
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Serve);
+
     // configure tracing
-    let subscriber = get_subscriber("rust2prod".into(), "info".into(), std::io::stdout);
-    init_subscriber_once(subscriber);
+    if command.logs_to_stdout() {
+        let subscriber = get_subscriber("rust2prod".into(), "info".into(), std::io::stdout);
+        init_subscriber_once(subscriber);
+    } else {
+        let subscriber = get_subscriber("rust2prod".into(), "warn".into(), std::io::stderr);
+        init_subscriber_once(subscriber);
+    }
 
     // read configuration
     let configuration = get_configuration().expect("Failed to read configuration");
-    let application = Application::build(configuration).await?;
-
-    application.run_until_stopped().await?;
 
-    Ok(())
+    run(command, configuration).await
 }
@@ -0,0 +1,61 @@
+use actix_web::{http::header::ContentType, web, HttpResponse};
+use actix_web_flash_messages::{IncomingFlashMessages, Level};
+use askama::Template;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::{ApiTokensPage, EXPIRY_OPTIONS};
+use crate::authentication::{get_username, list_api_tokens, UserId};
+use crate::csrf::CsrfToken;
+use crate::routes::home::AppLayout;
+use crate::utils::{e500, flash_messages_with_level};
+
+pub async fn api_tokens_form(
+    flash_messages: IncomingFlashMessages,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse, actix_web::Error> {
+    render_api_tokens_page(
+        &pool,
+        **user_id,
+        &csrf_token,
+        None,
+        flash_messages_with_level(&flash_messages, Level::Info),
+        flash_messages_with_level(&flash_messages, Level::Error),
+    )
+    .await
+}
+
+/// Also used right after creating a token, which is the only time it is shown in plain text
+pub(super) async fn render_api_tokens_page(
+    pool: &PgPool,
+    user_id: Uuid,
+    csrf_token: &CsrfToken,
+    new_token: Option<&str>,
+    info_messages: Vec<&str>,
+    error_messages: Vec<&str>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let username = get_username(user_id, pool).await.map_err(e500)?;
+    let tokens = list_api_tokens(pool, user_id).await.map_err(e500)?;
+
+    let page = ApiTokensPage {
+        csrf_token: csrf_token.as_str(),
+        new_token,
+        tokens: tokens.into_iter().map(Into::into).collect(),
+        expiry_options: &EXPIRY_OPTIONS,
+        info_messages,
+        error_messages,
+    };
+
+    let layout = AppLayout {
+        title: "API tokens",
+        user: Some(username),
+        body: &page.render().unwrap(),
+        csrf_token: csrf_token.as_str(),
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(layout.render().unwrap()))
+}
@@ -0,0 +1,59 @@
+mod get;
+mod post;
+
+pub use get::api_tokens_form;
+pub use post::{create_api_token, revoke_api_token};
+
+use askama::Template;
+
+use crate::authentication::ApiTokenInfo;
+
+/// Expiry choices offered by the form, in days
+const EXPIRY_OPTIONS: [i64; 3] = [30, 90, 365];
+
+#[derive(Template)]
+#[template(path = "admin/api_tokens.html")]
+struct ApiTokensPage<'a> {
+    csrf_token: &'a str,
+    new_token: Option<&'a str>,
+    tokens: Vec<ApiTokenRow>,
+    expiry_options: &'a [i64],
+    info_messages: Vec<&'a str>,
+    error_messages: Vec<&'a str>,
+}
+
+struct ApiTokenRow {
+    token_id: String,
+    name: String,
+    scopes: String,
+    created_at: String,
+    expires_at: String,
+    last_used_at: String,
+    status: &'static str,
+}
+
+impl From<ApiTokenInfo> for ApiTokenRow {
+    fn from(token: ApiTokenInfo) -> Self {
+        let format = |t: chrono::DateTime<chrono::Utc>| t.format("%Y-%m-%d %H:%M UTC").to_string();
+        let status = if token.revoked_at.is_some() {
+            "revoked"
+        } else if token.expires_at <= chrono::Utc::now() {
+            "expired"
+        } else {
+            "active"
+        };
+
+        Self {
+            token_id: token.token_id.to_string(),
+            name: token.name,
+            scopes: token.scopes.join(", "),
+            created_at: format(token.created_at),
+            expires_at: format(token.expires_at),
+            last_used_at: token
+                .last_used_at
+                .map(format)
+                .unwrap_or_else(|| "never".into()),
+            status,
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::get::render_api_tokens_page;
+use super::EXPIRY_OPTIONS;
+use crate::authentication::{self, ApiScope, UserId};
+use crate::csrf::CsrfToken;
+use crate::utils::{e500, see_other};
+
+#[derive(serde::Deserialize)]
+pub struct CreateApiTokenData {
+    name: String,
+    // checkboxes, only present when ticked
+    scope_newsletters_publish: Option<String>,
+    scope_subscribers_read: Option<String>,
+    expires_in_days: i64,
+}
+
+impl CreateApiTokenData {
+    fn scopes(&self) -> Vec<ApiScope> {
+        let mut scopes = Vec::new();
+        if self.scope_newsletters_publish.is_some() {
+            scopes.push(ApiScope::NewslettersPublish);
+        }
+        if self.scope_subscribers_read.is_some() {
+            scopes.push(ApiScope::SubscribersRead);
+        }
+        scopes
+    }
+}
+
+#[tracing::instrument(name = "Create API token", skip(form, pool, csrf_token), fields(user_id=%*user_id))]
+pub async fn create_api_token(
+    form: web::Form<CreateApiTokenData>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = **user_id;
+    let name = form.name.trim();
+    let scopes = form.scopes();
+
+    if name.is_empty() || name.chars().count() > 100 {
+        FlashMessage::error("The token name must be between 1 and 100 characters long.").send();
+        return Ok(see_other("/admin/tokens"));
+    }
+    if scopes.is_empty() {
+        FlashMessage::error("Select at least one scope for the token.").send();
+        return Ok(see_other("/admin/tokens"));
+    }
+    if !EXPIRY_OPTIONS.contains(&form.expires_in_days) {
+        FlashMessage::error("Choose one of the offered expiry periods.").send();
+        return Ok(see_other("/admin/tokens"));
+    }
+
+    let expires_at = chrono::Utc::now() + chrono::Duration::days(form.expires_in_days);
+    let token = authentication::create_api_token(&pool, user_id, name, &scopes, expires_at)
+        .await
+        .map_err(e500)?;
+
+    // rendered directly instead of redirecting, the token must not end up in a flash cookie
+    render_api_tokens_page(
+        &pool,
+        user_id,
+        &csrf_token,
+        Some(&token),
+        vec!["Your new API token has been created."],
+        Vec::new(),
+    )
+    .await
+}
+
+#[tracing::instrument(name = "Revoke API token", skip(pool), fields(user_id=%*user_id))]
+pub async fn revoke_api_token(
+    token_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let revoked = authentication::revoke_api_token(&pool, **user_id, token_id.into_inner())
+        .await
+        .map_err(e500)?;
+
+    if revoked {
+        FlashMessage::info("The API token has been revoked.").send();
+    } else {
+        FlashMessage::error("The API token does not exist or was already revoked.").send();
+    }
+
+    Ok(see_other("/admin/tokens"))
+}
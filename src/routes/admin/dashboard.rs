@@ -0,0 +1,26 @@
+use actix_web::{http::header::ContentType, web, HttpResponse};
+use askama::Template;
+use sqlx::PgPool;
+
+use crate::authentication::{get_username, UserId};
+use crate::csrf::CsrfToken;
+use crate::routes::home::AppLayout;
+use crate::utils::e500;
+
+pub async fn admin_dashboard(
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse, actix_web::Error> {
+    let username = get_username(**user_id, &pool).await.map_err(e500)?;
+
+    let dashboard = AppLayout {
+        title: "Dashboard",
+        user: Some(username),
+        body: include_str!("dashboard.html"),
+        csrf_token: csrf_token.as_str(),
+    };
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(dashboard.render().unwrap()))
+}
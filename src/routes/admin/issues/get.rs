@@ -0,0 +1,121 @@
+use actix_web::{http::header::ContentType, web, HttpResponse};
+use askama::Template;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::{
+    format_timestamp, get_issue_details, get_issue_progress, IssueProgressFragment, IssueRow,
+    NewsletterIssuePage, NewsletterIssuesPage,
+};
+use crate::authentication::{get_username, UserId};
+use crate::csrf::CsrfToken;
+use crate::routes::home::AppLayout;
+use crate::utils::e500;
+
+pub async fn newsletter_issues(
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse, actix_web::Error> {
+    let username = get_username(**user_id, &pool).await.map_err(e500)?;
+
+    let issues = sqlx::query!(
+        r#"
+        SELECT
+            i.newsletter_issue_id,
+            i.title,
+            i.published_at,
+            COUNT(d.subscriber_email) AS "total!",
+            COUNT(*) FILTER (WHERE d.status = 'sent') AS "sent!",
+            COUNT(*) FILTER (WHERE d.status = 'failed') AS "failed!",
+            COUNT(*) FILTER (WHERE d.status = 'bounced') AS "bounced!"
+        FROM newsletter_issues i
+        LEFT JOIN deliveries d USING (newsletter_issue_id)
+        GROUP BY i.newsletter_issue_id
+        ORDER BY i.published_at DESC
+        "#
+    )
+    .fetch_all(pool.get_ref())
+    .await
+    .map_err(e500)?
+    .into_iter()
+    .map(|row| IssueRow {
+        newsletter_issue_id: row.newsletter_issue_id.to_string(),
+        title: row.title,
+        published_at: format_timestamp(row.published_at),
+        total: row.total,
+        sent: row.sent,
+        failed: row.failed,
+        bounced: row.bounced,
+    })
+    .collect();
+
+    let page = NewsletterIssuesPage { issues };
+
+    let layout = AppLayout {
+        title: "Newsletter issues",
+        user: Some(username),
+        body: &page.render().unwrap(),
+        csrf_token: csrf_token.as_str(),
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(layout.render().unwrap()))
+}
+
+pub async fn newsletter_issue(
+    issue_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse, actix_web::Error> {
+    let issue_id = issue_id.into_inner();
+    let Some(issue) = get_issue_details(&pool, issue_id).await.map_err(e500)? else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    let username = get_username(**user_id, &pool).await.map_err(e500)?;
+    let progress = get_issue_progress(&pool, issue_id).await.map_err(e500)?;
+
+    let page = NewsletterIssuePage {
+        published_at: &format_timestamp(issue.published_at),
+        progress,
+        notice: None,
+    };
+
+    let layout = AppLayout {
+        title: &issue.title,
+        user: Some(username),
+        body: &page.render().unwrap(),
+        csrf_token: csrf_token.as_str(),
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(layout.render().unwrap()))
+}
+
+// returns htmx fragment
+pub async fn issue_progress(
+    issue_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let issue_id = issue_id.into_inner();
+    if get_issue_details(&pool, issue_id)
+        .await
+        .map_err(e500)?
+        .is_none()
+    {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+    let progress = get_issue_progress(&pool, issue_id).await.map_err(e500)?;
+
+    let fragment = IssueProgressFragment {
+        progress,
+        notice: None,
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(fragment.render().unwrap()))
+}
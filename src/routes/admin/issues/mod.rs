@@ -0,0 +1,155 @@
+mod get;
+mod post;
+
+pub use get::{issue_progress, newsletter_issue, newsletter_issues};
+pub use post::requeue_failed_deliveries;
+
+use askama::Template;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+fn format_timestamp(t: chrono::DateTime<chrono::Utc>) -> String {
+    t.format("%Y-%m-%d %H:%M UTC").to_string()
+}
+
+#[derive(Template)]
+#[template(path = "admin/issues.html")]
+struct NewsletterIssuesPage {
+    issues: Vec<IssueRow>,
+}
+
+struct IssueRow {
+    newsletter_issue_id: String,
+    title: String,
+    published_at: String,
+    total: i64,
+    sent: i64,
+    failed: i64,
+    bounced: i64,
+}
+
+#[derive(Template)]
+#[template(path = "admin/issue.html")]
+struct NewsletterIssuePage<'a> {
+    published_at: &'a str,
+    progress: IssueProgress,
+    notice: Option<&'a str>,
+}
+
+/// Re-rendered by htmx while the issue is being delivered
+#[derive(Template)]
+#[template(path = "admin/issue_progress.html")]
+struct IssueProgressFragment<'a> {
+    progress: IssueProgress,
+    notice: Option<&'a str>,
+}
+
+struct IssueProgress {
+    issue_id: String,
+    queued: i64,
+    sent: i64,
+    failed: i64,
+    bounced: i64,
+    skipped: i64,
+    total: i64,
+    /// everything that didn't go out (yet), most recent first
+    problems: Vec<DeliveryRow>,
+}
+
+impl IssueProgress {
+    fn percent_done(&self) -> i64 {
+        if self.total == 0 {
+            return 100;
+        }
+        (self.total - self.queued) * 100 / self.total
+    }
+}
+
+struct DeliveryRow {
+    subscriber_email: String,
+    status: String,
+    n_attempts: i32,
+    last_error: String,
+    updated_at: String,
+}
+
+/// Only so many problem rows are shown, the counts are always complete
+const MAX_PROBLEM_ROWS: i64 = 500;
+
+#[tracing::instrument(name = "Load issue progress", skip(pool))]
+async fn get_issue_progress(pool: &PgPool, issue_id: Uuid) -> Result<IssueProgress, anyhow::Error> {
+    let counts = sqlx::query!(
+        r#"
+        SELECT
+            COUNT(*) FILTER (WHERE status = 'queued') AS "queued!",
+            COUNT(*) FILTER (WHERE status = 'sent') AS "sent!",
+            COUNT(*) FILTER (WHERE status = 'failed') AS "failed!",
+            COUNT(*) FILTER (WHERE status = 'bounced') AS "bounced!",
+            COUNT(*) FILTER (WHERE status = 'skipped') AS "skipped!",
+            COUNT(*) AS "total!"
+        FROM deliveries
+        WHERE newsletter_issue_id = $1
+        "#,
+        issue_id
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let problems = sqlx::query!(
+        r#"
+        SELECT subscriber_email, status, n_attempts, last_error, updated_at
+        FROM deliveries
+        WHERE newsletter_issue_id = $1 AND status IN ('failed', 'bounced', 'skipped')
+        ORDER BY updated_at DESC
+        LIMIT $2
+        "#,
+        issue_id,
+        MAX_PROBLEM_ROWS
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| DeliveryRow {
+        subscriber_email: row.subscriber_email,
+        status: row.status,
+        n_attempts: row.n_attempts,
+        last_error: row.last_error.unwrap_or_default(),
+        updated_at: format_timestamp(row.updated_at),
+    })
+    .collect();
+
+    Ok(IssueProgress {
+        issue_id: issue_id.to_string(),
+        queued: counts.queued,
+        sent: counts.sent,
+        failed: counts.failed,
+        bounced: counts.bounced,
+        skipped: counts.skipped,
+        total: counts.total,
+        problems,
+    })
+}
+
+struct IssueDetails {
+    title: String,
+    published_at: chrono::DateTime<chrono::Utc>,
+}
+
+async fn get_issue_details(
+    pool: &PgPool,
+    issue_id: Uuid,
+) -> Result<Option<IssueDetails>, anyhow::Error> {
+    let issue = sqlx::query_as!(
+        IssueDetails,
+        r#"
+        SELECT title, published_at
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1
+        "#,
+        issue_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(issue)
+}
@@ -0,0 +1,62 @@
+use actix_web::{http::header::ContentType, web, HttpResponse};
+use askama::Template;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::{get_issue_details, get_issue_progress, IssueProgressFragment};
+use crate::authentication::UserId;
+use crate::utils::e500;
+
+// returns htmx fragment
+#[tracing::instrument(name = "Re-queue failed deliveries", skip(pool), fields(user_id=%*user_id))]
+pub async fn requeue_failed_deliveries(
+    issue_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let issue_id = issue_id.into_inner();
+    if get_issue_details(&pool, issue_id)
+        .await
+        .map_err(e500)?
+        .is_none()
+    {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    // bounced addresses stay as they are, sending to them again won't help
+    let n_requeued = sqlx::query!(
+        r#"
+        WITH requeued AS (
+            UPDATE deliveries
+            SET status = 'queued', updated_at = now()
+            WHERE newsletter_issue_id = $1 AND status = 'failed'
+            RETURNING newsletter_issue_id, subscriber_email
+        )
+        INSERT INTO issue_delivery_queue (newsletter_issue_id, subscriber_email)
+        SELECT newsletter_issue_id, subscriber_email
+        FROM requeued
+        ON CONFLICT DO NOTHING
+        "#,
+        issue_id
+    )
+    .execute(pool.get_ref())
+    .await
+    .map_err(e500)?
+    .rows_affected();
+    tracing::info!(n_requeued, "Re-queued failed deliveries.");
+
+    let progress = get_issue_progress(&pool, issue_id).await.map_err(e500)?;
+    let notice = match n_requeued {
+        1 => "1 recipient has been re-queued.".to_string(),
+        n => format!("{} recipients have been re-queued.", n),
+    };
+
+    let fragment = IssueProgressFragment {
+        progress,
+        notice: Some(&notice),
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(fragment.render().unwrap()))
+}
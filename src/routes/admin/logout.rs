@@ -0,0 +1,30 @@
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use sqlx::PgPool;
+
+use crate::authentication::UserId;
+use crate::session_state::TypedSession;
+use crate::session_store::revoke_user_sessions;
+use crate::utils::{e500, see_other};
+
+pub async fn log_out(session: TypedSession) -> HttpResponse {
+    session.log_out();
+    FlashMessage::info("You have successfully logged out.").send();
+
+    see_other("/login")
+}
+
+#[tracing::instrument(name = "Log out everywhere", skip(session, pool), fields(user_id=%*user_id))]
+pub async fn log_out_everywhere(
+    session: TypedSession,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, actix_web::Error> {
+    revoke_user_sessions(pool.get_ref(), **user_id)
+        .await
+        .map_err(e500)?;
+    session.log_out();
+    FlashMessage::info("You have been logged out on all devices.").send();
+
+    Ok(see_other("/login"))
+}
@@ -0,0 +1,13 @@
+mod api_tokens;
+mod dashboard;
+mod issues;
+mod logout;
+mod password;
+mod two_factor;
+
+pub use api_tokens::*;
+pub use dashboard::*;
+pub use issues::*;
+pub use logout::*;
+pub use password::*;
+pub use two_factor::*;
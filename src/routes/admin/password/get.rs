@@ -0,0 +1,36 @@
+use actix_web::{http::header::ContentType, web, HttpResponse};
+use actix_web_flash_messages::{IncomingFlashMessages, Level};
+use askama::Template;
+use sqlx::PgPool;
+
+use super::ChangePasswordPage;
+use crate::authentication::{get_username, UserId};
+use crate::csrf::CsrfToken;
+use crate::routes::home::AppLayout;
+use crate::utils::{e500, flash_messages_with_level};
+
+pub async fn change_password_form(
+    flash_messages: IncomingFlashMessages,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse, actix_web::Error> {
+    let username = get_username(**user_id, &pool).await.map_err(e500)?;
+
+    let page = ChangePasswordPage {
+        csrf_token: csrf_token.as_str(),
+        info_messages: flash_messages_with_level(&flash_messages, Level::Info),
+        error_messages: flash_messages_with_level(&flash_messages, Level::Error),
+    };
+
+    let layout = AppLayout {
+        title: "Change password",
+        user: Some(username),
+        body: &page.render().unwrap(),
+        csrf_token: csrf_token.as_str(),
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(layout.render().unwrap()))
+}
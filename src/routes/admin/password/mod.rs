@@ -0,0 +1,15 @@
+mod get;
+mod post;
+
+pub use get::change_password_form;
+pub use post::change_password;
+
+use askama::Template;
+
+#[derive(Template)]
+#[template(path = "admin/password.html")]
+struct ChangePasswordPage<'a> {
+    csrf_token: &'a str,
+    info_messages: Vec<&'a str>,
+    error_messages: Vec<&'a str>,
+}
@@ -0,0 +1,78 @@
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+
+use crate::authentication::{
+    self, get_username, validate_credentials, AuthError, Credentials, PasswordHashing, UserId,
+};
+use crate::domain::NewPassword;
+use crate::session_state::TypedSession;
+use crate::session_store::revoke_user_sessions;
+use crate::utils::{e500, see_other};
+
+#[derive(serde::Deserialize)]
+pub struct ChangePasswordData {
+    current_password: Secret<String>,
+    new_password: Secret<String>,
+    new_password_check: Secret<String>,
+}
+
+#[tracing::instrument(name = "Change password", skip(form, pool, hashing, session), fields(user_id=%*user_id))]
+pub async fn change_password(
+    form: web::Form<ChangePasswordData>,
+    pool: web::Data<PgPool>,
+    hashing: web::Data<PasswordHashing>,
+    session: TypedSession,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = **user_id;
+
+    if form.new_password.expose_secret() != form.new_password_check.expose_secret() {
+        FlashMessage::error(
+            "You entered two different new passwords - the field values must match.",
+        )
+        .send();
+        return Ok(see_other("/admin/password"));
+    }
+
+    let new_password = match NewPassword::parse(form.0.new_password) {
+        Ok(password) => password,
+        Err(error_message) => {
+            FlashMessage::error(error_message).send();
+            return Ok(see_other("/admin/password"));
+        }
+    };
+
+    let username = get_username(user_id, &pool).await.map_err(e500)?;
+    let credentials = Credentials {
+        username,
+        password: form.0.current_password,
+    };
+
+    if let Err(e) = validate_credentials(credentials, &hashing, &pool).await {
+        return match e {
+            AuthError::InvalidCredentials(_) => {
+                FlashMessage::error("The current password is incorrect.").send();
+                Ok(see_other("/admin/password"))
+            }
+            AuthError::UnexpectedError(_) => Err(e500(e)),
+        };
+    }
+
+    authentication::change_password(user_id, new_password, &hashing, pool.get_ref())
+        .await
+        .map_err(e500)?;
+
+    // sessions on other devices were established with the old password, drop them
+    // and hand a fresh session to the current one
+    revoke_user_sessions(pool.get_ref(), user_id)
+        .await
+        .map_err(e500)?;
+    session.renew();
+    session.insert_user_id(user_id).map_err(e500)?;
+
+    FlashMessage::info("Your password has been changed.").send();
+
+    Ok(see_other("/admin/password"))
+}
@@ -0,0 +1,75 @@
+use actix_web::{http::header::ContentType, web, HttpResponse};
+use actix_web_flash_messages::{IncomingFlashMessages, Level};
+use askama::Template;
+use sqlx::PgPool;
+
+use super::{TwoFactorEnabledPage, TwoFactorSetupPage};
+use crate::authentication::{
+    begin_totp_enrollment, get_totp_enrollment, get_username, TotpEncryptionKey, UserId,
+};
+use crate::csrf::CsrfToken;
+use crate::routes::home::AppLayout;
+use crate::utils::{e500, flash_messages_with_level};
+
+pub async fn two_factor_form(
+    flash_messages: IncomingFlashMessages,
+    pool: web::Data<PgPool>,
+    key: web::Data<TotpEncryptionKey>,
+    user_id: web::ReqData<UserId>,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = **user_id;
+    let username = get_username(user_id, &pool).await.map_err(e500)?;
+    let info_messages = flash_messages_with_level(&flash_messages, Level::Info);
+    let error_messages = flash_messages_with_level(&flash_messages, Level::Error);
+
+    // keep a pending secret around, reloading the page must not invalidate an already scanned code
+    let enrollment = match get_totp_enrollment(&pool, &key, user_id)
+        .await
+        .map_err(e500)?
+    {
+        Some(enrollment) => enrollment,
+        None => {
+            begin_totp_enrollment(&pool, &key, user_id)
+                .await
+                .map_err(e500)?;
+            get_totp_enrollment(&pool, &key, user_id)
+                .await
+                .map_err(e500)?
+                .ok_or_else(|| e500("The TOTP secret was not stored."))?
+        }
+    };
+
+    let body = if enrollment.enabled {
+        TwoFactorEnabledPage {
+            csrf_token: csrf_token.as_str(),
+            recovery_codes: Vec::new(),
+            info_messages,
+            error_messages,
+        }
+        .render()
+        .unwrap()
+    } else {
+        TwoFactorSetupPage {
+            csrf_token: csrf_token.as_str(),
+            qr_code: &enrollment.qr_code_base64().map_err(e500)?,
+            secret: &enrollment.secret_base32().map_err(e500)?,
+            otpauth_uri: &enrollment.otpauth_uri().map_err(e500)?,
+            info_messages,
+            error_messages,
+        }
+        .render()
+        .unwrap()
+    };
+
+    let layout = AppLayout {
+        title: "Two-factor authentication",
+        user: Some(username),
+        body: &body,
+        csrf_token: csrf_token.as_str(),
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(layout.render().unwrap()))
+}
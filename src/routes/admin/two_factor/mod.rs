@@ -0,0 +1,27 @@
+mod get;
+mod post;
+
+pub use get::two_factor_form;
+pub use post::{disable_two_factor, enable_two_factor};
+
+use askama::Template;
+
+#[derive(Template)]
+#[template(path = "admin/two_factor_setup.html")]
+struct TwoFactorSetupPage<'a> {
+    csrf_token: &'a str,
+    qr_code: &'a str,
+    secret: &'a str,
+    otpauth_uri: &'a str,
+    info_messages: Vec<&'a str>,
+    error_messages: Vec<&'a str>,
+}
+
+#[derive(Template)]
+#[template(path = "admin/two_factor_enabled.html")]
+struct TwoFactorEnabledPage<'a> {
+    csrf_token: &'a str,
+    recovery_codes: Vec<String>,
+    info_messages: Vec<&'a str>,
+    error_messages: Vec<&'a str>,
+}
@@ -0,0 +1,92 @@
+use actix_web::{http::header::ContentType, web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use askama::Template;
+use sqlx::PgPool;
+
+use super::TwoFactorEnabledPage;
+use crate::authentication::{
+    disable_totp, enable_totp, get_username, is_totp_enabled, verify_second_factor,
+    verify_totp_code, TotpEncryptionKey, UserId,
+};
+use crate::csrf::CsrfToken;
+use crate::routes::home::AppLayout;
+use crate::utils::{e500, see_other};
+
+#[derive(serde::Deserialize)]
+pub struct TwoFactorCodeData {
+    code: String,
+}
+
+#[tracing::instrument(name = "Enable two-factor authentication", skip(form, pool, key, csrf_token), fields(user_id=%*user_id))]
+pub async fn enable_two_factor(
+    form: web::Form<TwoFactorCodeData>,
+    pool: web::Data<PgPool>,
+    key: web::Data<TotpEncryptionKey>,
+    user_id: web::ReqData<UserId>,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = **user_id;
+
+    if is_totp_enabled(&pool, user_id).await.map_err(e500)? {
+        FlashMessage::error("Two-factor authentication is already turned on.").send();
+        return Ok(see_other("/admin/two-factor"));
+    }
+
+    // proves that the authenticator app was set up correctly before we rely on it
+    if !verify_totp_code(&pool, &key, user_id, form.code.trim())
+        .await
+        .map_err(e500)?
+    {
+        FlashMessage::error("Invalid authentication code, please try again.").send();
+        return Ok(see_other("/admin/two-factor"));
+    }
+
+    let recovery_codes = enable_totp(&pool, user_id).await.map_err(e500)?;
+    let username = get_username(user_id, &pool).await.map_err(e500)?;
+
+    // the recovery codes are rendered right away, they only exist in plain text in this response
+    let page = TwoFactorEnabledPage {
+        csrf_token: csrf_token.as_str(),
+        recovery_codes,
+        info_messages: vec!["Two-factor authentication has been turned on."],
+        error_messages: Vec::new(),
+    };
+
+    let layout = AppLayout {
+        title: "Two-factor authentication",
+        user: Some(username),
+        body: &page.render().unwrap(),
+        csrf_token: csrf_token.as_str(),
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(layout.render().unwrap()))
+}
+
+#[tracing::instrument(name = "Disable two-factor authentication", skip(form, pool, key), fields(user_id=%*user_id))]
+pub async fn disable_two_factor(
+    form: web::Form<TwoFactorCodeData>,
+    pool: web::Data<PgPool>,
+    key: web::Data<TotpEncryptionKey>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = **user_id;
+
+    if !is_totp_enabled(&pool, user_id).await.map_err(e500)? {
+        return Ok(see_other("/admin/two-factor"));
+    }
+
+    if !verify_second_factor(&pool, &key, user_id, &form.code)
+        .await
+        .map_err(e500)?
+    {
+        FlashMessage::error("Invalid authentication code, please try again.").send();
+        return Ok(see_other("/admin/two-factor"));
+    }
+
+    disable_totp(&pool, user_id).await.map_err(e500)?;
+    FlashMessage::info("Two-factor authentication has been turned off.").send();
+
+    Ok(see_other("/admin/two-factor"))
+}
@@ -5,4 +5,4 @@ pub async fn static_files(req: HttpRequest) -> actix_web::Result<NamedFile> {
     let path: std::path::PathBuf = req.match_info().query("filename").parse().unwrap();
     let file = NamedFile::open(path)?;
     Ok(file.use_last_modified(true))
-}
\ No newline at end of file
+}
@@ -1,21 +1,38 @@
-use actix_web::{http::header::ContentType, HttpResponse};
+use actix_web::{http::header::ContentType, web, HttpResponse};
 use askama::Template;
+use sqlx::PgPool;
+
+use crate::authentication::get_username;
+use crate::csrf::CsrfToken;
+use crate::session_state::TypedSession;
+use crate::utils::e500;
 
 #[derive(Template)]
 #[template(path = "app.html", escape = "none")]
-struct AppLayout<'a> {
-    title: &'a str,
-    user: Option<String>,
-    body: &'a str,
+pub(crate) struct AppLayout<'a> {
+    pub title: &'a str,
+    pub user: Option<String>,
+    pub body: &'a str,
+    pub csrf_token: &'a str,
 }
 
-pub async fn home() -> HttpResponse {
+pub async fn home(
+    session: TypedSession,
+    pool: web::Data<PgPool>,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user = match session.get_user_id().map_err(e500)? {
+        Some(user_id) => Some(get_username(user_id, &pool).await.map_err(e500)?),
+        None => None,
+    };
+
     let home = AppLayout {
         title: "Homepage",
-        user: None,
+        user,
         body: include_str!("home.html"),
+        csrf_token: csrf_token.as_str(),
     };
-    HttpResponse::Ok()
+    Ok(HttpResponse::Ok()
         .content_type(ContentType::html())
-        .body(home.render().unwrap())
+        .body(home.render().unwrap()))
 }
@@ -4,8 +4,12 @@ use askama::Template;
 use std::fmt::Write;
 
 use super::{AuthLayout, LoginPage};
+use crate::csrf::CsrfToken;
 
-pub async fn login_form(flash_messages: IncomingFlashMessages) -> HttpResponse {
+pub async fn login_form(
+    flash_messages: IncomingFlashMessages,
+    csrf_token: CsrfToken,
+) -> HttpResponse {
     // if the login page is openend directly with a flash cookie, we need to display it as an error message
     let mut error_html = String::new();
 
@@ -18,13 +22,28 @@ pub async fn login_form(flash_messages: IncomingFlashMessages) -> HttpResponse {
         htmlescape::encode_minimal(error_html.as_str())
     );
 
+    // info messages (e.g. after logging out) are shown on top of the error fragment
+    let mut messages_html = String::new();
+
+    for message in flash_messages.iter().filter(|m| m.level() == Level::Info) {
+        write!(
+            messages_html,
+            include_str!("fragments/login_info.htmx.html"),
+            htmlescape::encode_minimal(message.content())
+        )
+        .unwrap();
+    }
+
+    messages_html.push_str(&error_html);
+
     let page = LoginPage {
-        error_message: &error_html,
+        error_message: &messages_html,
     };
 
     let login = AuthLayout {
         title: "Login",
         body: &page.render().unwrap(),
+        csrf_token: csrf_token.as_str(),
     };
 
     HttpResponse::Ok()
@@ -1,16 +1,19 @@
 mod get;
 mod post;
+mod two_factor;
 
 pub use get::login_form;
 pub use post::login_post;
+pub use two_factor::login_two_factor;
 
 use askama::Template;
 
 #[derive(Template)]
 #[template(path = "auth.html", escape = "none")]
-struct AuthLayout<'a> {
-    title: &'a str,
-    body: &'a str,
+pub(crate) struct AuthLayout<'a> {
+    pub title: &'a str,
+    pub body: &'a str,
+    pub csrf_token: &'a str,
 }
 
 #[derive(Template)]
@@ -1,15 +1,19 @@
 use actix_web::{
     error::InternalError,
     http::header::{self, ContentType},
-    web, HttpResponse,
+    web, HttpRequest, HttpResponse,
 };
 use actix_web_flash_messages::FlashMessage;
 use secrecy::Secret;
 use sqlx::PgPool;
 
 use crate::{
-    authentication::{validate_credentials, AuthError, Credentials},
+    authentication::{
+        check_login_throttle, clear_failed_logins, client_ip, is_totp_enabled, record_failed_login,
+        validate_credentials, AuthError, Credentials, Lockout, PasswordHashing,
+    },
     errors::error_chain_fmt,
+    session_state::{PendingTwoFactor, TypedSession},
 };
 
 #[derive(serde::Deserialize)]
@@ -22,6 +26,8 @@ pub struct LoginData {
 pub enum LoginError {
     #[error("Invalid credentials")]
     AuthError(#[source] anyhow::Error),
+    #[error("Too many failed login attempts")]
+    Throttled(Lockout),
     #[error("Unexpected error occurred")]
     UnexpectedError(#[from] anyhow::Error),
 }
@@ -35,23 +41,81 @@ impl std::fmt::Debug for LoginError {
 // returns htmx fragment
 #[tracing::instrument(
     name = "Login",
-    skip(form, pool),
-    fields(username=tracing::field::Empty, email=tracing::field::Empty)
+    skip(form, pool, hashing, session, request),
+    fields(
+        username=tracing::field::Empty,
+        user_id=tracing::field::Empty,
+        ip_address=tracing::field::Empty,
+        lockout=tracing::field::Empty,
+        retry_after_secs=tracing::field::Empty
+    )
 )]
 pub async fn login_post(
     form: web::Form<LoginData>,
     pool: web::Data<PgPool>,
+    hashing: web::Data<PasswordHashing>,
+    session: TypedSession,
+    request: HttpRequest,
 ) -> Result<HttpResponse, InternalError<LoginError>> {
     let credentials = Credentials {
         username: form.0.email,
         password: form.0.password,
     };
+    let username = credentials.username.clone();
+    let ip_address = client_ip(&request);
 
-    tracing::Span::current().record("username", &tracing::field::display(&credentials.username));
+    tracing::Span::current().record("username", tracing::field::display(&username));
+    tracing::Span::current().record("ip_address", tracing::field::display(&ip_address));
 
-    match validate_credentials(credentials, &pool).await {
+    // throttled attempts don't even get to the (expensive) password check
+    if let Some(lockout) = check_login_throttle(&pool, &username, &ip_address)
+        .await
+        .map_err(|e| login_redirect(LoginError::UnexpectedError(e)))?
+    {
+        return Err(too_many_attempts(lockout));
+    }
+
+    match validate_credentials(credentials, &hashing, &pool).await {
         Ok(user_id) => {
-            tracing::Span::current().record("user_id", &tracing::field::display(&user_id));
+            tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+
+            let two_factor_enabled = is_totp_enabled(&pool, user_id)
+                .await
+                .map_err(|e| login_redirect(LoginError::UnexpectedError(e)))?;
+
+            // with a second factor the backoff is only reset once that is passed as well,
+            // otherwise every password login would buy another round of code guesses
+            if !two_factor_enabled {
+                clear_failed_logins(&pool, &username)
+                    .await
+                    .map_err(|e| login_redirect(LoginError::UnexpectedError(e)))?;
+            }
+
+            // new session key on login, so a planted session id can't be carried over (session fixation)
+            session.renew();
+
+            if two_factor_enabled {
+                // the password was right, but the session is only established after the second factor
+                session
+                    .insert_pending_two_factor(&PendingTwoFactor {
+                        user_id,
+                        username,
+                        started_at: chrono::Utc::now(),
+                        failed_attempts: 0,
+                    })
+                    .map_err(|e| login_redirect(LoginError::UnexpectedError(e.into())))?;
+
+                // swap the login form for the code form
+                return Ok(HttpResponse::Ok()
+                    .insert_header(("HX-Retarget", "#login-form"))
+                    .insert_header(("HX-Reswap", "outerHTML"))
+                    .content_type(ContentType::html())
+                    .body(include_str!("fragments/two_factor.htmx.html")));
+            }
+
+            session
+                .insert_user_id(user_id)
+                .map_err(|e| login_redirect(LoginError::UnexpectedError(e.into())))?;
 
             // user is authenticated, redirect to home page
             Ok(HttpResponse::SeeOther()
@@ -75,9 +139,13 @@ pub async fn login_post(
 
             FlashMessage::error(&error_message).send();
 
-            let response = match e {
+            match e {
                 // invalid credentials, show error message in the fragment
                 LoginError::AuthError(_) => {
+                    record_failed_login(&pool, &username, &ip_address)
+                        .await
+                        .map_err(|e| login_redirect(LoginError::UnexpectedError(e)))?;
+
                     // simple pass-in error message to the fragment (could go for askama template here as well)
                     let response_fragment = format!(
                         include_str!("fragments/login_error.htmx.html"),
@@ -86,19 +154,50 @@ pub async fn login_post(
                     // 422 unprocessable entity would be more appropriate, but we want to show the error message
                     // 418 I'm a teapot is a fun status code to use for this purpose
                     // htmx needs to be configured to allow successfull swap for the response type
-                    HttpResponse::ImATeapot()
+                    let response = HttpResponse::ImATeapot()
                         .content_type(ContentType::html())
-                        .body(response_fragment)
-                }
+                        .body(response_fragment);
 
-                // unexpected error, redirect to login page
-                LoginError::UnexpectedError(_) => HttpResponse::SeeOther()
-                    .insert_header((header::LOCATION, "/login"))
-                    .insert_header(("HX-Redirect", "/"))
-                    .finish(),
-            };
+                    Err(InternalError::from_response(e, response))
+                }
 
-            Err(InternalError::from_response(e, response))
+                _ => Err(login_redirect(e)),
+            }
         }
     }
 }
+
+// too many failed attempts, show how long to wait in the error fragment
+pub(super) fn too_many_attempts(lockout: Lockout) -> InternalError<LoginError> {
+    let retry_after_secs = lockout.retry_after_secs();
+    let span = tracing::Span::current();
+    span.record("lockout", tracing::field::display(&lockout.scope));
+    span.record("retry_after_secs", retry_after_secs);
+    tracing::warn!(lockout = %lockout.scope, retry_after_secs, "Login attempt throttled.");
+
+    let error_message = format!(
+        "Too many failed login attempts. Please try again in {} seconds.",
+        retry_after_secs
+    );
+    FlashMessage::error(&error_message).send();
+
+    let response = HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, retry_after_secs.to_string()))
+        .content_type(ContentType::html())
+        .body(format!(
+            include_str!("fragments/login_error.htmx.html"),
+            &error_message
+        ));
+
+    InternalError::from_response(LoginError::Throttled(lockout), response)
+}
+
+// unexpected error, redirect to login page
+pub(super) fn login_redirect(e: LoginError) -> InternalError<LoginError> {
+    let response = HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, "/login"))
+        .insert_header(("HX-Redirect", "/"))
+        .finish();
+
+    InternalError::from_response(e, response)
+}
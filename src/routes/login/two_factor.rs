@@ -0,0 +1,127 @@
+use actix_web::{error::InternalError, http::header::ContentType, web, HttpRequest, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use sqlx::PgPool;
+
+use super::post::{login_redirect, too_many_attempts, LoginError};
+use crate::authentication::{
+    check_login_throttle, clear_failed_logins, client_ip, record_failed_login,
+    verify_second_factor, TotpEncryptionKey,
+};
+use crate::session_state::TypedSession;
+
+/// How long a user has to enter the second factor after the password check
+const PENDING_TWO_FACTOR_TTL: chrono::Duration = chrono::Duration::minutes(5);
+/// Wrong codes allowed before the login has to start over, they also count as failed logins
+/// for the throttle, which carries over from one login to the next
+const MAX_FAILED_ATTEMPTS: u8 = 5;
+
+#[derive(serde::Deserialize)]
+pub struct TwoFactorData {
+    code: String,
+}
+
+// returns htmx fragment
+#[tracing::instrument(
+    name = "Login second factor",
+    skip(form, pool, session, key, request),
+    fields(
+        user_id=tracing::field::Empty,
+        lockout=tracing::field::Empty,
+        retry_after_secs=tracing::field::Empty
+    )
+)]
+pub async fn login_two_factor(
+    form: web::Form<TwoFactorData>,
+    pool: web::Data<PgPool>,
+    session: TypedSession,
+    key: web::Data<TotpEncryptionKey>,
+    request: HttpRequest,
+) -> Result<HttpResponse, InternalError<LoginError>> {
+    let pending = session
+        .get_pending_two_factor()
+        .map_err(|e| login_redirect(LoginError::UnexpectedError(e.into())))?;
+
+    let Some(mut pending) =
+        pending.filter(|p| chrono::Utc::now() - p.started_at < PENDING_TWO_FACTOR_TTL)
+    else {
+        return Err(start_over(
+            &session,
+            "Your login attempt has expired, please log in again.",
+        ));
+    };
+    tracing::Span::current().record("user_id", tracing::field::display(&pending.user_id));
+
+    let ip_address = client_ip(&request);
+    if let Some(lockout) = check_login_throttle(&pool, &pending.username, &ip_address)
+        .await
+        .map_err(|e| login_redirect(LoginError::UnexpectedError(e)))?
+    {
+        return Err(too_many_attempts(lockout));
+    }
+
+    let verified = verify_second_factor(&pool, &key, pending.user_id, &form.code)
+        .await
+        .map_err(|e| login_redirect(LoginError::UnexpectedError(e)))?;
+
+    if !verified {
+        record_failed_login(&pool, &pending.username, &ip_address)
+            .await
+            .map_err(|e| login_redirect(LoginError::UnexpectedError(e)))?;
+        pending.failed_attempts += 1;
+
+        if pending.failed_attempts >= MAX_FAILED_ATTEMPTS {
+            tracing::warn!("Too many invalid authentication codes, the login has to start over.");
+            return Err(start_over(
+                &session,
+                "Too many invalid authentication codes, please log in again.",
+            ));
+        }
+
+        session
+            .insert_pending_two_factor(&pending)
+            .map_err(|e| login_redirect(LoginError::UnexpectedError(e.into())))?;
+
+        let response_fragment = format!(
+            include_str!("fragments/login_error.htmx.html"),
+            "Invalid authentication code"
+        );
+        let response = HttpResponse::ImATeapot()
+            .content_type(ContentType::html())
+            .body(response_fragment);
+
+        return Err(InternalError::from_response(
+            LoginError::AuthError(anyhow::anyhow!("Invalid authentication code.")),
+            response,
+        ));
+    }
+
+    clear_failed_logins(&pool, &pending.username)
+        .await
+        .map_err(|e| login_redirect(LoginError::UnexpectedError(e)))?;
+
+    session.renew();
+    session.remove_pending_two_factor();
+    session
+        .insert_user_id(pending.user_id)
+        .map_err(|e| login_redirect(LoginError::UnexpectedError(e.into())))?;
+
+    // user is authenticated, redirect to home page
+    Ok(HttpResponse::SeeOther()
+        .insert_header(("HX-Redirect", "/"))
+        .finish())
+}
+
+// send the user back to the password form
+fn start_over(session: &TypedSession, message: &str) -> InternalError<LoginError> {
+    session.remove_pending_two_factor();
+    FlashMessage::error(message).send();
+
+    let response = HttpResponse::Ok()
+        .insert_header(("HX-Redirect", "/login"))
+        .finish();
+
+    InternalError::from_response(
+        LoginError::AuthError(anyhow::anyhow!(message.to_string())),
+        response,
+    )
+}
@@ -1,15 +1,23 @@
+mod admin;
 mod file_handlers;
 mod health_check;
 mod home;
 mod login;
 mod newsletters;
+mod password_reset;
 mod subscriptions;
 mod subscriptions_confirm;
+mod subscriptions_preferences;
+mod subscriptions_unsubscribe;
 
+pub use admin::*;
 pub use file_handlers::*;
 pub use health_check::*;
 pub use home::*;
 pub use login::*;
 pub use newsletters::*;
+pub use password_reset::*;
 pub use subscriptions::*;
 pub use subscriptions_confirm::*;
+pub use subscriptions_preferences::*;
+pub use subscriptions_unsubscribe::*;
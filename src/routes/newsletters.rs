@@ -1,17 +1,26 @@
-use crate::authentication::{validate_credentials, AuthError, Credentials};
-use crate::{domain::SubscriberEmail, email_client::EmailClient, errors::error_chain_fmt};
+use crate::authentication::{
+    authorize, check_login_throttle, clear_failed_logins, client_ip, is_totp_enabled,
+    record_failed_login, validate_api_token, validate_credentials, ApiScope, AuthError,
+    AuthorizationError, Credentials, Lockout, PasswordHashing, Permission,
+};
+use crate::errors::error_chain_fmt;
+use crate::idempotency::{save_response, try_processing, IdempotencyKey, NextAction};
+use crate::topics::find_topic;
 use actix_web::http::header::{HeaderMap, HeaderValue};
 use actix_web::http::{header, StatusCode};
 use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
 use anyhow::Context;
 use base64::Engine;
-use secrecy::Secret;
-use sqlx::PgPool;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use uuid::Uuid;
 
 #[derive(serde::Deserialize)]
 pub struct NewsletterBody {
     title: String,
     content: NewsletterContent,
+    /// Name of a topic, subscribers who turned it off don't get the issue
+    topic: Option<String>,
 }
 
 #[derive(serde::Deserialize)]
@@ -20,37 +29,94 @@ pub struct NewsletterContent {
     text: String,
 }
 
-struct ConfirmedSubscriber {
-    email: SubscriberEmail,
+#[tracing::instrument(skip_all)]
+async fn insert_newsletter_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    body: &NewsletterBody,
+    topic_id: Option<Uuid>,
+    published_by: Uuid,
+) -> Result<Uuid, sqlx::Error> {
+    let newsletter_issue_id = Uuid::new_v4();
+
+    transaction
+        .execute(sqlx::query!(
+            r#"
+            INSERT INTO newsletter_issues (
+                newsletter_issue_id, title, text_content, html_content, topic_id, published_by,
+                published_at
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, now())
+            "#,
+            newsletter_issue_id,
+            body.title,
+            body.content.text,
+            body.content.html,
+            topic_id,
+            published_by
+        ))
+        .await?;
+
+    Ok(newsletter_issue_id)
 }
 
-#[tracing::instrument(name = "Get confirmed subscribers", skip(pool))]
-async fn get_confirmed_subscribers(
-    pool: &PgPool,
-) -> Result<Vec<Result<ConfirmedSubscriber, anyhow::Error>>, anyhow::Error> {
-    let confirmed_subscribers = sqlx::query!(
-        r#"
-        SELECT email
-        FROM subscriptions
-        WHERE status = 'confirmed'
-        "#,
-    )
-    .fetch_all(pool)
-    .await?
-    .into_iter()
-    .map(|row| match SubscriberEmail::parse(row.email) {
-        Ok(email) => Ok(ConfirmedSubscriber { email }),
-        Err(error) => Err(anyhow::anyhow!("Invalid email: {}", error)),
-    })
-    .collect();
+/// One task per confirmed subscriber who didn't opt out of the topic, picked up by the
+/// issue delivery worker, next to the delivery record that tracks its outcome.
+///
+/// The list is copied inside the database and never passes through the application,
+/// the worker reads it back one batch at a time, so memory use doesn't grow with the list.
+#[tracing::instrument(skip_all)]
+async fn enqueue_delivery_tasks(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+    topic_id: Option<Uuid>,
+) -> Result<(), sqlx::Error> {
+    transaction
+        .execute(sqlx::query!(
+            r#"
+            INSERT INTO deliveries (newsletter_issue_id, subscriber_email)
+            SELECT $1, s.email
+            FROM subscriptions s
+            WHERE s.status = 'confirmed'
+                AND NOT EXISTS (
+                    SELECT 1
+                    FROM topic_opt_outs o
+                    WHERE o.subscriber_id = s.id AND o.topic_id = $2
+                )
+            "#,
+            newsletter_issue_id,
+            topic_id
+        ))
+        .await?;
+
+    transaction
+        .execute(sqlx::query!(
+            r#"
+            INSERT INTO issue_delivery_queue (newsletter_issue_id, subscriber_email)
+            SELECT newsletter_issue_id, subscriber_email
+            FROM deliveries
+            WHERE newsletter_issue_id = $1
+            "#,
+            newsletter_issue_id
+        ))
+        .await?;
 
-    Ok(confirmed_subscribers)
+    Ok(())
 }
 
 #[derive(thiserror::Error)]
 pub enum PublishError {
     #[error("Authentication failed.")]
     AuthError(#[source] anyhow::Error),
+    #[error("The API token lacks the required scope.")]
+    InsufficientScope(ApiScope),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("Too many failed authentication attempts.")]
+    Throttled(Lockout),
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("A request with the same idempotency key is still being processed.")]
+    Conflict,
     #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
 }
@@ -66,13 +132,37 @@ impl ResponseError for PublishError {
         match self {
             PublishError::AuthError(_) => {
                 let mut response = HttpResponse::new(StatusCode::UNAUTHORIZED);
-                let header_value = HeaderValue::from_str(r#"Basic realm="publish""#).unwrap();
+                // one challenge per supported scheme, Basic stays first for existing clients
+                response.headers_mut().append(
+                    header::WWW_AUTHENTICATE,
+                    HeaderValue::from_static(r#"Basic realm="publish""#),
+                );
+                response.headers_mut().append(
+                    header::WWW_AUTHENTICATE,
+                    HeaderValue::from_static(r#"Bearer realm="publish""#),
+                );
+
+                response
+            }
+            PublishError::InsufficientScope(scope) => {
+                let mut response = HttpResponse::new(StatusCode::FORBIDDEN);
+                let header_value = HeaderValue::from_str(&format!(
+                    r#"Bearer realm="publish", error="insufficient_scope", scope="{}""#,
+                    scope
+                ))
+                .unwrap();
                 response
                     .headers_mut()
                     .insert(header::WWW_AUTHENTICATE, header_value);
 
                 response
             }
+            PublishError::Forbidden(reason) => HttpResponse::Forbidden().body(reason.clone()),
+            PublishError::Throttled(lockout) => HttpResponse::TooManyRequests()
+                .insert_header((header::RETRY_AFTER, lockout.retry_after_secs().to_string()))
+                .finish(),
+            PublishError::BadRequest(reason) => HttpResponse::BadRequest().body(reason.clone()),
+            PublishError::Conflict => HttpResponse::Conflict().body(self.to_string()),
             PublishError::UnexpectedError(_) => {
                 HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
             }
@@ -81,13 +171,29 @@ impl ResponseError for PublishError {
     // no need for status_code() anymore, default error_response will take care of that
 }
 
-fn basic_authentication(headers: &HeaderMap) -> Result<Credentials, anyhow::Error> {
+/// What a client may present in the Authorization header
+enum PublishCredentials {
+    Basic(Credentials),
+    Bearer(Secret<String>),
+}
+
+fn extract_credentials(headers: &HeaderMap) -> Result<PublishCredentials, anyhow::Error> {
     let header_value = headers
         .get("Authorization")
         .context("The Authorization header is missing.")?
         .to_str()
         .context("Failed to parse Authorization header value as string.")?;
 
+    if let Some(token) = header_value.strip_prefix("Bearer ") {
+        return Ok(PublishCredentials::Bearer(Secret::new(
+            token.trim().to_string(),
+        )));
+    }
+
+    basic_authentication(header_value).map(PublishCredentials::Basic)
+}
+
+fn basic_authentication(header_value: &str) -> Result<Credentials, anyhow::Error> {
     let base64_segment = header_value
         .strip_prefix("Basic ")
         .context("Invalid Authorization header.")?;
@@ -117,51 +223,165 @@ fn basic_authentication(headers: &HeaderMap) -> Result<Credentials, anyhow::Erro
     })
 }
 
+/// Optional, requests without the header are simply not deduplicated
+fn extract_idempotency_key(headers: &HeaderMap) -> Result<Option<IdempotencyKey>, PublishError> {
+    let Some(header_value) = headers.get("Idempotency-Key") else {
+        return Ok(None);
+    };
+    let key = header_value
+        .to_str()
+        .map_err(|_| PublishError::BadRequest("The idempotency key must be ASCII.".into()))?;
+
+    IdempotencyKey::parse(key.to_string())
+        .map(Some)
+        .map_err(PublishError::BadRequest)
+}
+
+fn publish_auth_error(e: AuthError) -> PublishError {
+    match e {
+        AuthError::InvalidCredentials(_) => PublishError::AuthError(e.into()),
+        AuthError::UnexpectedError(_) => PublishError::UnexpectedError(e.into()),
+    }
+}
+
+/// Same limits as the login form, otherwise the API would be the cheaper place to guess passwords
+async fn basic_auth_with_throttle(
+    credentials: Credentials,
+    ip_address: &str,
+    hashing: &PasswordHashing,
+    pool: &PgPool,
+) -> Result<uuid::Uuid, PublishError> {
+    let username = credentials.username.clone();
+
+    if let Some(lockout) = check_login_throttle(pool, &username, ip_address).await? {
+        let span = tracing::Span::current();
+        span.record("lockout", tracing::field::display(&lockout.scope));
+        span.record("retry_after_secs", lockout.retry_after_secs());
+        tracing::warn!(
+            lockout = %lockout.scope,
+            retry_after_secs = lockout.retry_after_secs(),
+            "Publishing attempt throttled."
+        );
+        return Err(PublishError::Throttled(lockout));
+    }
+
+    match validate_credentials(credentials, hashing, pool).await {
+        Ok(user_id) => {
+            // a password alone must not be enough to publish for accounts protected by a
+            // second factor, nor to reset the backoff that also guards the code form
+            if is_totp_enabled(pool, user_id).await? {
+                return Err(PublishError::Forbidden(
+                    "Two-factor authentication is enabled for this account, \
+                    publish with an API token (Authorization: Bearer) instead."
+                        .into(),
+                ));
+            }
+            clear_failed_logins(pool, &username).await?;
+            Ok(user_id)
+        }
+        Err(e) => {
+            if let AuthError::InvalidCredentials(_) = e {
+                record_failed_login(pool, &username, ip_address).await?;
+            }
+            Err(publish_auth_error(e))
+        }
+    }
+}
+
 #[tracing::instrument(
     name = "Publish newsletter",
-    skip(body, pool, email_client, request),
-    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+    skip(body, pool, hashing, request),
+    fields(
+        username=tracing::field::Empty,
+        user_id=tracing::field::Empty,
+        token_id=tracing::field::Empty,
+        lockout=tracing::field::Empty,
+        retry_after_secs=tracing::field::Empty,
+        newsletter_issue_id=tracing::field::Empty
+    )
 )]
 pub async fn publish_newsletter(
     body: web::Json<NewsletterBody>,
     pool: web::Data<PgPool>,
-    email_client: web::Data<EmailClient>,
+    hashing: web::Data<PasswordHashing>,
     request: HttpRequest,
 ) -> Result<HttpResponse, PublishError> {
-    let credentials = basic_authentication(request.headers()).map_err(PublishError::AuthError)?;
-    tracing::Span::current().record("username", &tracing::field::display(&credentials.username));
+    let user_id = match extract_credentials(request.headers()).map_err(PublishError::AuthError)? {
+        PublishCredentials::Basic(credentials) => {
+            tracing::Span::current()
+                .record("username", tracing::field::display(&credentials.username));
+
+            basic_auth_with_throttle(credentials, &client_ip(&request), &hashing, &pool).await?
+        }
+        PublishCredentials::Bearer(token) => {
+            let grant = validate_api_token(token.expose_secret(), &pool)
+                .await
+                .map_err(publish_auth_error)?;
+            tracing::Span::current().record("token_id", tracing::field::display(&grant.token_id));
 
-    let user_id = validate_credentials(credentials, &pool)
+            if !grant.has_scope(ApiScope::NewslettersPublish) {
+                return Err(PublishError::InsufficientScope(
+                    ApiScope::NewslettersPublish,
+                ));
+            }
+            grant.user_id
+        }
+    };
+
+    tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+
+    // the token scope limits what the token may do, the role what its owner may do
+    authorize(&pool, user_id, Permission::PublishNewsletters)
         .await
         .map_err(|e| match e {
-            AuthError::InvalidCredentials(_) => PublishError::AuthError(e.into()),
-            AuthError::UnexpectedError(_) => PublishError::UnexpectedError(e.into()),
+            AuthorizationError::Forbidden { .. } => PublishError::Forbidden(e.to_string()),
+            AuthorizationError::UnexpectedError(e) => PublishError::UnexpectedError(e),
         })?;
 
-    tracing::Span::current().record("user_id", &tracing::field::display(&user_id));
-
-    let subscribers = get_confirmed_subscribers(&pool).await?;
-
-    for subscriber in subscribers {
-        match subscriber {
-            Ok(subscriber) => {
-                email_client
-                    .send_email(
-                        &subscriber.email,
-                        &body.title,
-                        &body.content.html,
-                        &body.content.text,
-                    )
-                    .await
-                    .with_context(|| {
-                        format!("Failed to send newsletter issue to {}", subscriber.email)
-                    })?;
-            }
-            Err(error) => {
-                tracing::warn!(error.cause_chain = ?error, "Skipping invalid subscriber. Contact details are invalid.")
-            }
+    let idempotency_key = extract_idempotency_key(request.headers())?;
+    let topic_id = match &body.topic {
+        Some(name) => Some(
+            find_topic(&pool, name)
+                .await?
+                .ok_or_else(|| PublishError::BadRequest(format!("There is no topic {}.", name)))?,
+        ),
+        None => None,
+    };
+
+    // the issue and all of its deliveries are stored together (with the response), or not at all
+    let mut transaction = match &idempotency_key {
+        Some(key) => match try_processing(&pool, key, user_id).await? {
+            NextAction::StartProcessing(transaction) => *transaction,
+            NextAction::ReturnSavedResponse(saved_response) => return Ok(saved_response),
+            NextAction::Conflict => return Err(PublishError::Conflict),
+        },
+        None => pool
+            .begin()
+            .await
+            .context("Failed to acquire a Postgres connection from the pool.")?,
+    };
+    let newsletter_issue_id = insert_newsletter_issue(&mut transaction, &body, topic_id, user_id)
+        .await
+        .context("Failed to store newsletter issue details.")?;
+    tracing::Span::current().record(
+        "newsletter_issue_id",
+        tracing::field::display(&newsletter_issue_id),
+    );
+    enqueue_delivery_tasks(&mut transaction, newsletter_issue_id, topic_id)
+        .await
+        .context("Failed to enqueue delivery tasks.")?;
+
+    let response = HttpResponse::Accepted().finish();
+    let response = match idempotency_key {
+        Some(key) => save_response(transaction, &key, user_id, response).await?,
+        None => {
+            transaction
+                .commit()
+                .await
+                .context("Failed to commit the newsletter issue.")?;
+            response
         }
-    }
+    };
 
-    Ok(HttpResponse::Ok().finish())
+    Ok(response)
 }
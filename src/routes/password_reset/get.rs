@@ -0,0 +1,73 @@
+use actix_web::{http::header::ContentType, web, HttpResponse};
+use actix_web_flash_messages::{FlashMessage, IncomingFlashMessages, Level};
+use askama::Template;
+use sqlx::PgPool;
+
+use super::{PasswordResetConfirmPage, PasswordResetRequestPage};
+use crate::authentication::get_user_id_by_password_reset_token;
+use crate::csrf::CsrfToken;
+use crate::routes::login::AuthLayout;
+use crate::startup::HmacSecret;
+use crate::utils::{e500, flash_messages_with_level, see_other};
+
+#[derive(serde::Deserialize)]
+pub struct Parameters {
+    token: String,
+}
+
+pub async fn password_reset_form(
+    flash_messages: IncomingFlashMessages,
+    csrf_token: CsrfToken,
+) -> HttpResponse {
+    let page = PasswordResetRequestPage {
+        csrf_token: csrf_token.as_str(),
+        info_messages: flash_messages_with_level(&flash_messages, Level::Info),
+        error_messages: flash_messages_with_level(&flash_messages, Level::Error),
+    };
+
+    let layout = AuthLayout {
+        title: "Reset password",
+        body: &page.render().unwrap(),
+        csrf_token: csrf_token.as_str(),
+    };
+
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(layout.render().unwrap())
+}
+
+pub async fn password_reset_confirm_form(
+    params: web::Query<Parameters>,
+    flash_messages: IncomingFlashMessages,
+    pool: web::Data<PgPool>,
+    hmac_secret: web::Data<HmacSecret>,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse, actix_web::Error> {
+    // don't let the user fill in a new password only to tell them the link is dead afterwards
+    if get_user_id_by_password_reset_token(&pool, &hmac_secret.0, &params.token)
+        .await
+        .map_err(e500)?
+        .is_none()
+    {
+        FlashMessage::error("The reset link is invalid or has expired, please request a new one.")
+            .send();
+        return Ok(see_other("/password-reset"));
+    }
+
+    let page = PasswordResetConfirmPage {
+        csrf_token: csrf_token.as_str(),
+        token: &params.token,
+        info_messages: flash_messages_with_level(&flash_messages, Level::Info),
+        error_messages: flash_messages_with_level(&flash_messages, Level::Error),
+    };
+
+    let layout = AuthLayout {
+        title: "Reset password",
+        body: &page.render().unwrap(),
+        csrf_token: csrf_token.as_str(),
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(layout.render().unwrap()))
+}
@@ -0,0 +1,24 @@
+mod get;
+mod post;
+
+pub use get::{password_reset_confirm_form, password_reset_form};
+pub use post::{confirm_password_reset, request_password_reset};
+
+use askama::Template;
+
+#[derive(Template)]
+#[template(path = "password_reset/request.html")]
+struct PasswordResetRequestPage<'a> {
+    csrf_token: &'a str,
+    info_messages: Vec<&'a str>,
+    error_messages: Vec<&'a str>,
+}
+
+#[derive(Template)]
+#[template(path = "password_reset/confirm.html")]
+struct PasswordResetConfirmPage<'a> {
+    csrf_token: &'a str,
+    token: &'a str,
+    info_messages: Vec<&'a str>,
+    error_messages: Vec<&'a str>,
+}
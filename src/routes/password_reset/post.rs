@@ -0,0 +1,177 @@
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use anyhow::Context;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use tracing::Instrument;
+
+use crate::authentication::{
+    change_password, consume_password_reset_token, create_password_reset_token, get_user_id,
+    PasswordHashing,
+};
+use crate::domain::{NewPassword, SubscriberEmail};
+use crate::email_client::EmailSender;
+use crate::session_store::revoke_user_sessions;
+use crate::startup::{ApplicationBaseUrl, HmacSecret};
+use crate::utils::{e500, see_other};
+
+#[derive(serde::Deserialize)]
+pub struct PasswordResetRequestData {
+    email: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct PasswordResetConfirmData {
+    token: String,
+    new_password: Secret<String>,
+    new_password_check: Secret<String>,
+}
+
+#[tracing::instrument(
+    name = "Request password reset",
+    skip(form, pool, email_client, base_url, hmac_secret),
+    fields(username=%form.email)
+)]
+pub async fn request_password_reset(
+    form: web::Form<PasswordResetRequestData>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<dyn EmailSender>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    hmac_secret: web::Data<HmacSecret>,
+) -> Result<HttpResponse, actix_web::Error> {
+    // usernames are email addresses
+    let username = form.0.email;
+
+    // all of it in the background, so the response time does not reveal whether the account exists
+    tokio::spawn(
+        async move {
+            if let Err(e) = send_password_reset(
+                &pool,
+                email_client.get_ref(),
+                &hmac_secret.0,
+                username,
+                &base_url.0,
+            )
+            .await
+            {
+                tracing::error!(error.cause_chain = ?e, "Failed to send password reset email.");
+            }
+        }
+        .instrument(tracing::Span::current()),
+    );
+
+    // same answer for known and unknown accounts to avoid user enumeration
+    FlashMessage::info(
+        "If an account exists for that address, you will receive an email with a reset link shortly.",
+    )
+    .send();
+
+    Ok(see_other("/login"))
+}
+
+/// Does nothing for unknown accounts
+async fn send_password_reset(
+    pool: &PgPool,
+    email_client: &dyn EmailSender,
+    hmac_secret: &Secret<String>,
+    username: String,
+    base_url: &str,
+) -> Result<(), anyhow::Error> {
+    let Some(user_id) = get_user_id(&username, pool).await? else {
+        return Ok(());
+    };
+    let token = create_password_reset_token(pool, hmac_secret, user_id).await?;
+
+    send_password_reset_email(email_client, username, base_url, &token).await
+}
+
+#[tracing::instrument(
+    name = "Sending a password reset email",
+    skip(email_client, base_url, token)
+)]
+async fn send_password_reset_email(
+    email_client: &dyn EmailSender,
+    username: String,
+    base_url: &str,
+    token: &str,
+) -> Result<(), anyhow::Error> {
+    let recipient = SubscriberEmail::parse(username).map_err(|e| anyhow::anyhow!(e))?;
+    let reset_link = format!("{}/password-reset/confirm?token={}", base_url, token);
+    let plain_body = format!(
+        "Somebody requested a password reset for your account.\n\
+        Visit {} to choose a new password. The link expires in one hour.\n\
+        If this wasn't you, you can safely ignore this email.",
+        reset_link
+    );
+    let html_body = format!(
+        "Somebody requested a password reset for your account.<br />\
+        Click <a href=\"{}\">here</a> to choose a new password. The link expires in one hour.<br />\
+        If this wasn't you, you can safely ignore this email.",
+        reset_link
+    );
+
+    email_client
+        .send_email(&recipient, "Reset your password", &html_body, &plain_body)
+        .await
+        .context("Failed to send the password reset email.")?;
+
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Confirm password reset",
+    skip(form, pool, hashing, hmac_secret),
+    fields(user_id=tracing::field::Empty)
+)]
+pub async fn confirm_password_reset(
+    form: web::Form<PasswordResetConfirmData>,
+    pool: web::Data<PgPool>,
+    hashing: web::Data<PasswordHashing>,
+    hmac_secret: web::Data<HmacSecret>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let form = form.0;
+    let retry_location = format!(
+        "/password-reset/confirm?token={}",
+        urlencoding::encode(&form.token)
+    );
+
+    if form.new_password.expose_secret() != form.new_password_check.expose_secret() {
+        FlashMessage::error(
+            "You entered two different new passwords - the field values must match.",
+        )
+        .send();
+        return Ok(see_other(&retry_location));
+    }
+
+    let new_password = match NewPassword::parse(form.new_password) {
+        Ok(password) => password,
+        Err(error_message) => {
+            FlashMessage::error(error_message).send();
+            return Ok(see_other(&retry_location));
+        }
+    };
+
+    // all or nothing: a failure leaves the link usable and never a new password with old sessions
+    let mut transaction = pool.begin().await.map_err(e500)?;
+    let Some(user_id) = consume_password_reset_token(&mut transaction, &hmac_secret.0, &form.token)
+        .await
+        .map_err(e500)?
+    else {
+        FlashMessage::error("The reset link is invalid or has expired, please request a new one.")
+            .send();
+        return Ok(see_other("/password-reset"));
+    };
+    tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+
+    change_password(user_id, new_password, &hashing, &mut *transaction)
+        .await
+        .map_err(e500)?;
+    revoke_user_sessions(&mut *transaction, user_id)
+        .await
+        .map_err(e500)?;
+    transaction.commit().await.map_err(e500)?;
+
+    FlashMessage::info("Your password has been reset, you can log in with the new one now.").send();
+
+    Ok(see_other("/login"))
+}
@@ -1,14 +1,21 @@
-use crate::email_client::EmailClient;
+use crate::authentication::hmac_token;
+use crate::domain::*;
+use crate::email_client::{EmailSender, SendEmailError};
 use crate::errors::error_chain_fmt;
-use crate::{domain::*, startup::ApplicationBaseUrl};
+use crate::startup::{ApplicationBaseUrl, HmacSecret};
 use actix_web::{web, HttpResponse, ResponseError};
 use anyhow::Context;
 use chrono::Utc;
 use rand::{thread_rng, Rng};
+use secrecy::Secret;
 use serde::{Deserialize, Serialize};
 use sqlx::{Executor, PgPool, Postgres, Transaction};
+use tracing::Instrument;
 use uuid::Uuid;
 
+/// How long a link to confirm a subscription or a new email address stays valid
+pub(crate) const SUBSCRIPTION_TOKEN_TTL: chrono::Duration = chrono::Duration::hours(24);
+
 #[derive(thiserror::Error)]
 pub enum SubscribeError {
     #[error("{0}")]
@@ -73,7 +80,7 @@ impl TryFrom<SubscribeFormData> for NewSubscriber {
 
 #[tracing::instrument(
     name = "Adding a new subscriber",
-    skip(form, pool, email_client, base_url),
+    skip(form, pool, email_client, base_url, hmac_secret),
     fields(
         subscriber_email = %form.email,
         subscriber_name = %form.name
@@ -82,56 +89,171 @@ impl TryFrom<SubscribeFormData> for NewSubscriber {
 pub async fn subscribe(
     form: web::Form<SubscribeFormData>,
     pool: web::Data<PgPool>,
-    email_client: web::Data<EmailClient>,
+    email_client: web::Data<dyn EmailSender>,
     base_url: web::Data<ApplicationBaseUrl>,
+    hmac_secret: web::Data<HmacSecret>,
 ) -> Result<HttpResponse, SubscribeError> {
+    let secret = &hmac_secret.0;
     // try_into here is a trait fn that is implemented by TryFrom for the NewSubscriber struct
-    let new_subscriber = form.0.try_into().map_err(SubscribeError::ValidationError)?;
+    let new_subscriber: NewSubscriber =
+        form.0.try_into().map_err(SubscribeError::ValidationError)?;
 
     let mut transaction = pool
         .begin()
         .await
         .context("Failed to acquire a postgres connection from the pool")?;
 
-    let subscriber_id = insert_subscriber(&mut transaction, &new_subscriber)
+    let subscription_token = match get_subscription(&mut transaction, &new_subscriber.email)
         .await
-        .context("Failed to insert new subscriber into database")?;
+        .context("Failed to look up an existing subscription")?
+    {
+        None => match insert_subscriber(&mut transaction, &new_subscriber)
+            .await
+            .context("Failed to insert new subscriber into database")?
+        {
+            Some(subscriber_id) => Some(new_token(&mut transaction, secret, subscriber_id).await?),
+            // a concurrent request just signed the address up and sends the confirmation
+            None => None,
+        },
+        // nothing to do, and no email either: the answer must not tell who is subscribed
+        Some(subscription) if subscription.status == "confirmed" => None,
+        // the confirmation email got lost or was deleted, send it again
+        Some(subscription) if subscription.status == "pending_confirmation" => {
+            Some(pending_confirmation_token(&mut transaction, secret, subscription.id).await?)
+        }
+        // coming back after unsubscribing, through double opt-in like the first time
+        Some(subscription) => {
+            resubscribe(&mut transaction, subscription.id)
+                .await
+                .context("Failed to re-subscribe the subscriber")?;
+            Some(new_token(&mut transaction, secret, subscription.id).await?)
+        }
+    };
 
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction")?; // "Failed to commit SQL transaction to store new subscriber
+
+    if let Some(subscription_token) = subscription_token {
+        spawn_confirmation_email(
+            email_client,
+            new_subscriber.email,
+            base_url,
+            subscription_token,
+        );
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn new_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    secret: &Secret<String>,
+    subscriber_id: Uuid,
+) -> Result<String, anyhow::Error> {
     let subscription_token = generate_subscription_token();
-    store_token(&mut transaction, subscriber_id, &subscription_token)
+    store_token(transaction, secret, subscriber_id, &subscription_token)
         .await
         .context("Failed to store the subscription token")?;
 
-    transaction
-        .commit()
+    Ok(subscription_token)
+}
+
+/// The token to send again to a subscriber who has yet to confirm. Only the hash of the
+/// one sent before is stored, so it is always a new one; earlier links keep working until
+/// they expire.
+pub(crate) async fn pending_confirmation_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    secret: &Secret<String>,
+    subscriber_id: Uuid,
+) -> Result<String, anyhow::Error> {
+    delete_expired_confirmation_tokens(transaction, subscriber_id)
         .await
-        .context("Failed to commit SQL transaction")?; // "Failed to commit SQL transaction to store new subscriber
+        .context("Failed to delete expired subscription tokens")?;
+    new_token(transaction, secret, subscriber_id).await
+}
 
-    send_confirmation_email(
-        &email_client,
-        new_subscriber,
-        &base_url.0,
-        &subscription_token,
+pub(crate) struct Subscription {
+    pub id: Uuid,
+    pub status: String,
+}
+
+/// Locks the row, so concurrent requests for the same address queue up behind each other
+#[tracing::instrument(name = "Looking up an existing subscription", skip(transaction, email))]
+pub(crate) async fn get_subscription(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &SubscriberEmail,
+) -> Result<Option<Subscription>, sqlx::Error> {
+    sqlx::query_as!(
+        Subscription,
+        r#"
+        SELECT id, status
+        FROM subscriptions
+        WHERE email = $1
+        FOR UPDATE
+        "#,
+        email.as_ref()
     )
+    .fetch_optional(&mut **transaction)
     .await
-    .context("Failed to send a confirmation email")?;
+}
 
-    Ok(HttpResponse::Ok().finish())
+#[tracing::instrument(name = "Deleting expired subscription tokens", skip(transaction))]
+async fn delete_expired_confirmation_tokens(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    let query = sqlx::query!(
+        r#"
+        DELETE FROM subscription_tokens
+        WHERE subscriber_id = $1 AND new_email IS NULL AND expires_at <= now()
+        "#,
+        subscriber_id
+    );
+
+    transaction.execute(query).await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Marking an unsubscribed subscriber as pending",
+    skip(transaction)
+)]
+async fn resubscribe(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    let query = sqlx::query!(
+        r#"
+        UPDATE subscriptions
+        SET status = 'pending_confirmation'
+        WHERE id = $1
+        "#,
+        subscriber_id
+    );
+
+    transaction.execute(query).await?;
+
+    Ok(())
 }
 
 #[tracing::instrument(
     name = "Saving new subscriber details in the database",
     skip(new_subscriber, transaction)
 )]
+/// `None` when the address was signed up in the meantime
 pub async fn insert_subscriber(
     transaction: &mut Transaction<'_, Postgres>,
     new_subscriber: &NewSubscriber,
-) -> Result<Uuid, sqlx::Error> {
+) -> Result<Option<Uuid>, sqlx::Error> {
     let subscriber_id = Uuid::new_v4();
     let query = sqlx::query!(
         r#"
         INSERT INTO subscriptions (id, email, name, subscribed_at, status)
         VALUES ($1, $2, $3, $4, 'pending_confirmation')
+        ON CONFLICT (email) DO NOTHING
         "#,
         subscriber_id,
         new_subscriber.email.as_ref(),
@@ -139,27 +261,33 @@ pub async fn insert_subscriber(
         Utc::now()
     );
 
-    transaction.execute(query).await?;
+    let inserted = transaction.execute(query).await?.rows_affected() > 0;
 
-    Ok(subscriber_id)
+    Ok(inserted.then_some(subscriber_id))
 }
 
 #[tracing::instrument(
     name = "Saving subscription token in the database",
-    skip(subscription_token, transaction)
+    skip(subscription_token, transaction, secret)
 )]
+/// Only a keyed hash of the token is persisted, a leaked table can't be used to confirm
+/// subscriptions
 pub async fn store_token(
     transaction: &mut Transaction<'_, Postgres>,
+    secret: &Secret<String>,
     subscriber_id: Uuid,
     subscription_token: &str,
 ) -> Result<(), StoreTokenError> {
+    let now = Utc::now();
     let query = sqlx::query!(
         r#"
-        INSERT INTO subscription_tokens (subscriber_id, subscription_token)
-        VALUES ($1, $2)
+        INSERT INTO subscription_tokens (subscriber_id, token_hash, created_at, expires_at)
+        VALUES ($1, $2, $3, $4)
         "#,
         subscriber_id,
-        subscription_token
+        hmac_token(secret, subscription_token),
+        now,
+        now + SUBSCRIPTION_TOKEN_TTL
     );
 
     transaction.execute(query).await.map_err(StoreTokenError)?;
@@ -167,33 +295,104 @@ pub async fn store_token(
     Ok(())
 }
 
+/// Replaces the tokens stored in plain text before tokens were hashed by their keyed hash,
+/// outstanding links keep working. Returns the number of tokens hashed.
+#[tracing::instrument(name = "Hashing legacy subscription tokens", skip(pool, secret))]
+pub async fn hash_legacy_subscription_tokens(
+    pool: &PgPool,
+    secret: &Secret<String>,
+) -> Result<u64, anyhow::Error> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a postgres connection from the pool")?;
+
+    let tokens = sqlx::query_scalar!(
+        r#"
+        SELECT subscription_token AS "subscription_token!"
+        FROM subscription_tokens
+        WHERE subscription_token IS NOT NULL
+        FOR UPDATE
+        "#
+    )
+    .fetch_all(&mut *transaction)
+    .await
+    .context("Failed to load the legacy subscription tokens")?;
+
+    for token in &tokens {
+        let query = sqlx::query!(
+            r#"
+            UPDATE subscription_tokens
+            SET token_hash = $2, subscription_token = NULL
+            WHERE subscription_token = $1
+            "#,
+            token,
+            hmac_token(secret, token)
+        );
+        transaction
+            .execute(query)
+            .await
+            .context("Failed to hash a legacy subscription token")?;
+    }
+
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction")?;
+
+    Ok(tokens.len() as u64)
+}
+
+/// Sends in the background, so the response time does not reveal whether the address
+/// is subscribed already
+pub(crate) fn spawn_confirmation_email(
+    email_client: web::Data<dyn EmailSender>,
+    email: SubscriberEmail,
+    base_url: web::Data<ApplicationBaseUrl>,
+    token: String,
+) {
+    tokio::spawn(
+        async move {
+            if let Err(e) =
+                send_confirmation_email(email_client.get_ref(), &email, &base_url.0, &token).await
+            {
+                tracing::error!(error.cause_chain = ?e, "Failed to send a confirmation email.");
+            }
+        }
+        .instrument(tracing::Span::current()),
+    );
+}
+
 #[tracing::instrument(
     name = "Sending a confirmation email",
-    skip(email_client, new_subscriber, base_url)
+    skip(email_client, email, base_url)
 )]
 pub async fn send_confirmation_email(
-    email_client: &EmailClient,
-    new_subscriber: NewSubscriber,
+    email_client: &dyn EmailSender,
+    email: &SubscriberEmail,
     base_url: &str,
     token: &str,
-) -> Result<(), reqwest::Error> {
+) -> Result<(), SendEmailError> {
     let confirmation_link = format!(
         "{}/subscriptions/confirm?subscription_token={}",
         base_url, token
     );
     let plain_body = format!(
-        "Welcome to our newsletter!\nVisit {} to confirm your subscription.",
+        "Welcome to our newsletter!\n\
+        Visit {} to confirm your subscription. The link expires in 24 hours.",
         confirmation_link
     );
     let html_body = format!(
         "Welcome to our newsletter!<br />\
-        Click <a href=\"{}\">here</a> to confirm your subscription.",
+        Click <a href=\"{}\">here</a> to confirm your subscription. The link expires in 24 hours.",
         confirmation_link
     );
 
     email_client
-        .send_email(&new_subscriber.email, "Welcome!", &html_body, &plain_body)
-        .await
+        .send_email(email, "Welcome!", &html_body, &plain_body)
+        .await?;
+
+    Ok(())
 }
 
 pub async fn get_all_subscribers(_pool: web::Data<PgPool>) -> HttpResponse {
@@ -211,7 +410,7 @@ pub async fn get_all_subscribers(_pool: web::Data<PgPool>) -> HttpResponse {
     // }
 }
 
-fn generate_subscription_token() -> String {
+pub(crate) fn generate_subscription_token() -> String {
     let mut rng = thread_rng();
     std::iter::repeat_with(|| rng.sample(rand::distributions::Alphanumeric))
         .map(char::from)
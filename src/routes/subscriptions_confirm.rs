@@ -1,19 +1,55 @@
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
 use actix_web::{web, HttpResponse, ResponseError};
+use actix_web_flash_messages::{FlashMessage, IncomingFlashMessages, Level};
 use anyhow::Context;
-use sqlx::PgPool;
+use askama::Template;
+use chrono::{DateTime, Utc};
+use secrecy::Secret;
+use sqlx::{PgPool, Postgres, Transaction};
 use uuid::Uuid;
 
+use crate::authentication::hmac_token;
+use crate::csrf::CsrfToken;
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailSender;
 use crate::errors::error_chain_fmt;
+use crate::routes::login::AuthLayout;
+use crate::routes::subscriptions::{
+    get_subscription, pending_confirmation_token, spawn_confirmation_email,
+};
+use crate::startup::{ApplicationBaseUrl, HmacSecret};
+use crate::utils::{e500, flash_messages_with_level, see_other};
 
 #[derive(serde::Deserialize)]
 pub struct Parameters {
     subscription_token: String,
 }
 
+#[derive(serde::Deserialize)]
+pub struct ResendConfirmationFormData {
+    email: String,
+}
+
+#[derive(Template)]
+#[template(path = "subscriptions/resend_confirmation.html")]
+struct ResendConfirmationPage<'a> {
+    csrf_token: &'a str,
+    email: &'a str,
+    /// Shown in place of a confirmation that came too late
+    expired: bool,
+    /// The expired link was for a new email address, not for signing up
+    email_change: bool,
+    info_messages: Vec<&'a str>,
+    error_messages: Vec<&'a str>,
+}
+
 #[derive(thiserror::Error)]
 pub enum ConfirmError {
     #[error("Could not find subscriber with that token")]
     UnauthorizedError(),
+    #[error("The new email address has been subscribed by someone else in the meantime.")]
+    EmailTaken,
     #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
 }
@@ -28,6 +64,7 @@ impl ResponseError for ConfirmError {
     fn status_code(&self) -> actix_web::http::StatusCode {
         match self {
             ConfirmError::UnauthorizedError() => actix_web::http::StatusCode::UNAUTHORIZED,
+            ConfirmError::EmailTaken => actix_web::http::StatusCode::CONFLICT,
             ConfirmError::UnexpectedError(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -53,28 +90,174 @@ impl std::error::Error for ConfirmSubscriberError {
     }
 }
 
-#[tracing::instrument(name = "Confirming a pending subscription", skip(params, pool))]
+/// Tokens are single-use: the token is deleted in the same transaction that acts on it,
+/// so following a link twice, even concurrently, only confirms once
+#[tracing::instrument(
+    name = "Confirming a pending subscription",
+    skip(params, pool, hmac_secret, csrf_token)
+)]
 pub async fn confirm(
     params: web::Query<Parameters>,
     pool: web::Data<PgPool>,
+    hmac_secret: web::Data<HmacSecret>,
+    csrf_token: CsrfToken,
 ) -> Result<HttpResponse, ConfirmError> {
-    let id = get_subscriber_id_by_token(&pool, &params.subscription_token)
+    let mut transaction = pool
+        .begin()
         .await
-        .context("Failed to retrieve subscriber ID by token")?;
+        .context("Failed to acquire a postgres connection from the pool")?;
 
-    match id {
-        None => return Err(ConfirmError::UnauthorizedError()),
-        Some(subscriber_id) => {
-            confirm_subscriber(&pool, subscriber_id)
-                .await
-                .context("Failed to confirm subscriber")?;
-            Ok(HttpResponse::Ok().finish())
+    let token =
+        consume_subscription_token(&mut transaction, &hmac_secret.0, &params.subscription_token)
+            .await
+            .context("Failed to retrieve subscriber ID by token")?
+            .ok_or(ConfirmError::UnauthorizedError())?;
+
+    if token.expires_at <= Utc::now() {
+        let email = get_subscriber_email(&mut transaction, token.subscriber_id)
+            .await
+            .context("Failed to retrieve the subscriber email")?;
+        // dropping the transaction keeps the token, following it again gets the same answer
+        return expired_link(&email, token.new_email.is_some(), &csrf_token);
+    }
+
+    match token.new_email {
+        None => confirm_subscriber(&mut transaction, token.subscriber_id)
+            .await
+            .context("Failed to confirm subscriber")?,
+        Some(new_email) => {
+            let changed =
+                change_subscriber_email(&mut transaction, token.subscriber_id, &new_email)
+                    .await
+                    .context("Failed to change the email address of the subscriber")?;
+            if !changed {
+                // dropping the transaction keeps the token, following it again gets the same answer
+                return Err(ConfirmError::EmailTaken);
+            }
         }
     }
+
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction")?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// 410, with the offer to send a new link right away
+fn expired_link(
+    email: &str,
+    email_change: bool,
+    csrf_token: &CsrfToken,
+) -> Result<HttpResponse, ConfirmError> {
+    let page = ResendConfirmationPage {
+        csrf_token: csrf_token.as_str(),
+        email,
+        expired: true,
+        email_change,
+        info_messages: vec![],
+        error_messages: vec![],
+    };
+    let layout = AuthLayout {
+        title: "Link expired",
+        body: &page
+            .render()
+            .context("Failed to render the expired link page")?,
+        csrf_token: csrf_token.as_str(),
+    };
+
+    Ok(HttpResponse::build(StatusCode::GONE)
+        .content_type(ContentType::html())
+        .body(
+            layout
+                .render()
+                .context("Failed to render the expired link page")?,
+        ))
+}
+
+pub async fn resend_confirmation_form(
+    flash_messages: IncomingFlashMessages,
+    csrf_token: CsrfToken,
+) -> HttpResponse {
+    let page = ResendConfirmationPage {
+        csrf_token: csrf_token.as_str(),
+        email: "",
+        expired: false,
+        email_change: false,
+        info_messages: flash_messages_with_level(&flash_messages, Level::Info),
+        error_messages: flash_messages_with_level(&flash_messages, Level::Error),
+    };
+
+    let layout = AuthLayout {
+        title: "Confirm your subscription",
+        body: &page.render().unwrap(),
+        csrf_token: csrf_token.as_str(),
+    };
+
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(layout.render().unwrap())
+}
+
+/// Sends the confirmation email again, only to addresses that are waiting to be confirmed
+#[tracing::instrument(
+    name = "Resending a confirmation email",
+    skip(form, pool, email_client, base_url, hmac_secret),
+    fields(subscriber_email = %form.email)
+)]
+pub async fn resend_confirmation(
+    form: web::Form<ResendConfirmationFormData>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<dyn EmailSender>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    hmac_secret: web::Data<HmacSecret>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let email = match SubscriberEmail::parse(form.0.email) {
+        Ok(email) => email,
+        Err(e) => {
+            FlashMessage::error(e).send();
+            return Ok(see_other("/subscriptions/resend-confirmation"));
+        }
+    };
+
+    let mut transaction = pool.begin().await.map_err(e500)?;
+    let token = match get_subscription(&mut transaction, &email)
+        .await
+        .map_err(e500)?
+    {
+        Some(subscription) if subscription.status == "pending_confirmation" => Some(
+            pending_confirmation_token(&mut transaction, &hmac_secret.0, subscription.id)
+                .await
+                .map_err(e500)?,
+        ),
+        _ => None,
+    };
+    transaction.commit().await.map_err(e500)?;
+
+    // the same answer for every address, whether it is subscribed or not
+    FlashMessage::info(format!(
+        "If {} is waiting to be confirmed, a new confirmation link is on its way.",
+        email.as_ref()
+    ))
+    .send();
+
+    if let Some(token) = token {
+        spawn_confirmation_email(email_client, email, base_url, token);
+    }
+
+    Ok(see_other("/subscriptions/resend-confirmation"))
 }
 
-#[tracing::instrument(name = "Mark subscriber as confirmed", skip(pool, subscriber_id))]
-pub async fn confirm_subscriber(pool: &PgPool, subscriber_id: Uuid) -> Result<(), sqlx::Error> {
+/// Other signup links of the subscriber are of no use anymore once one of them was followed
+#[tracing::instrument(
+    name = "Mark subscriber as confirmed",
+    skip(transaction, subscriber_id)
+)]
+pub async fn confirm_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
     sqlx::query!(
         r#"
         UPDATE subscriptions
@@ -83,30 +266,93 @@ pub async fn confirm_subscriber(pool: &PgPool, subscriber_id: Uuid) -> Result<()
         "#,
         subscriber_id
     )
-    .execute(pool)
+    .execute(&mut **transaction)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        DELETE FROM subscription_tokens
+        WHERE subscriber_id = $1 AND new_email IS NULL
+        "#,
+        subscriber_id
+    )
+    .execute(&mut **transaction)
     .await?;
 
     Ok(())
 }
 
 #[tracing::instrument(
-    name = "Fetching subscriber ID from the database",
-    skip(pool, subscribtion_token)
+    name = "Change the email address of a subscriber",
+    skip(transaction, subscriber_id, new_email)
 )]
-pub async fn get_subscriber_id_by_token(
-    pool: &PgPool,
-    subscribtion_token: &str,
-) -> Result<Option<Uuid>, sqlx::Error> {
+/// `false` when somebody subscribed the address since the change was requested,
+/// it was checked to be free back then
+pub async fn change_subscriber_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    new_email: &str,
+) -> Result<bool, sqlx::Error> {
     let result = sqlx::query!(
         r#"
-        SELECT subscriber_id
-        FROM subscription_tokens
-        WHERE subscription_token = $1
+        UPDATE subscriptions
+        SET email = $2
+        WHERE id = $1 AND NOT EXISTS (SELECT 1 FROM subscriptions WHERE email = $2)
         "#,
-        subscribtion_token
+        subscriber_id,
+        new_email
     )
-    .fetch_optional(pool)
-    .await?;
+    .execute(&mut **transaction)
+    .await;
 
-    Ok(result.map(|r| r.subscriber_id))
+    match result {
+        Ok(result) => Ok(result.rows_affected() > 0),
+        // signed up concurrently, after the check above
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+pub struct SubscriptionToken {
+    pub subscriber_id: Uuid,
+    /// Set when the token confirms a change of the email address, see the preference center
+    pub new_email: Option<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Deletes the token and returns what it was for, the row stays locked until the
+/// transaction ends, so a concurrent attempt with the same token waits and finds nothing
+#[tracing::instrument(
+    name = "Consuming a subscription token",
+    skip(transaction, secret, subscribtion_token)
+)]
+pub async fn consume_subscription_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    secret: &Secret<String>,
+    subscribtion_token: &str,
+) -> Result<Option<SubscriptionToken>, sqlx::Error> {
+    sqlx::query_as!(
+        SubscriptionToken,
+        r#"
+        DELETE FROM subscription_tokens
+        WHERE token_hash = $1
+        RETURNING subscriber_id, new_email, expires_at
+        "#,
+        hmac_token(secret, subscribtion_token)
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+}
+
+#[tracing::instrument(name = "Fetching the subscriber email", skip(transaction))]
+async fn get_subscriber_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<String, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT email FROM subscriptions WHERE id = $1"#,
+        subscriber_id
+    )
+    .fetch_one(&mut **transaction)
+    .await
 }
@@ -0,0 +1,429 @@
+use std::collections::HashMap;
+
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse, ResponseError};
+use actix_web_flash_messages::{FlashMessage, IncomingFlashMessages, Level};
+use anyhow::Context;
+use askama::Template;
+use chrono::Utc;
+use secrecy::Secret;
+use sqlx::{PgPool, Postgres, Transaction};
+use tracing::Instrument;
+use uuid::Uuid;
+
+use crate::authentication::hmac_token;
+use crate::csrf::CsrfToken;
+use crate::domain::{SubscriberEmail, SubscriberName};
+use crate::email_client::{EmailSender, SendEmailError};
+use crate::errors::error_chain_fmt;
+use crate::routes::login::AuthLayout;
+use crate::routes::subscriptions::{generate_subscription_token, SUBSCRIPTION_TOKEN_TTL};
+use crate::startup::{ApplicationBaseUrl, HmacSecret};
+use crate::subscriber_token::{self, TokenPurpose};
+use crate::utils::{flash_messages_with_level, see_other};
+
+#[derive(serde::Deserialize)]
+pub struct PreferencesParameters {
+    token: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct PreferencesFormData {
+    token: String,
+    name: String,
+    email: String,
+    // a `topic_<topic id>` checkbox per topic, only present when ticked
+    #[serde(flatten)]
+    fields: HashMap<String, String>,
+}
+
+impl PreferencesFormData {
+    fn selected_topics(&self) -> Vec<Uuid> {
+        self.fields
+            .keys()
+            .filter_map(|key| key.strip_prefix("topic_")?.parse().ok())
+            .collect()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "subscriptions/preferences.html")]
+struct PreferencesPage<'a> {
+    csrf_token: &'a str,
+    token: &'a str,
+    name: &'a str,
+    email: &'a str,
+    topics: Vec<TopicChoice>,
+    unsubscribe_link: &'a str,
+    info_messages: Vec<&'a str>,
+    error_messages: Vec<&'a str>,
+}
+
+struct TopicChoice {
+    topic_id: Uuid,
+    name: String,
+    selected: bool,
+}
+
+#[derive(thiserror::Error)]
+pub enum PreferencesError {
+    #[error("The preferences token is invalid")]
+    InvalidToken,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for PreferencesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for PreferencesError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        match self {
+            PreferencesError::InvalidToken => actix_web::http::StatusCode::UNAUTHORIZED,
+            PreferencesError::UnexpectedError(_) => {
+                actix_web::http::StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+}
+
+/// The link for the footer of every email to the subscriber, no login needed
+pub fn preferences_link(
+    base_url: &str,
+    hmac_secret: &Secret<String>,
+    subscriber_id: Uuid,
+) -> String {
+    format!(
+        "{}/subscriptions/preferences?token={}",
+        base_url,
+        subscriber_token::sign(hmac_secret, TokenPurpose::Preferences, subscriber_id)
+    )
+}
+
+pub async fn preferences_form(
+    params: web::Query<PreferencesParameters>,
+    pool: web::Data<PgPool>,
+    hmac_secret: web::Data<HmacSecret>,
+    flash_messages: IncomingFlashMessages,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse, PreferencesError> {
+    let subscriber_id =
+        subscriber_token::verify(&hmac_secret.0, TokenPurpose::Preferences, &params.token)
+            .ok_or(PreferencesError::InvalidToken)?;
+    let subscriber = get_subscriber(&pool, subscriber_id)
+        .await
+        .context("Failed to load the subscriber")?
+        .ok_or(PreferencesError::InvalidToken)?;
+    let topics = get_topic_choices(&pool, subscriber_id)
+        .await
+        .context("Failed to load the topics")?;
+
+    let unsubscribe_link = format!(
+        "/subscriptions/unsubscribe?token={}",
+        subscriber_token::sign(&hmac_secret.0, TokenPurpose::Unsubscribe, subscriber_id)
+    );
+    let page = PreferencesPage {
+        csrf_token: csrf_token.as_str(),
+        token: &params.token,
+        name: &subscriber.name,
+        email: &subscriber.email,
+        topics,
+        unsubscribe_link: &unsubscribe_link,
+        info_messages: flash_messages_with_level(&flash_messages, Level::Info),
+        error_messages: flash_messages_with_level(&flash_messages, Level::Error),
+    };
+    let layout = AuthLayout {
+        title: "Preferences",
+        body: &page
+            .render()
+            .context("Failed to render the preferences page")?,
+        csrf_token: csrf_token.as_str(),
+    };
+
+    Ok(HttpResponse::Ok().content_type(ContentType::html()).body(
+        layout
+            .render()
+            .context("Failed to render the preferences page")?,
+    ))
+}
+
+/// A new name and the topic choices are saved right away, a new email address only once
+/// it is confirmed through a link sent to it, like signing up in the first place
+#[tracing::instrument(
+    name = "Updating subscriber preferences",
+    skip(form, pool, email_client, base_url, hmac_secret),
+    fields(subscriber_id = tracing::field::Empty)
+)]
+pub async fn update_preferences(
+    form: web::Form<PreferencesFormData>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<dyn EmailSender>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    hmac_secret: web::Data<HmacSecret>,
+) -> Result<HttpResponse, PreferencesError> {
+    let form = form.0;
+    let selected_topics = form.selected_topics();
+    let subscriber_id =
+        subscriber_token::verify(&hmac_secret.0, TokenPurpose::Preferences, &form.token)
+            .ok_or(PreferencesError::InvalidToken)?;
+    tracing::Span::current().record("subscriber_id", tracing::field::display(subscriber_id));
+    let retry_location = format!(
+        "/subscriptions/preferences?token={}",
+        urlencoding::encode(&form.token)
+    );
+
+    let (name, email) = match (
+        SubscriberName::parse(form.name),
+        SubscriberEmail::parse(form.email),
+    ) {
+        (Ok(name), Ok(email)) => (name, email),
+        (Err(e), _) | (_, Err(e)) => {
+            FlashMessage::error(e).send();
+            return Ok(see_other(&retry_location));
+        }
+    };
+
+    let subscriber = get_subscriber(&pool, subscriber_id)
+        .await
+        .context("Failed to load the subscriber")?
+        .ok_or(PreferencesError::InvalidToken)?;
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a postgres connection from the pool")?;
+    if name.as_ref() != subscriber.name {
+        update_subscriber_name(&mut transaction, subscriber_id, &name)
+            .await
+            .context("Failed to update the subscriber name")?;
+    }
+    save_topic_choices(&mut transaction, subscriber_id, &selected_topics)
+        .await
+        .context("Failed to save the topic choices")?;
+    let email_change_token = if email.as_ref() != subscriber.email {
+        store_email_change_token(&mut transaction, &hmac_secret.0, subscriber_id, &email)
+            .await
+            .context("Failed to store the email change token")?
+    } else {
+        None
+    };
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction")?;
+
+    FlashMessage::info("Your preferences have been saved.").send();
+    if email.as_ref() != subscriber.email {
+        // the same whether or not the address belongs to somebody else already
+        FlashMessage::info(format!(
+            "We sent a confirmation link to {}, your new address is used once you followed it.",
+            email.as_ref()
+        ))
+        .send();
+    }
+
+    if let Some(token) = email_change_token {
+        // in the background, waiting for it would tell a free address from a taken one
+        tokio::spawn(
+            async move {
+                if let Err(e) = send_email_change_confirmation(
+                    email_client.get_ref(),
+                    &email,
+                    &base_url.0,
+                    &token,
+                )
+                .await
+                {
+                    tracing::error!(
+                        error.cause_chain = ?e,
+                        "Failed to send the email change confirmation."
+                    );
+                }
+            }
+            .instrument(tracing::Span::current()),
+        );
+    }
+
+    Ok(see_other(&retry_location))
+}
+
+struct Subscriber {
+    name: String,
+    email: String,
+}
+
+#[tracing::instrument(name = "Fetching subscriber from the database", skip(pool))]
+async fn get_subscriber(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<Option<Subscriber>, sqlx::Error> {
+    sqlx::query_as!(
+        Subscriber,
+        r#"
+        SELECT name, email
+        FROM subscriptions
+        WHERE id = $1
+        "#,
+        subscriber_id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+#[tracing::instrument(name = "Fetching the topic choices of a subscriber", skip(pool))]
+async fn get_topic_choices(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<Vec<TopicChoice>, sqlx::Error> {
+    sqlx::query_as!(
+        TopicChoice,
+        r#"
+        SELECT
+            t.topic_id,
+            t.name,
+            NOT EXISTS (
+                SELECT 1
+                FROM topic_opt_outs o
+                WHERE o.subscriber_id = $1 AND o.topic_id = t.topic_id
+            ) AS "selected!"
+        FROM topics t
+        ORDER BY t.name
+        "#,
+        subscriber_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Every topic that isn't ticked is turned off
+#[tracing::instrument(name = "Saving the topic choices", skip(transaction))]
+async fn save_topic_choices(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    selected_topics: &[Uuid],
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "DELETE FROM topic_opt_outs WHERE subscriber_id = $1",
+        subscriber_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO topic_opt_outs (subscriber_id, topic_id)
+        SELECT $1, topic_id
+        FROM topics
+        WHERE NOT (topic_id = ANY($2))
+        "#,
+        subscriber_id,
+        selected_topics
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "Saving the new subscriber name", skip(transaction, name))]
+async fn update_subscriber_name(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    name: &SubscriberName,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions
+        SET name = $2
+        WHERE id = $1
+        "#,
+        subscriber_id,
+        name.as_ref()
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    Ok(())
+}
+
+/// A subscription token carrying the new address, `confirm` swaps it in once the link is followed.
+/// No token when the address is subscribed already, there is nothing to confirm then.
+#[tracing::instrument(
+    name = "Saving email change token in the database",
+    skip(transaction, secret, new_email)
+)]
+async fn store_email_change_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    secret: &Secret<String>,
+    subscriber_id: Uuid,
+    new_email: &SubscriberEmail,
+) -> Result<Option<String>, sqlx::Error> {
+    let taken = sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM subscriptions WHERE email = $1) AS "taken!""#,
+        new_email.as_ref()
+    )
+    .fetch_one(&mut **transaction)
+    .await?;
+    if taken {
+        return Ok(None);
+    }
+
+    let token = generate_subscription_token();
+    let now = Utc::now();
+    sqlx::query!(
+        r#"
+        INSERT INTO subscription_tokens
+            (subscriber_id, token_hash, new_email, created_at, expires_at)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+        subscriber_id,
+        hmac_token(secret, &token),
+        new_email.as_ref(),
+        now,
+        now + SUBSCRIPTION_TOKEN_TTL
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    Ok(Some(token))
+}
+
+#[tracing::instrument(
+    name = "Sending an email change confirmation",
+    skip(email_client, new_email, base_url, token)
+)]
+async fn send_email_change_confirmation(
+    email_client: &dyn EmailSender,
+    new_email: &SubscriberEmail,
+    base_url: &str,
+    token: &str,
+) -> Result<(), SendEmailError> {
+    let confirmation_link = format!(
+        "{}/subscriptions/confirm?subscription_token={}",
+        base_url, token
+    );
+    let plain_body = format!(
+        "You asked to receive our newsletter at this address from now on.\n\
+        Visit {} to confirm the change. The link expires in 24 hours.",
+        confirmation_link
+    );
+    let html_body = format!(
+        "You asked to receive our newsletter at this address from now on.<br />\
+        Click <a href=\"{}\">here</a> to confirm the change. The link expires in 24 hours.",
+        confirmation_link
+    );
+
+    email_client
+        .send_email(
+            new_email,
+            "Confirm your new email address",
+            &html_body,
+            &plain_body,
+        )
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,160 @@
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use askama::Template;
+use secrecy::Secret;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::csrf::CsrfToken;
+use crate::errors::error_chain_fmt;
+use crate::routes::login::AuthLayout;
+use crate::startup::HmacSecret;
+use crate::subscriber_token::{self, TokenPurpose};
+
+#[derive(serde::Deserialize)]
+pub struct UnsubscribeParameters {
+    token: String,
+}
+
+#[derive(Template)]
+#[template(path = "subscriptions/unsubscribe.html")]
+struct UnsubscribePage<'a> {
+    token: &'a str,
+    unsubscribed: bool,
+}
+
+#[derive(thiserror::Error)]
+pub enum UnsubscribeError {
+    #[error("The unsubscribe token is invalid")]
+    InvalidToken,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for UnsubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for UnsubscribeError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        match self {
+            UnsubscribeError::InvalidToken => actix_web::http::StatusCode::UNAUTHORIZED,
+            UnsubscribeError::UnexpectedError(_) => {
+                actix_web::http::StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+}
+
+/// The link for the footer and the `List-Unsubscribe` header of every email to the subscriber
+pub fn unsubscribe_link(
+    base_url: &str,
+    hmac_secret: &Secret<String>,
+    subscriber_id: Uuid,
+) -> String {
+    format!(
+        "{}/subscriptions/unsubscribe?token={}",
+        base_url,
+        subscriber_token::sign(hmac_secret, TokenPurpose::Unsubscribe, subscriber_id)
+    )
+}
+
+/// Asks for confirmation only, link scanners and prefetching mail clients follow
+/// links in emails and must not unsubscribe anybody by doing so
+pub async fn unsubscribe_form(
+    params: web::Query<UnsubscribeParameters>,
+    hmac_secret: web::Data<HmacSecret>,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse, UnsubscribeError> {
+    subscriber_token::verify(&hmac_secret.0, TokenPurpose::Unsubscribe, &params.token)
+        .ok_or(UnsubscribeError::InvalidToken)?;
+
+    render(&params.token, false, &csrf_token)
+}
+
+/// Both the form above and one-click unsubscribe (RFC 8058), where the mail client posts
+/// `List-Unsubscribe=One-Click` to the `List-Unsubscribe` link. No CSRF token for either:
+/// mail clients don't have one, and the signed token already proves the link was ours.
+#[tracing::instrument(
+    name = "Unsubscribing a subscriber",
+    skip(params, pool, hmac_secret, csrf_token)
+)]
+pub async fn unsubscribe(
+    params: web::Query<UnsubscribeParameters>,
+    pool: web::Data<PgPool>,
+    hmac_secret: web::Data<HmacSecret>,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse, UnsubscribeError> {
+    let subscriber_id =
+        subscriber_token::verify(&hmac_secret.0, TokenPurpose::Unsubscribe, &params.token)
+            .ok_or(UnsubscribeError::InvalidToken)?;
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a postgres connection from the pool")?;
+    unsubscribe_subscriber(&mut transaction, subscriber_id)
+        .await
+        .context("Failed to unsubscribe the subscriber")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction")?;
+
+    render(&params.token, true, &csrf_token)
+}
+
+/// Outstanding signup and email change links go as well, following one of them must not
+/// confirm the subscriber again
+#[tracing::instrument(name = "Mark subscriber as unsubscribed", skip(transaction))]
+pub async fn unsubscribe_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions
+        SET status = 'unsubscribed'
+        WHERE id = $1
+        "#,
+        subscriber_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    sqlx::query!(
+        "DELETE FROM subscription_tokens WHERE subscriber_id = $1",
+        subscriber_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    Ok(())
+}
+
+fn render(
+    token: &str,
+    unsubscribed: bool,
+    csrf_token: &CsrfToken,
+) -> Result<HttpResponse, UnsubscribeError> {
+    let page = UnsubscribePage {
+        token,
+        unsubscribed,
+    };
+    let layout = AuthLayout {
+        title: "Unsubscribe",
+        body: &page
+            .render()
+            .context("Failed to render the unsubscribe page")?,
+        csrf_token: csrf_token.as_str(),
+    };
+
+    Ok(HttpResponse::Ok().content_type(ContentType::html()).body(
+        layout
+            .render()
+            .context("Failed to render the unsubscribe page")?,
+    ))
+}
@@ -0,0 +1,67 @@
+use std::future::{ready, Ready};
+
+use actix_session::{Session, SessionExt, SessionGetError, SessionInsertError};
+use actix_web::dev::Payload;
+use actix_web::{FromRequest, HttpRequest};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+pub(crate) const USER_ID_KEY: &str = "user_id";
+const PENDING_TWO_FACTOR_KEY: &str = "pending_two_factor";
+
+/// A user that passed the password check but still owes the second factor
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct PendingTwoFactor {
+    pub user_id: Uuid,
+    /// As entered on the login form, wrong codes count against it in the login throttle
+    pub username: String,
+    pub started_at: DateTime<Utc>,
+    pub failed_attempts: u8,
+}
+
+/// Typed wrapper around the actix session, so handlers don't have to deal with raw keys.
+pub struct TypedSession(Session);
+
+impl TypedSession {
+    /// Rotates the session key, call it whenever the privilege level changes (e.g. on login)
+    pub fn renew(&self) {
+        self.0.renew();
+    }
+
+    pub fn insert_user_id(&self, user_id: Uuid) -> Result<(), SessionInsertError> {
+        self.0.insert(USER_ID_KEY, user_id)
+    }
+
+    pub fn get_user_id(&self) -> Result<Option<Uuid>, SessionGetError> {
+        self.0.get(USER_ID_KEY)
+    }
+
+    pub fn insert_pending_two_factor(
+        &self,
+        pending: &PendingTwoFactor,
+    ) -> Result<(), SessionInsertError> {
+        self.0.insert(PENDING_TWO_FACTOR_KEY, pending)
+    }
+
+    pub fn get_pending_two_factor(&self) -> Result<Option<PendingTwoFactor>, SessionGetError> {
+        self.0.get(PENDING_TWO_FACTOR_KEY)
+    }
+
+    pub fn remove_pending_two_factor(&self) {
+        self.0.remove(PENDING_TWO_FACTOR_KEY);
+    }
+
+    /// Removes the session state on the server and the cookie on the client
+    pub fn log_out(self) {
+        self.0.purge();
+    }
+}
+
+impl FromRequest for TypedSession {
+    type Error = <Session as FromRequest>::Error;
+    type Future = Ready<Result<TypedSession, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(TypedSession(req.get_session())))
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+
+use actix_session::storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError};
+use actix_web::cookie::time::Duration;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use rand::distributions::Alphanumeric;
+use rand::{rngs::OsRng, Rng};
+use sqlx::types::Json;
+use sqlx::{PgExecutor, PgPool};
+use uuid::Uuid;
+
+use crate::session_state::USER_ID_KEY;
+
+type SessionState = HashMap<String, String>;
+
+/// Session store backed by the `sessions` table.
+///
+/// The user id is lifted out of the session state into its own column,
+/// so all sessions of a user can be found (and revoked) with a single query.
+#[derive(Clone)]
+pub struct PostgresSessionStore {
+    pool: PgPool,
+}
+
+impl PostgresSessionStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+impl SessionStore for PostgresSessionStore {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        let row = sqlx::query!(
+            r#"
+            SELECT state AS "state: Json<SessionState>"
+            FROM sessions
+            WHERE session_key = $1 AND expires_at > now()
+            "#,
+            session_key.as_ref()
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .context("Failed to load session state.")
+        .map_err(LoadError::Other)?;
+
+        Ok(row.map(|r| r.state.0))
+    }
+
+    async fn save(
+        &self,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        let session_key = generate_session_key();
+
+        // piggyback on logins to get rid of sessions nobody will ever come back for
+        sqlx::query!("DELETE FROM sessions WHERE expires_at <= now()")
+            .execute(&self.pool)
+            .await
+            .context("Failed to delete expired sessions.")
+            .map_err(SaveError::Other)?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO sessions (session_key, user_id, state, expires_at)
+            VALUES ($1, $2, $3, $4)
+            "#,
+            session_key.as_ref(),
+            user_id(&session_state),
+            Json(&session_state) as _,
+            expires_at(ttl)
+        )
+        .execute(&self.pool)
+        .await
+        .context("Failed to save session state.")
+        .map_err(SaveError::Other)?;
+
+        Ok(session_key)
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE sessions
+            SET user_id = $2, state = $3, expires_at = $4
+            WHERE session_key = $1 AND expires_at > now()
+            "#,
+            session_key.as_ref(),
+            user_id(&session_state),
+            Json(&session_state) as _,
+            expires_at(ttl)
+        )
+        .execute(&self.pool)
+        .await
+        .context("Failed to update session state.")
+        .map_err(UpdateError::Other)?;
+
+        // the session expired (or was revoked) in the meantime. Saving the state again would
+        // bring a revoked login back, so the fresh session starts out empty.
+        if result.rows_affected() == 0 {
+            return self
+                .save(SessionState::new(), ttl)
+                .await
+                .map_err(|e| match e {
+                    SaveError::Serialization(e) => UpdateError::Serialization(e),
+                    SaveError::Other(e) => UpdateError::Other(e),
+                });
+        }
+
+        Ok(session_key)
+    }
+
+    async fn update_ttl(
+        &self,
+        session_key: &SessionKey,
+        ttl: &Duration,
+    ) -> Result<(), anyhow::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE sessions
+            SET expires_at = $2
+            WHERE session_key = $1
+            "#,
+            session_key.as_ref(),
+            expires_at(ttl)
+        )
+        .execute(&self.pool)
+        .await
+        .context("Failed to update session TTL.")?;
+
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        sqlx::query!(
+            "DELETE FROM sessions WHERE session_key = $1",
+            session_key.as_ref()
+        )
+        .execute(&self.pool)
+        .await
+        .context("Failed to delete session.")?;
+
+        Ok(())
+    }
+}
+
+fn user_id(session_state: &SessionState) -> Option<Uuid> {
+    session_state
+        .get(USER_ID_KEY)
+        .and_then(|value| serde_json::from_str(value).ok())
+}
+
+fn expires_at(ttl: &Duration) -> DateTime<Utc> {
+    Utc::now() + chrono::Duration::seconds(ttl.whole_seconds())
+}
+
+// same recipe as the built-in stores: 64 alphanumeric characters from the OS rng
+fn generate_session_key() -> SessionKey {
+    let key: String = std::iter::repeat_with(|| OsRng.sample(Alphanumeric))
+        .map(char::from)
+        .take(64)
+        .collect();
+
+    key.try_into()
+        .expect("64 alphanumeric characters are a valid session key")
+}
+
+/// Invalidates every session of a user, e.g. after a password change or a suspected compromise.
+#[tracing::instrument(name = "Revoke all sessions of a user", skip(executor))]
+pub async fn revoke_user_sessions(
+    executor: impl PgExecutor<'_>,
+    user_id: Uuid,
+) -> Result<u64, anyhow::Error> {
+    let result = sqlx::query!("DELETE FROM sessions WHERE user_id = $1", user_id)
+        .execute(executor)
+        .await
+        .context("Failed to revoke the sessions of a user.")?;
+
+    Ok(result.rows_affected())
+}
@@ -1,11 +1,21 @@
 use std::net::TcpListener;
+use std::sync::Arc;
 
-use crate::configuration::{DatabaseSettings, Settings};
-use crate::email_client::EmailClient;
+use crate::authentication::{
+    reject_anonymous_users, require_permission, PasswordHashing, Permission, TotpEncryptionKey,
+    TrustedProxies,
+};
+use crate::configuration::{ApplicationSettings, DatabaseSettings, Settings};
+use crate::csrf::{protect_against_csrf, CsrfKey};
+use crate::email_client::EmailSender;
+use crate::issue_delivery_worker::IssueDeliveryWorker;
+use crate::session_store::PostgresSessionStore;
 use actix_files as fs;
+use actix_session::SessionMiddleware;
 use actix_web::cookie::Key;
 use actix_web::{dev::Server, web, App, HttpServer};
 use actix_web_flash_messages::{storage::CookieMessageStore, FlashMessagesFramework};
+use actix_web_lab::middleware::from_fn;
 
 use secrecy::{ExposeSecret, Secret};
 use sqlx::postgres::PgPoolOptions;
@@ -17,25 +27,29 @@ use crate::routes::*;
 pub struct Application {
     port: u16,
     server: Server,
+    delivery_worker: IssueDeliveryWorker,
 }
 
 impl Application {
     pub async fn build(configuration: Settings) -> Result<Self, std::io::Error> {
         let connection_pool = get_connection_pool(&configuration.database);
 
-        let sender_email = configuration
-            .email_client
-            .sender()
-            .expect("Invalid sender email address");
+        // links sent before tokens were hashed must keep working, also where the schema
+        // is migrated with `sqlx migrate run` instead of `rust2prod migrate`
+        let hashed = hash_legacy_subscription_tokens(
+            &connection_pool,
+            &configuration.application.hmac_secret,
+        )
+        .await
+        .map_err(std::io::Error::other)?;
+        if hashed > 0 {
+            tracing::info!(hashed, "Hashed subscription tokens stored in plain text.");
+        }
 
-        let timeout = configuration.email_client.timeout();
+        let email_client = configuration.email_client.clone().client();
 
-        let email_client = EmailClient::new(
-            configuration.email_client.base_url,
-            sender_email,
-            configuration.email_client.authorization_token,
-            timeout,
-        );
+        let password_hashing = PasswordHashing::new(&configuration.application.password_hashing)
+            .expect("Invalid password hashing settings");
 
         let address = format!(
             "{}:{}",
@@ -45,23 +59,42 @@ impl Application {
         let listener = TcpListener::bind(address)?;
         let port = listener.local_addr().unwrap().port();
 
+        // the worker shares the email client with the request handlers
+        let delivery_worker = IssueDeliveryWorker::new(
+            connection_pool.clone(),
+            email_client.clone(),
+            configuration.email_client.concurrency,
+            configuration.application.base_url.clone(),
+            configuration.application.hmac_secret.clone(),
+        );
+
         let server = run(
             listener,
             connection_pool,
             email_client,
-            configuration.application.base_url,
-            configuration.application.hmac_secret,
+            configuration.application,
+            password_hashing,
         )?;
 
-        Ok(Self { port, server })
+        Ok(Self {
+            port,
+            server,
+            delivery_worker,
+        })
     }
 
     pub fn port(&self) -> u16 {
         self.port
     }
 
+    /// Runs the HTTP server and the issue delivery worker side by side,
+    /// tasks the worker was busy with when the server stops stay in the queue
     pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
-        self.server.await
+        let worker = tokio::spawn(self.delivery_worker.run_until_stopped());
+        let result = self.server.await;
+        worker.abort();
+
+        result
     }
 }
 
@@ -77,37 +110,139 @@ pub struct HmacSecret(pub Secret<String>);
 pub fn run(
     listener: TcpListener,
     connection_pool: PgPool,
-    email_client: EmailClient,
-    base_url: String,
-    hmac_secret: Secret<String>,
+    email_client: Arc<dyn EmailSender>,
+    settings: ApplicationSettings,
+    password_hashing: PasswordHashing,
 ) -> Result<Server, std::io::Error> {
+    let ApplicationSettings {
+        base_url,
+        hmac_secret,
+        totp_encryption_key,
+        trusted_proxies,
+        ..
+    } = settings;
+    let session_store = PostgresSessionStore::new(connection_pool.clone());
     let connection_pool = web::Data::new(connection_pool);
-    let email_client = web::Data::new(email_client);
+    let email_client: web::Data<dyn EmailSender> = web::Data::from(email_client);
     let base_url = web::Data::new(ApplicationBaseUrl(base_url));
-    // let hmac_secret = web::Data::new(HmacSecret(hmac_secret);
+    let totp_encryption_key = web::Data::new(
+        TotpEncryptionKey::parse(totp_encryption_key).map_err(std::io::Error::other)?,
+    );
+    let password_hashing = web::Data::new(password_hashing);
+    let trusted_proxies = web::Data::new(TrustedProxies(trusted_proxies));
 
-    let message_store =
-        CookieMessageStore::builder(Key::from(hmac_secret.expose_secret().as_bytes())).build();
+    let secret_key = Key::from(hmac_secret.expose_secret().as_bytes());
+    // signs the links in emails to subscribers
+    let hmac_secret = web::Data::new(HmacSecret(hmac_secret));
+    let message_store = CookieMessageStore::builder(secret_key.clone()).build();
     let message_framework = FlashMessagesFramework::builder(message_store).build();
+    let csrf_key = web::Data::new(CsrfKey(secret_key.clone()));
 
     let server = HttpServer::new(move || {
         App::new()
+            .wrap(from_fn(protect_against_csrf))
             .wrap(message_framework.clone())
+            .wrap(SessionMiddleware::new(
+                session_store.clone(),
+                secret_key.clone(),
+            ))
             .wrap(TracingLogger::default())
             // pass in application state (needs to be cloned bc. each worker needs to have a copy)
             .app_data(connection_pool.clone())
             .app_data(email_client.clone())
             .app_data(base_url.clone())
+            .app_data(totp_encryption_key.clone())
+            .app_data(password_hashing.clone())
+            .app_data(trusted_proxies.clone())
+            .app_data(csrf_key.clone())
+            .app_data(hmac_secret.clone())
             // home
             .route("/", web::get().to(home))
             .route("/login", web::get().to(login_form))
             .route("/login", web::post().to(login_post))
+            .route("/login/two-factor", web::post().to(login_two_factor))
+            .route("/password-reset", web::get().to(password_reset_form))
+            .route("/password-reset", web::post().to(request_password_reset))
+            .route(
+                "/password-reset/confirm",
+                web::get().to(password_reset_confirm_form),
+            )
+            .route(
+                "/password-reset/confirm",
+                web::post().to(confirm_password_reset),
+            )
+            // admin, only for logged in users
+            .service(
+                web::scope("/admin")
+                    .wrap(from_fn(reject_anonymous_users))
+                    .route("/dashboard", web::get().to(admin_dashboard))
+                    .route("/password", web::get().to(change_password_form))
+                    .route("/password", web::post().to(change_password))
+                    .route("/two-factor", web::get().to(two_factor_form))
+                    .route("/two-factor", web::post().to(enable_two_factor))
+                    .route("/two-factor/disable", web::post().to(disable_two_factor))
+                    .service(
+                        web::scope("/tokens")
+                            .wrap(from_fn(|req, next| {
+                                require_permission(Permission::ManageApiTokens, req, next)
+                            }))
+                            .route("", web::get().to(api_tokens_form))
+                            .route("", web::post().to(create_api_token))
+                            .route("/{token_id}/revoke", web::post().to(revoke_api_token)),
+                    )
+                    .service(
+                        web::scope("/issues")
+                            .wrap(from_fn(|req, next| {
+                                require_permission(Permission::ReadStats, req, next)
+                            }))
+                            .route("", web::get().to(newsletter_issues))
+                            .route("/{issue_id}", web::get().to(newsletter_issue))
+                            .route("/{issue_id}/progress", web::get().to(issue_progress))
+                            .service(
+                                web::resource("/{issue_id}/requeue")
+                                    .wrap(from_fn(|req, next| {
+                                        require_permission(
+                                            Permission::PublishNewsletters,
+                                            req,
+                                            next,
+                                        )
+                                    }))
+                                    .route(web::post().to(requeue_failed_deliveries)),
+                            ),
+                    )
+                    .route("/logout", web::post().to(log_out))
+                    .route("/logout/everywhere", web::post().to(log_out_everywhere)),
+            )
             // GET health_check
             .route("/health_check", web::get().to(health_check))
             // POST subscriptions
             .route("/subscriptions", web::post().to(subscribe))
             // POST subscriptions/confirm
             .route("/subscriptions/confirm", web::get().to(confirm))
+            // GET/POST subscriptions/resend-confirmation
+            .route(
+                "/subscriptions/resend-confirmation",
+                web::get().to(resend_confirmation_form),
+            )
+            .route(
+                "/subscriptions/resend-confirmation",
+                web::post().to(resend_confirmation),
+            )
+            // GET/POST subscriptions/preferences
+            .route(
+                "/subscriptions/preferences",
+                web::get().to(preferences_form),
+            )
+            .route(
+                "/subscriptions/preferences",
+                web::post().to(update_preferences),
+            )
+            // GET/POST subscriptions/unsubscribe
+            .route(
+                "/subscriptions/unsubscribe",
+                web::get().to(unsubscribe_form),
+            )
+            .route("/subscriptions/unsubscribe", web::post().to(unsubscribe))
             // GET subscriptions
             .route("/subscriptions", web::get().to(get_all_subscribers))
             // POST newsletters
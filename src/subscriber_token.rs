@@ -0,0 +1,123 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What a token grants, a token signed for one purpose is worthless for any other
+#[derive(Clone, Copy, Debug)]
+pub enum TokenPurpose {
+    Unsubscribe,
+    Preferences,
+}
+
+impl TokenPurpose {
+    fn as_str(self) -> &'static str {
+        match self {
+            TokenPurpose::Unsubscribe => "unsubscribe",
+            TokenPurpose::Preferences => "preferences",
+        }
+    }
+}
+
+/// Stateless token for links in emails that act on behalf of a subscriber.
+///
+/// The subscriber id followed by an HMAC-SHA256 over purpose and id, keyed with
+/// `hmac_secret`: nothing to store, and it can't be guessed or forged from an id.
+pub fn sign(secret: &Secret<String>, purpose: TokenPurpose, subscriber_id: Uuid) -> String {
+    let mut token = subscriber_id.as_bytes().to_vec();
+    token.extend(mac(secret, purpose, subscriber_id).finalize().into_bytes());
+
+    URL_SAFE_NO_PAD.encode(token)
+}
+
+/// The subscriber the token was signed for, if the signature holds
+pub fn verify(secret: &Secret<String>, purpose: TokenPurpose, token: &str) -> Option<Uuid> {
+    let token = URL_SAFE_NO_PAD.decode(token).ok()?;
+    if token.len() <= 16 {
+        return None;
+    }
+    let (subscriber_id, signature) = token.split_at(16);
+    let subscriber_id = Uuid::from_slice(subscriber_id).ok()?;
+
+    // constant time, a timing side channel must not give the signature away
+    mac(secret, purpose, subscriber_id)
+        .verify_slice(signature)
+        .ok()?;
+
+    Some(subscriber_id)
+}
+
+fn mac(secret: &Secret<String>, purpose: TokenPurpose, subscriber_id: Uuid) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.expose_secret().as_bytes())
+        .expect("HMAC takes keys of any length");
+    mac.update(purpose.as_str().as_bytes());
+    mac.update(b":");
+    mac.update(subscriber_id.as_bytes());
+
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::subscriber_token::{sign, verify, TokenPurpose};
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    fn secret() -> Secret<String> {
+        Secret::new("super-long-and-secret-random-key-needed-to-verify-message-integrity".into())
+    }
+
+    #[test]
+    fn a_signed_token_verifies_to_its_subscriber() {
+        let subscriber_id = Uuid::new_v4();
+
+        let token = sign(&secret(), TokenPurpose::Unsubscribe, subscriber_id);
+
+        assert_eq!(
+            verify(&secret(), TokenPurpose::Unsubscribe, &token),
+            Some(subscriber_id)
+        );
+    }
+
+    #[test]
+    fn a_token_signed_with_another_secret_is_rejected() {
+        let token = sign(
+            &Secret::new("another-secret".into()),
+            TokenPurpose::Unsubscribe,
+            Uuid::new_v4(),
+        );
+
+        assert_eq!(verify(&secret(), TokenPurpose::Unsubscribe, &token), None);
+    }
+
+    #[test]
+    fn a_token_for_another_subscriber_can_not_be_made_up() {
+        let token = sign(&secret(), TokenPurpose::Unsubscribe, Uuid::new_v4());
+        // keep the signature, swap the subscriber id
+        let forged = format!(
+            "{}{}",
+            &sign(&secret(), TokenPurpose::Unsubscribe, Uuid::new_v4())[..22],
+            &token[22..]
+        );
+
+        assert_eq!(verify(&secret(), TokenPurpose::Unsubscribe, &forged), None);
+    }
+
+    #[test]
+    fn a_token_is_only_good_for_its_purpose() {
+        let token = sign(&secret(), TokenPurpose::Unsubscribe, Uuid::new_v4());
+
+        assert_eq!(verify(&secret(), TokenPurpose::Preferences, &token), None);
+    }
+
+    #[test]
+    fn malformed_tokens_are_rejected() {
+        for token in ["", "not base64!", "c2hvcnQ", &Uuid::new_v4().to_string()] {
+            assert_eq!(verify(&secret(), TokenPurpose::Unsubscribe, token), None);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// What an issue can be about, subscribers choose the topics they want in the preference center
+pub struct Topic {
+    pub topic_id: Uuid,
+    pub name: String,
+}
+
+#[tracing::instrument(name = "Create topic", skip(pool))]
+pub async fn create_topic(pool: &PgPool, name: &str) -> Result<Uuid, anyhow::Error> {
+    let name = name.trim();
+    anyhow::ensure!(
+        !name.is_empty() && name.chars().count() <= 100,
+        "A topic name must be between 1 and 100 characters long."
+    );
+
+    let topic_id = Uuid::new_v4();
+    let inserted = sqlx::query!(
+        r#"
+        INSERT INTO topics (topic_id, name, created_at)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (name) DO NOTHING
+        "#,
+        topic_id,
+        name,
+        Utc::now()
+    )
+    .execute(pool)
+    .await
+    .context("Failed to store the topic.")?
+    .rows_affected();
+    anyhow::ensure!(inserted > 0, "There is a topic {} already.", name);
+
+    Ok(topic_id)
+}
+
+pub async fn list_topics(pool: &PgPool) -> Result<Vec<Topic>, anyhow::Error> {
+    let topics = sqlx::query_as!(Topic, "SELECT topic_id, name FROM topics ORDER BY name")
+        .fetch_all(pool)
+        .await
+        .context("Failed to list topics.")?;
+
+    Ok(topics)
+}
+
+pub async fn find_topic(pool: &PgPool, name: &str) -> Result<Option<Uuid>, anyhow::Error> {
+    let topic_id = sqlx::query_scalar!("SELECT topic_id FROM topics WHERE name = $1", name)
+        .fetch_optional(pool)
+        .await
+        .context("Failed to look up the topic.")?;
+
+    Ok(topic_id)
+}
@@ -0,0 +1,26 @@
+use actix_web::http::header::LOCATION;
+use actix_web::HttpResponse;
+use actix_web_flash_messages::{IncomingFlashMessages, Level};
+
+// return an opaque 500 while preserving the error root cause for logging
+pub fn e500<T>(e: T) -> actix_web::Error
+where
+    T: std::fmt::Debug + std::fmt::Display + 'static,
+{
+    actix_web::error::ErrorInternalServerError(e)
+}
+
+pub fn see_other(location: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header((LOCATION, location))
+        .finish()
+}
+
+// flash message contents of a single level, in the order they were sent
+pub fn flash_messages_with_level(messages: &IncomingFlashMessages, level: Level) -> Vec<&str> {
+    messages
+        .iter()
+        .filter(|m| m.level() == level)
+        .map(|m| m.content())
+        .collect()
+}
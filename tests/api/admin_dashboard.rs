@@ -0,0 +1,67 @@
+use crate::helpers::{assert_is_redirect_to, spawn_app};
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_access_the_admin_dashboard() {
+    // arrange
+    let app = spawn_app().await;
+
+    // act
+    let response = app.get_admin_dashboard().await;
+
+    // assert
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn htmx_requests_of_anonymous_users_are_told_to_redirect_to_login() {
+    // arrange
+    let app = spawn_app().await;
+
+    // act
+    let response = app
+        .api_client
+        .get(format!("{}/admin/dashboard", &app.address))
+        .header("HX-Request", "true")
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // assert
+    assert_eq!(response.headers().get("HX-Redirect").unwrap(), "/login");
+}
+
+#[tokio::test]
+async fn logged_in_users_can_access_the_admin_dashboard() {
+    // arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    // act
+    let response = app.get_admin_dashboard().await;
+
+    // assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains(&app.test_user.username));
+}
+
+#[tokio::test]
+async fn the_admin_dashboard_is_no_longer_accessible_after_logout() {
+    // arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    assert!(app
+        .get_admin_dashboard_html()
+        .await
+        .contains(&app.test_user.username));
+
+    // act
+    app.post_logout().await;
+
+    // assert
+    let response = app.get_admin_dashboard().await;
+    assert_is_redirect_to(&response, "/login");
+}
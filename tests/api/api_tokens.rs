@@ -0,0 +1,210 @@
+use uuid::Uuid;
+use wiremock::matchers::any;
+use wiremock::{Mock, ResponseTemplate};
+
+use crate::helpers::{assert_is_redirect_to, spawn_app, TestApplication};
+
+fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "newsletter title",
+        "content": {
+            "text": "Newsletter content",
+            "html": "<h1>Newsletter content</h1>"
+        }
+    })
+}
+
+async fn token_id(app: &TestApplication) -> Uuid {
+    sqlx::query!("SELECT token_id FROM api_tokens")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap()
+        .token_id
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_manage_api_tokens() {
+    let app = spawn_app().await;
+
+    let response = app.get_api_tokens().await;
+
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn created_tokens_are_shown_once_and_stored_hashed() {
+    // arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    // act
+    let token = app.create_api_token(&["newsletters:publish"]).await;
+
+    // assert
+    let row = sqlx::query!("SELECT token_hash, scopes, last_used_at FROM api_tokens")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert_ne!(row.token_hash, token);
+    assert!(!row.token_hash.contains(&token));
+    assert_eq!(row.scopes, vec!["newsletters:publish".to_string()]);
+    assert!(row.last_used_at.is_none());
+
+    let html_page = app.get_api_tokens_html().await;
+    assert!(html_page.contains("test token"));
+    assert!(!html_page.contains(&token));
+}
+
+#[tokio::test]
+async fn a_token_requires_at_least_one_scope() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    let response = app
+        .post_api_token(&serde_json::json!({
+            "name": "no scopes",
+            "expires_in_days": 30,
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/admin/tokens");
+
+    let html_page = app.get_api_tokens_html().await;
+    assert!(html_page.contains("Select at least one scope for the token."));
+}
+
+#[tokio::test]
+async fn newsletters_can_be_published_with_a_bearer_token() {
+    // arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    let token = app.create_api_token(&["newsletters:publish"]).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    // act
+    let response = app
+        .post_newsletters_with_token(&token, newsletter_request_body())
+        .await;
+
+    // assert
+    assert_eq!(response.status().as_u16(), 202);
+
+    let last_used_at = sqlx::query!("SELECT last_used_at FROM api_tokens")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap()
+        .last_used_at;
+    assert!(last_used_at.is_some());
+}
+
+#[tokio::test]
+async fn a_token_without_the_publish_scope_is_forbidden() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    let token = app.create_api_token(&["subscribers:read"]).await;
+
+    let response = app
+        .post_newsletters_with_token(&token, newsletter_request_body())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 403);
+    assert_eq!(
+        r#"Bearer realm="publish", error="insufficient_scope", scope="newsletters:publish""#,
+        response.headers()["www-authenticate"]
+    );
+}
+
+#[tokio::test]
+async fn unknown_tokens_are_rejected_with_a_challenge() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_newsletters_with_token("r2p_notarealtoken", newsletter_request_body())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 401);
+    let challenges: Vec<_> = response
+        .headers()
+        .get_all("www-authenticate")
+        .iter()
+        .map(|v| v.to_str().unwrap())
+        .collect();
+    assert_eq!(
+        challenges,
+        vec![r#"Basic realm="publish""#, r#"Bearer realm="publish""#]
+    );
+}
+
+#[tokio::test]
+async fn revoked_tokens_are_rejected() {
+    // arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    let token = app.create_api_token(&["newsletters:publish"]).await;
+
+    // act - part 1 - revoke
+    let response = app.post_revoke_api_token(token_id(&app).await).await;
+    assert_is_redirect_to(&response, "/admin/tokens");
+
+    let html_page = app.get_api_tokens_html().await;
+    assert!(html_page.contains("The API token has been revoked."));
+
+    // act - part 2 - use it anyway
+    let response = app
+        .post_newsletters_with_token(&token, newsletter_request_body())
+        .await;
+
+    // assert
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn expired_tokens_are_rejected() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    let token = app.create_api_token(&["newsletters:publish"]).await;
+
+    sqlx::query!("UPDATE api_tokens SET expires_at = now() - interval '1 minute'")
+        .execute(&app.connection_pool)
+        .await
+        .unwrap();
+
+    let response = app
+        .post_newsletters_with_token(&token, newsletter_request_body())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn users_cannot_revoke_tokens_of_others() {
+    // arrange - the test user owns a token
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    let token = app.create_api_token(&["newsletters:publish"]).await;
+    let token_id = token_id(&app).await;
+
+    // act - somebody else tries to revoke it
+    let other_user = crate::helpers::TestUser::generate();
+    other_user.store(&app.connection_pool).await;
+    app.post_logout().await;
+    other_user.login(&app).await;
+    let response = app.post_revoke_api_token(token_id).await;
+
+    // assert
+    assert_is_redirect_to(&response, "/admin/tokens");
+    let html_page = app.get_api_tokens_html().await;
+    assert!(html_page.contains("The API token does not exist or was already revoked."));
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let response = app
+        .post_newsletters_with_token(&token, newsletter_request_body())
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+}
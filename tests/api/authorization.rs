@@ -0,0 +1,141 @@
+use wiremock::matchers::any;
+use wiremock::{Mock, ResponseTemplate};
+
+use crate::helpers::{spawn_app, TestApplication};
+
+fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "newsletter title",
+        "content": {
+            "text": "Newsletter content",
+            "html": "<h1>Newsletter content</h1>"
+        }
+    })
+}
+
+async fn mock_email_server(app: &TestApplication) {
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+}
+
+#[tokio::test]
+async fn new_users_are_viewers_by_default() {
+    let app = spawn_app().await;
+
+    let user_id = uuid::Uuid::new_v4();
+    sqlx::query!(
+        "INSERT INTO users (user_id, username, password_hash) VALUES ($1, $2, 'not-a-hash')",
+        user_id,
+        "new-user@example.com"
+    )
+    .execute(&app.connection_pool)
+    .await
+    .unwrap();
+
+    let role = sqlx::query!("SELECT role FROM users WHERE user_id = $1", user_id)
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap()
+        .role;
+    assert_eq!(role, "viewer");
+}
+
+#[tokio::test]
+async fn viewers_cannot_publish_newsletters() {
+    // arrange
+    let app = spawn_app().await;
+    app.test_user.set_role(&app, "viewer").await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // act
+    let response = app.post_newsletters(newsletter_request_body()).await;
+
+    // assert
+    assert_eq!(response.status().as_u16(), 403);
+    assert_eq!(
+        response.text().await.unwrap(),
+        "Your role (viewer) does not allow you to draft and send newsletter issues."
+    );
+}
+
+#[tokio::test]
+async fn editors_can_publish_newsletters() {
+    let app = spawn_app().await;
+    app.test_user.set_role(&app, "editor").await;
+    mock_email_server(&app).await;
+
+    let response = app.post_newsletters(newsletter_request_body()).await;
+
+    assert_eq!(response.status().as_u16(), 202);
+}
+
+#[tokio::test]
+async fn api_tokens_are_limited_by_the_role_of_their_owner() {
+    // arrange - an admin creates a token, then gets demoted
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    let token = app.create_api_token(&["newsletters:publish"]).await;
+    app.test_user.set_role(&app, "viewer").await;
+    mock_email_server(&app).await;
+
+    // act
+    let response = app
+        .post_newsletters_with_token(&token, newsletter_request_body())
+        .await;
+
+    // assert
+    assert_eq!(response.status().as_u16(), 403);
+}
+
+#[tokio::test]
+async fn only_admins_can_manage_api_tokens() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    for role in ["editor", "viewer"] {
+        app.test_user.set_role(&app, role).await;
+
+        let response = app.get_api_tokens().await;
+        assert_eq!(response.status().as_u16(), 403);
+        assert_eq!(
+            response.text().await.unwrap(),
+            format!(
+                "Your role ({}) does not allow you to manage API tokens.",
+                role
+            )
+        );
+
+        let response = app
+            .post_api_token(&serde_json::json!({
+                "name": "sneaky",
+                "scope_newsletters_publish": "on",
+                "expires_in_days": 30,
+            }))
+            .await;
+        assert_eq!(response.status().as_u16(), 403);
+    }
+
+    app.test_user.set_role(&app, "admin").await;
+    let response = app.get_api_tokens().await;
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn every_role_can_use_the_dashboard() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    for role in ["admin", "editor", "viewer"] {
+        app.test_user.set_role(&app, role).await;
+
+        let response = app.get_admin_dashboard().await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+}
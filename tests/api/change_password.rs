@@ -0,0 +1,198 @@
+use uuid::Uuid;
+
+use crate::helpers::{assert_is_redirect_to, get_csrf_token, spawn_app};
+
+const STRONG_PASSWORD: &str = "correct-Horse-battery-staple";
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_see_the_change_password_form() {
+    // arrange
+    let app = spawn_app().await;
+
+    // act
+    let response = app.get_change_password().await;
+
+    // assert
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_change_your_password() {
+    // arrange
+    let app = spawn_app().await;
+
+    // act
+    let response = app
+        .post_change_password(&serde_json::json!({
+            "current_password": Uuid::new_v4().to_string(),
+            "new_password": STRONG_PASSWORD,
+            "new_password_check": STRONG_PASSWORD,
+        }))
+        .await;
+
+    // assert
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn new_password_fields_must_match() {
+    // arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    // act - 1
+    let response = app
+        .post_change_password(&serde_json::json!({
+            "current_password": &app.test_user.password,
+            "new_password": STRONG_PASSWORD,
+            "new_password_check": "another-Horse-battery-staple",
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/admin/password");
+
+    // act - 2
+    let html_page = app.get_change_password_html().await;
+    assert!(html_page
+        .contains("You entered two different new passwords - the field values must match."));
+}
+
+#[tokio::test]
+async fn current_password_must_be_valid() {
+    // arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    // act - 1
+    let response = app
+        .post_change_password(&serde_json::json!({
+            "current_password": Uuid::new_v4().to_string(),
+            "new_password": STRONG_PASSWORD,
+            "new_password_check": STRONG_PASSWORD,
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/admin/password");
+
+    // act - 2
+    let html_page = app.get_change_password_html().await;
+    assert!(html_page.contains("The current password is incorrect."));
+}
+
+#[tokio::test]
+async fn weak_new_passwords_are_rejected() {
+    // arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    let test_cases = vec![
+        ("sh0rt-Pass", "at least 12 characters long"),
+        ("correcthorsebatterystaple", "mix at least three of"),
+    ];
+
+    for (new_password, error_message) in test_cases {
+        // act - 1
+        let response = app
+            .post_change_password(&serde_json::json!({
+                "current_password": &app.test_user.password,
+                "new_password": new_password,
+                "new_password_check": new_password,
+            }))
+            .await;
+        assert_is_redirect_to(&response, "/admin/password");
+
+        // act - 2
+        let html_page = app.get_change_password_html().await;
+        assert!(
+            html_page.contains(error_message),
+            "The password {} was not rejected with '{}'.",
+            new_password,
+            error_message
+        );
+    }
+}
+
+#[tokio::test]
+async fn changing_password_works() {
+    // arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    // act - 1
+    let response = app
+        .post_change_password(&serde_json::json!({
+            "current_password": &app.test_user.password,
+            "new_password": STRONG_PASSWORD,
+            "new_password_check": STRONG_PASSWORD,
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/admin/password");
+
+    // act - 2
+    // the current session survives the change
+    let html_page = app.get_change_password_html().await;
+    assert!(html_page.contains("Your password has been changed."));
+
+    // act - 3
+    app.post_logout().await;
+
+    // act - 4
+    // the old password does not work anymore, the new one does
+    let response = app
+        .post_login(&serde_json::json!({
+            "email": &app.test_user.username,
+            "password": &app.test_user.password,
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 418);
+
+    let response = app
+        .post_login(&serde_json::json!({
+            "email": &app.test_user.username,
+            "password": STRONG_PASSWORD,
+        }))
+        .await;
+    assert_eq!(response.headers().get("HX-Redirect").unwrap(), "/");
+}
+
+#[tokio::test]
+async fn changing_password_revokes_sessions_on_other_devices() {
+    // arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    let other_client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .cookie_store(true)
+        .build()
+        .unwrap();
+    let other_csrf_token = get_csrf_token(&other_client, &app.address).await;
+
+    other_client
+        .post(format!("{}/login", &app.address))
+        .header("X-CSRF-Token", &other_csrf_token)
+        .form(&serde_json::json!({
+            "email": &app.test_user.username,
+            "password": &app.test_user.password,
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    // act
+    app.post_change_password(&serde_json::json!({
+        "current_password": &app.test_user.password,
+        "new_password": STRONG_PASSWORD,
+        "new_password_check": STRONG_PASSWORD,
+    }))
+    .await;
+
+    // assert
+    let response = other_client
+        .get(format!("{}/admin/dashboard", &app.address))
+        .send()
+        .await
+        .unwrap();
+    assert_is_redirect_to(&response, "/login");
+
+    let response = app.get_admin_dashboard().await;
+    assert_eq!(response.status().as_u16(), 200);
+}
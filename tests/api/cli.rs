@@ -0,0 +1,249 @@
+use clap::Parser;
+use rust2prod::authentication::{
+    create_api_token, create_user, disable_user, enable_user, find_user, list_users, ApiScope,
+    PasswordHashing, Role,
+};
+use rust2prod::cli::{Cli, Command, TokenCommand, UserCommand};
+use rust2prod::configuration::PasswordHashingSettings;
+use rust2prod::domain::NewPassword;
+use rust2prod::topics::{create_topic, list_topics};
+use secrecy::Secret;
+use wiremock::matchers::any;
+use wiremock::{Mock, ResponseTemplate};
+
+use crate::helpers::spawn_app;
+
+const PASSWORD: &str = "correct-Horse-battery";
+
+fn hashing() -> PasswordHashing {
+    PasswordHashing::new(&PasswordHashingSettings {
+        memory_kib: 15000,
+        iterations: 2,
+        parallelism: 1,
+    })
+    .unwrap()
+}
+
+fn new_password() -> NewPassword {
+    NewPassword::parse(Secret::new(PASSWORD.to_string())).unwrap()
+}
+
+#[test]
+fn no_subcommand_means_serve() {
+    let cli = Cli::try_parse_from(["rust2prod"]).unwrap();
+
+    assert!(cli.command.is_none());
+}
+
+#[test]
+fn user_create_defaults_to_the_viewer_role() {
+    let cli = Cli::try_parse_from(["rust2prod", "user", "create", "ursula@example.com"]).unwrap();
+
+    match cli.command {
+        Some(Command::User(UserCommand::Create { username, role })) => {
+            assert_eq!(username, "ursula@example.com");
+            assert_eq!(role, Role::Viewer);
+        }
+        _ => panic!("Expected `user create`."),
+    }
+}
+
+#[test]
+fn unknown_roles_and_scopes_are_rejected() {
+    let invalid_role = [
+        "rust2prod",
+        "user",
+        "create",
+        "a@example.com",
+        "--role",
+        "root",
+    ];
+    let invalid_scope = [
+        "rust2prod",
+        "token",
+        "issue",
+        "a@example.com",
+        "--name",
+        "ci",
+        "--scope",
+        "all",
+    ];
+
+    assert!(Cli::try_parse_from(invalid_role).is_err());
+    assert!(Cli::try_parse_from(invalid_scope).is_err());
+}
+
+#[test]
+fn token_issue_takes_multiple_scopes() {
+    let cli = Cli::try_parse_from([
+        "rust2prod",
+        "token",
+        "issue",
+        "ursula@example.com",
+        "--name",
+        "ci",
+        "--scope",
+        "newsletters:publish",
+        "--scope",
+        "subscribers:read",
+    ])
+    .unwrap();
+
+    match cli.command {
+        Some(Command::Token(TokenCommand::Issue {
+            scopes,
+            expires_in_days,
+            ..
+        })) => {
+            assert_eq!(
+                scopes,
+                vec![ApiScope::NewslettersPublish, ApiScope::SubscribersRead]
+            );
+            assert_eq!(expires_in_days, 90);
+        }
+        _ => panic!("Expected `token issue`."),
+    }
+}
+
+#[test]
+fn token_lifetimes_out_of_range_are_rejected() {
+    for expires_in_days in ["0", "-1", "3651", "9223372036854775807"] {
+        let result = Cli::try_parse_from([
+            "rust2prod",
+            "token",
+            "issue",
+            "ursula@example.com",
+            "--name",
+            "ci",
+            "--scope",
+            "newsletters:publish",
+            "--expires-in-days",
+            expires_in_days,
+        ]);
+
+        assert!(result.is_err(), "{}", expires_in_days);
+    }
+}
+
+#[tokio::test]
+async fn topic_names_are_unique() {
+    // arrange
+    let app = spawn_app().await;
+    create_topic(&app.connection_pool, "Rust").await.unwrap();
+
+    // act
+    let result = create_topic(&app.connection_pool, " Rust ").await;
+
+    // assert
+    assert!(result.is_err());
+    let topics = list_topics(&app.connection_pool).await.unwrap();
+    assert_eq!(topics.len(), 1);
+    assert_eq!(topics[0].name, "Rust");
+}
+
+#[tokio::test]
+async fn a_created_user_can_log_in() {
+    // arrange
+    let app = spawn_app().await;
+
+    // act
+    create_user(
+        &app.connection_pool,
+        "ursula@example.com",
+        new_password(),
+        Role::Editor,
+        &hashing(),
+    )
+    .await
+    .unwrap();
+
+    // assert
+    let response = app
+        .post_login(&serde_json::json!({
+            "email": "ursula@example.com",
+            "password": PASSWORD,
+        }))
+        .await;
+    assert_eq!(response.headers().get("HX-Redirect").unwrap(), "/");
+
+    let users = list_users(&app.connection_pool).await.unwrap();
+    let ursula = users
+        .iter()
+        .find(|u| u.username == "ursula@example.com")
+        .unwrap();
+    assert_eq!(ursula.role, "editor");
+}
+
+#[tokio::test]
+async fn usernames_must_be_email_addresses() {
+    let app = spawn_app().await;
+
+    let result = create_user(
+        &app.connection_pool,
+        "ursula",
+        new_password(),
+        Role::Admin,
+        &hashing(),
+    )
+    .await;
+
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn disabled_users_cannot_log_in_or_use_their_tokens() {
+    // arrange
+    let app = spawn_app().await;
+    let user_id = find_user(&app.connection_pool, &app.test_user.username)
+        .await
+        .unwrap()
+        .unwrap();
+    let token = create_api_token(
+        &app.connection_pool,
+        user_id,
+        "ci",
+        &[ApiScope::NewslettersPublish],
+        chrono::Utc::now() + chrono::Duration::days(1),
+    )
+    .await
+    .unwrap();
+    app.test_user.login(&app).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let newsletter = serde_json::json!({
+        "title": "newsletter title",
+        "content": {
+            "text": "Newsletter content",
+            "html": "<h1>Newsletter content</h1>"
+        }
+    });
+
+    // act
+    disable_user(&app.connection_pool, user_id).await.unwrap();
+
+    // assert - the existing session is gone, and nothing gets the user back in
+    let response = app.get_admin_dashboard().await;
+    assert_eq!(response.headers()["Location"], "/login");
+
+    let response = app
+        .post_login(&serde_json::json!({
+            "email": &app.test_user.username,
+            "password": &app.test_user.password,
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 418);
+
+    let response = app
+        .post_newsletters_with_token(&token, newsletter.clone())
+        .await;
+    assert_eq!(response.status().as_u16(), 401);
+
+    // act - enable again
+    enable_user(&app.connection_pool, user_id).await.unwrap();
+
+    let response = app.post_newsletters_with_token(&token, newsletter).await;
+    assert_eq!(response.status().as_u16(), 202);
+}
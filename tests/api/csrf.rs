@@ -0,0 +1,144 @@
+use crate::helpers::{assert_is_redirect_to, extract_csrf_token, get_csrf_token, spawn_app};
+
+#[tokio::test]
+async fn the_csrf_token_stays_the_same_for_a_client() {
+    // arrange
+    let app = spawn_app().await;
+
+    // act
+    let html_page = app.get_login_html().await;
+
+    // assert
+    assert_eq!(extract_csrf_token(&html_page), app.csrf_token);
+}
+
+#[tokio::test]
+async fn forms_carry_the_csrf_token() {
+    // arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    // act
+    let html_page = app.get_change_password_html().await;
+
+    // assert - the password form and both logout forms
+    let hidden_input = format!(
+        r#"<input type="hidden" name="csrf_token" value="{}">"#,
+        app.csrf_token
+    );
+    assert_eq!(html_page.matches(&hidden_input).count(), 3);
+}
+
+#[tokio::test]
+async fn requests_without_a_csrf_token_are_rejected() {
+    // arrange
+    let app = spawn_app().await;
+
+    // act
+    let response = app
+        .api_client
+        .post(format!("{}/login", &app.address))
+        .form(&serde_json::json!({
+            "email": &app.test_user.username,
+            "password": &app.test_user.password,
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    // assert
+    assert_eq!(response.status().as_u16(), 403);
+    assert!(response.headers().get("HX-Redirect").is_none());
+}
+
+#[tokio::test]
+async fn requests_with_a_mismatched_csrf_token_are_rejected() {
+    // arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    // a token that is valid, but belongs to somebody else's cookie
+    let attacker_client = reqwest::Client::builder()
+        .cookie_store(true)
+        .build()
+        .unwrap();
+    let attacker_token = get_csrf_token(&attacker_client, &app.address).await;
+
+    for token in ["not-the-token", attacker_token.as_str()] {
+        // act
+        let response = app
+            .api_client
+            .post(format!("{}/admin/logout", &app.address))
+            .header("X-CSRF-Token", token)
+            .send()
+            .await
+            .unwrap();
+
+        // assert
+        assert_eq!(response.status().as_u16(), 403);
+    }
+
+    // still logged in
+    let html_page = app.get_admin_dashboard_html().await;
+    assert!(html_page.contains(&app.test_user.username));
+}
+
+#[tokio::test]
+async fn a_csrf_token_without_its_cookie_is_rejected() {
+    // arrange
+    let app = spawn_app().await;
+
+    // act - right token, but a client without the cookie
+    let response = reqwest::Client::new()
+        .post(format!("{}/login", &app.address))
+        .header("X-CSRF-Token", &app.csrf_token)
+        .form(&serde_json::json!({
+            "email": &app.test_user.username,
+            "password": &app.test_user.password,
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    // assert
+    assert_eq!(response.status().as_u16(), 403);
+}
+
+#[tokio::test]
+async fn the_csrf_token_can_be_sent_as_form_field() {
+    // arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    // act - like the plain logout form does
+    let response = app
+        .api_client
+        .post(format!("{}/admin/logout", &app.address))
+        .form(&serde_json::json!({ "csrf_token": &app.csrf_token }))
+        .send()
+        .await
+        .unwrap();
+
+    // assert
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn the_newsletter_api_does_not_need_a_csrf_token() {
+    // arrange
+    let app = spawn_app().await;
+
+    // act
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "newsletter title",
+            "content": {
+                "text": "Newsletter content",
+                "html": "<h1>Newsletter content</h1>"
+            }
+        }))
+        .await;
+
+    // assert - authentication decides, not the CSRF check
+    assert_eq!(response.status().as_u16(), 202);
+}
@@ -2,12 +2,13 @@ use argon2::password_hash::SaltString;
 use argon2::{Argon2, PasswordHasher};
 use once_cell::sync::Lazy;
 use reqwest::Url;
-use rust2prod::configuration::{get_configuration, DatabaseSettings};
+use rust2prod::configuration::{get_configuration, DatabaseSettings, EmailClientKind};
 use rust2prod::startup::{get_connection_pool, Application};
 use rust2prod::telemetry::{get_subscriber, init_subscriber_once};
 use sqlx::{Connection, Executor, PgConnection, PgPool};
 use uuid::Uuid;
-use wiremock::MockServer;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
 
 static TRACING: Lazy<()> = Lazy::new(|| {
     let default_filter_level = "info".to_string();
@@ -29,6 +30,8 @@ pub struct TestApplication {
     pub email_server: MockServer,
     pub test_user: TestUser,
     pub api_client: reqwest::Client,
+    /// matches the CSRF cookie in `api_client`
+    pub csrf_token: String,
 }
 
 pub struct ConfirmationLinks {
@@ -42,16 +45,266 @@ impl TestApplication {
         Body: serde::Serialize,
     {
         self.api_client
-            .post(&format!("{}/login", &self.address))
+            .post(format!("{}/login", &self.address))
+            .header("X-CSRF-Token", &self.csrf_token)
             .form(body)
             .send()
             .await
             .expect("Failed to execute request.")
     }
 
+    /// Pretends to come from another client, as seen by the load balancer
+    pub async fn post_login_from<Body>(&self, ip_address: &str, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/login", &self.address))
+            .header("X-CSRF-Token", &self.csrf_token)
+            .header("X-Forwarded-For", ip_address)
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_admin_dashboard(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/dashboard", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_admin_dashboard_html(&self) -> String {
+        self.get_admin_dashboard().await.text().await.unwrap()
+    }
+
+    pub async fn get_change_password(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/password", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_change_password_html(&self) -> String {
+        self.get_change_password().await.text().await.unwrap()
+    }
+
+    pub async fn post_change_password<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/admin/password", &self.address))
+            .header("X-CSRF-Token", &self.csrf_token)
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_password_reset(&self, email: &str) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/password-reset", &self.address))
+            .header("X-CSRF-Token", &self.csrf_token)
+            .form(&serde_json::json!({ "email": email }))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_password_reset_confirm<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/password-reset/confirm", &self.address))
+            .header("X-CSRF-Token", &self.csrf_token)
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// Emails sent from background tasks arrive a little after the response
+    pub async fn wait_for_email_requests(&self, count: usize) -> Vec<wiremock::Request> {
+        for _ in 0..50 {
+            let requests = self.email_server.received_requests().await.unwrap();
+            if requests.len() >= count {
+                return requests;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        panic!("Expected {} email requests to be received.", count);
+    }
+
+    /// Newsletter issues are delivered by the background worker, wait until it has worked through the queue
+    pub async fn wait_for_delivery_queue_to_drain(&self) {
+        for _ in 0..100 {
+            let pending =
+                sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM issue_delivery_queue"#)
+                    .fetch_one(&self.connection_pool)
+                    .await
+                    .unwrap();
+            if pending == 0 {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        panic!("The issue delivery queue was not drained.");
+    }
+
+    /// Publishes an issue to the confirmed subscribers and returns the body of the batch request
+    pub async fn publish_issue(&self) -> serde_json::Value {
+        let _mock_guard = Mock::given(path("/emails/batch"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount_as_scoped(&self.email_server)
+            .await;
+
+        let response = self
+            .post_newsletters(serde_json::json!({
+                "title": "newsletter title",
+                "content": {
+                    "text": "Newsletter content",
+                    "html": "<h1>Newsletter content</h1>"
+                }
+            }))
+            .await;
+        assert_eq!(response.status().as_u16(), 202);
+        self.wait_for_delivery_queue_to_drain().await;
+
+        let email_request = self
+            .email_server
+            .received_requests()
+            .await
+            .unwrap()
+            .pop()
+            .unwrap();
+        serde_json::from_slice(&email_request.body).unwrap()
+    }
+
+    /// The link to `path` in an email, pointed at the test application
+    pub fn get_email_link(&self, text: &str, path: &str) -> Url {
+        let links: Vec<_> = linkify::LinkFinder::new()
+            .links(text)
+            .filter(|l| *l.kind() == linkify::LinkKind::Url)
+            .map(|l| Url::parse(l.as_str()).expect("Failed to parse URL."))
+            .filter(|l| l.path() == path)
+            .collect();
+        assert_eq!(links.len(), 1);
+        let mut link = links[0].clone();
+        assert_eq!(link.host_str().unwrap(), "127.0.0.1");
+        link.set_port(Some(self.port)).unwrap();
+
+        link
+    }
+
+    pub async fn post_resend_confirmation(&self, email: &str) -> reqwest::Response {
+        self.api_client
+            .post(format!(
+                "{}/subscriptions/resend-confirmation",
+                &self.address
+            ))
+            .header("X-CSRF-Token", &self.csrf_token)
+            .form(&serde_json::json!({ "email": email }))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_resend_confirmation_html(&self) -> String {
+        self.api_client
+            .get(format!(
+                "{}/subscriptions/resend-confirmation",
+                &self.address
+            ))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn post_preferences<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/subscriptions/preferences", &self.address))
+            .header("X-CSRF-Token", &self.csrf_token)
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_two_factor_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/admin/two-factor", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn post_two_factor(&self, code: &str) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/admin/two-factor", &self.address))
+            .header("X-CSRF-Token", &self.csrf_token)
+            .form(&serde_json::json!({ "code": code }))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_disable_two_factor(&self, code: &str) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/admin/two-factor/disable", &self.address))
+            .header("X-CSRF-Token", &self.csrf_token)
+            .form(&serde_json::json!({ "code": code }))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_login_two_factor(&self, code: &str) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/login/two-factor", &self.address))
+            .header("X-CSRF-Token", &self.csrf_token)
+            .form(&serde_json::json!({ "code": code }))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_logout(&self) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/admin/logout", &self.address))
+            .header("X-CSRF-Token", &self.csrf_token)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_logout_everywhere(&self) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/admin/logout/everywhere", &self.address))
+            .header("X-CSRF-Token", &self.csrf_token)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
     pub async fn post_subscriptions(&self, body: String) -> reqwest::Response {
         self.api_client
-            .post(&format!("{}/subscriptions", &self.address))
+            .post(format!("{}/subscriptions", &self.address))
             .header("Content-Type", "application/x-www-form-urlencoded")
             .body(body)
             .send()
@@ -61,7 +314,7 @@ impl TestApplication {
 
     pub async fn post_newsletters(&self, body: serde_json::Value) -> reqwest::Response {
         self.api_client
-            .post(&format!("{}/newsletters", &self.address))
+            .post(format!("{}/newsletters", &self.address))
             .header("Content-Type", "application/json")
             .basic_auth(&self.test_user.username, Some(&self.test_user.password))
             .body(body.to_string())
@@ -70,6 +323,123 @@ impl TestApplication {
             .expect("Failed to execute request.")
     }
 
+    pub async fn post_newsletters_with_idempotency_key(
+        &self,
+        idempotency_key: &str,
+        body: serde_json::Value,
+    ) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/newsletters", &self.address))
+            .header("Idempotency-Key", idempotency_key)
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_newsletters_with_token(
+        &self,
+        token: &str,
+        body: serde_json::Value,
+    ) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/newsletters", &self.address))
+            .header("Content-Type", "application/json")
+            .bearer_auth(token)
+            .body(body.to_string())
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_issue(&self, issue_id: Uuid) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/issues/{}", &self.address, issue_id))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_issue_html(&self, issue_id: Uuid) -> String {
+        self.get_issue(issue_id).await.text().await.unwrap()
+    }
+
+    pub async fn get_issues_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/admin/issues", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn post_requeue_failed_deliveries(&self, issue_id: Uuid) -> reqwest::Response {
+        self.api_client
+            .post(format!(
+                "{}/admin/issues/{}/requeue",
+                &self.address, issue_id
+            ))
+            .header("X-CSRF-Token", &self.csrf_token)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_api_tokens(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/tokens", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_api_tokens_html(&self) -> String {
+        self.get_api_tokens().await.text().await.unwrap()
+    }
+
+    pub async fn post_api_token<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/admin/tokens", &self.address))
+            .header("X-CSRF-Token", &self.csrf_token)
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_revoke_api_token(&self, token_id: Uuid) -> reqwest::Response {
+        self.api_client
+            .post(format!(
+                "{}/admin/tokens/{}/revoke",
+                &self.address, token_id
+            ))
+            .header("X-CSRF-Token", &self.csrf_token)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// Creates a token through the admin UI, the test user has to be logged in
+    pub async fn create_api_token(&self, scopes: &[&str]) -> String {
+        let mut body = serde_json::json!({
+            "name": "test token",
+            "expires_in_days": 30,
+        });
+        for scope in scopes {
+            let field = format!("scope_{}", scope.replace(':', "_"));
+            body[field] = "on".into();
+        }
+
+        let html = self.post_api_token(&body).await.text().await.unwrap();
+        extract_api_token(&html)
+    }
+
     pub fn get_confirmation_links(&self, email_request: &wiremock::Request) -> ConfirmationLinks {
         // parse json
         let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
@@ -94,9 +464,20 @@ impl TestApplication {
         ConfirmationLinks { html, plain_text }
     }
 
+    pub async fn get_home_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .text()
+            .await
+            .unwrap()
+    }
+
     pub async fn get_login_html(&self) -> String {
         self.api_client
-            .get(&format!("{}/login", &self.address))
+            .get(format!("{}/login", &self.address))
             .send()
             .await
             .expect("Failed to execute request.")
@@ -117,7 +498,12 @@ pub async fn spawn_app() -> TestApplication {
         let mut c = get_configuration().expect("Failed to read configuration");
         c.database.database_name = Uuid::new_v4().to_string();
         c.application.port = 0;
+        c.email_client.kind = EmailClientKind::Http;
         c.email_client.base_url = email_server.uri();
+        // keep retries of failing email requests quick
+        c.email_client.retry.base_delay_milliseconds = 10;
+        // requests from the tests play the load balancer, see `post_login_from`
+        c.application.trusted_proxies = vec!["127.0.0.1".parse().unwrap()];
         c
     };
 
@@ -131,13 +517,14 @@ pub async fn spawn_app() -> TestApplication {
     let address = format!("http://127.0.0.1:{}", application.port());
 
     // drop the spawned future handle
-    let _ = tokio::spawn(application.run_until_stopped());
+    tokio::spawn(application.run_until_stopped());
 
     let client = reqwest::Client::builder()
         .redirect(reqwest::redirect::Policy::none())
         .cookie_store(true)
         .build()
         .unwrap();
+    let csrf_token = get_csrf_token(&client, &address).await;
 
     let test_app = TestApplication {
         address,
@@ -146,6 +533,7 @@ pub async fn spawn_app() -> TestApplication {
         port: application_port,
         test_user: TestUser::generate(),
         api_client: client,
+        csrf_token,
     };
 
     test_app.test_user.store(&test_app.connection_pool).await;
@@ -153,6 +541,30 @@ pub async fn spawn_app() -> TestApplication {
     test_app
 }
 
+/// Visits the login page, which sets the CSRF cookie and hands out the token via `hx-headers`
+pub async fn get_csrf_token(client: &reqwest::Client, address: &str) -> String {
+    let html = client
+        .get(format!("{}/login", address))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .text()
+        .await
+        .unwrap();
+
+    extract_csrf_token(&html)
+}
+
+pub fn extract_csrf_token(html: &str) -> String {
+    let start = html
+        .find(r#"{"X-CSRF-Token": ""#)
+        .expect("No CSRF token in the page.")
+        + r#"{"X-CSRF-Token": ""#.len();
+    let end = start + html[start..].find('"').unwrap();
+
+    html[start..end].to_string()
+}
+
 async fn configure_database(config: &DatabaseSettings) -> PgPool {
     let mut connection = PgConnection::connect_with(&config.without_db())
         .await
@@ -185,12 +597,35 @@ impl TestUser {
     pub fn generate() -> Self {
         Self {
             user_id: Uuid::new_v4(),
-            username: Uuid::new_v4().to_string(),
+            // usernames are email addresses
+            username: format!("{}@example.com", Uuid::new_v4()),
             password: Uuid::new_v4().to_string(),
         }
     }
 
-    async fn store(&self, pool: &PgPool) {
+    pub async fn set_role(&self, app: &TestApplication, role: &str) {
+        sqlx::query!(
+            "UPDATE users SET role = $1 WHERE user_id = $2",
+            role,
+            self.user_id
+        )
+        .execute(&app.connection_pool)
+        .await
+        .expect("Failed to change the role of the test user.");
+    }
+
+    pub async fn login(&self, app: &TestApplication) {
+        let response = app
+            .post_login(&serde_json::json!({
+                "email": &self.username,
+                "password": &self.password,
+            }))
+            .await;
+
+        assert_eq!(response.headers().get("HX-Redirect").unwrap(), "/");
+    }
+
+    pub async fn store(&self, pool: &PgPool) {
         let salt = SaltString::generate(&mut rand::thread_rng());
 
         let password_hash = Argon2::new(
@@ -203,8 +638,8 @@ impl TestUser {
         .to_string();
 
         sqlx::query!(
-            "INSERT INTO users (user_id, username, password_hash)
-            VALUES ($1, $2, $3)",
+            "INSERT INTO users (user_id, username, password_hash, role)
+            VALUES ($1, $2, $3, 'admin')",
             self.user_id,
             self.username,
             password_hash
@@ -215,6 +650,15 @@ impl TestUser {
     }
 }
 
+/// The plain text token is only shown once, right after it was created
+pub fn extract_api_token(html: &str) -> String {
+    let start = html.find("r2p_").expect("No API token in the page.");
+    html[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+        .collect()
+}
+
 pub fn assert_is_redirect_to(response: &reqwest::Response, location: &str) {
     assert_eq!(response.status().as_u16(), 303);
 
@@ -226,3 +670,51 @@ pub fn assert_is_redirect_to(response: &reqwest::Response, location: &str) {
 
     assert_eq!(response.headers().get("Location").unwrap(), location);
 }
+
+pub async fn create_confirmed_subscriber(app: &TestApplication) {
+    create_confirmed_subscriber_with_email(app, "the_boss@gmail.com").await;
+}
+
+pub async fn create_confirmed_subscriber_with_email(app: &TestApplication, email: &str) {
+    let confirmation_links = create_unconfirmed_subscriber_with_email(app, email).await;
+
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+}
+
+// use application api to create a new subscriber
+pub async fn create_unconfirmed_subscriber(app: &TestApplication) -> ConfirmationLinks {
+    create_unconfirmed_subscriber_with_email(app, "the_boss@gmail.com").await
+}
+
+pub async fn create_unconfirmed_subscriber_with_email(
+    app: &TestApplication,
+    email: &str,
+) -> ConfirmationLinks {
+    let body = format!("name=the%20boss&email={}", urlencoding::encode(email));
+
+    let _mock_guard = Mock::given(path("/emails"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .named("Create unconfirmed subscriber")
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+
+    let sent_before = app.email_server.received_requests().await.unwrap().len();
+    app.post_subscriptions(body)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    let email_request = &app
+        .wait_for_email_requests(sent_before + 1)
+        .await
+        .pop()
+        .unwrap();
+
+    app.get_confirmation_links(email_request)
+}
@@ -0,0 +1,252 @@
+use uuid::Uuid;
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+use crate::helpers::{
+    assert_is_redirect_to, create_confirmed_subscriber, spawn_app, TestApplication,
+};
+
+struct Delivery {
+    status: String,
+    n_attempts: i32,
+    last_error: Option<String>,
+    provider_message_id: Option<String>,
+}
+
+/// Publishes an issue, waits for the worker to get through it and returns the issue id
+async fn publish_issue(app: &TestApplication) -> Uuid {
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "newsletter title",
+            "content": {
+                "text": "Newsletter content",
+                "html": "<h1>Newsletter content</h1>"
+            }
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    app.wait_for_delivery_queue_to_drain().await;
+
+    sqlx::query_scalar!("SELECT newsletter_issue_id FROM newsletter_issues")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap()
+}
+
+async fn get_delivery(app: &TestApplication, issue_id: Uuid) -> Delivery {
+    sqlx::query_as!(
+        Delivery,
+        r#"
+        SELECT status, n_attempts, last_error, provider_message_id
+        FROM deliveries
+        WHERE newsletter_issue_id = $1
+        "#,
+        issue_id
+    )
+    .fetch_one(&app.connection_pool)
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn sent_deliveries_are_recorded_with_the_provider_message_id() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/emails/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(
+            serde_json::json!({ "data": [{ "id": "49a3999c-0ce1-4ea6-ab68-afcd6dc2e794" }] }),
+        ))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // act
+    let issue_id = publish_issue(&app).await;
+
+    // assert
+    let delivery = get_delivery(&app, issue_id).await;
+    assert_eq!(delivery.status, "sent");
+    assert_eq!(delivery.n_attempts, 1);
+    assert_eq!(
+        delivery.provider_message_id.as_deref(),
+        Some("49a3999c-0ce1-4ea6-ab68-afcd6dc2e794")
+    );
+}
+
+#[tokio::test]
+async fn failed_deliveries_can_be_requeued() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.test_user.login(&app).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+    let issue_id = publish_issue(&app).await;
+
+    let delivery = get_delivery(&app, issue_id).await;
+    assert_eq!(delivery.status, "failed");
+    assert!(delivery.last_error.unwrap().contains("500"));
+
+    let html_page = app.get_issue_html(issue_id).await;
+    assert!(html_page
+        .contains(r#"id="deliveries-failed" class="text-2xl font-semibold text-gray-900">1<"#));
+    assert!(html_page.contains(r#"id="requeue-failed""#));
+
+    // the provider is back
+    app.email_server.reset().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // act
+    let response = app.post_requeue_failed_deliveries(issue_id).await;
+
+    // assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("1 recipient has been re-queued."));
+
+    app.wait_for_delivery_queue_to_drain().await;
+    let delivery = get_delivery(&app, issue_id).await;
+    assert_eq!(delivery.status, "sent");
+    assert_eq!(delivery.n_attempts, 2);
+}
+
+#[tokio::test]
+async fn rejected_deliveries_are_bounced_and_not_requeued() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.test_user.login(&app).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(422))
+        // no retries for permanent errors, not even when asked to re-queue
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let issue_id = publish_issue(&app).await;
+
+    // act
+    let response = app.post_requeue_failed_deliveries(issue_id).await;
+
+    // assert
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("0 recipients have been re-queued."));
+
+    let delivery = get_delivery(&app, issue_id).await;
+    assert_eq!(delivery.status, "bounced");
+    assert_eq!(delivery.n_attempts, 1);
+}
+
+#[tokio::test]
+async fn issues_are_listed_with_their_progress() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.test_user.login(&app).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let issue_id = publish_issue(&app).await;
+
+    // act
+    let html_page = app.get_issues_html().await;
+
+    // assert
+    assert!(html_page.contains(&format!(r#"href="/admin/issues/{}""#, issue_id)));
+    assert!(html_page.contains("1 / 1"));
+
+    let html_page = app.get_issue_html(issue_id).await;
+    assert!(html_page
+        .contains(r#"id="deliveries-sent" class="text-2xl font-semibold text-gray-900">1<"#));
+    // nothing left to do, so no more polling
+    assert!(!html_page.contains("hx-trigger"));
+}
+
+#[tokio::test]
+async fn issue_titles_are_escaped() {
+    // arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "<script>alert(1)</script>",
+            "content": {
+                "text": "Newsletter content",
+                "html": "<h1>Newsletter content</h1>"
+            }
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    let issue_id = sqlx::query_scalar!("SELECT newsletter_issue_id FROM newsletter_issues")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+
+    // act
+    let html_page = app.get_issue_html(issue_id).await;
+
+    // assert
+    assert!(!html_page.contains("<script>alert(1)</script>"));
+    assert!(html_page.contains("&lt;script&gt;alert(1)"));
+}
+
+#[tokio::test]
+async fn viewers_can_follow_issues_but_not_requeue_them() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.test_user.login(&app).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+    let issue_id = publish_issue(&app).await;
+    app.test_user.set_role(&app, "viewer").await;
+
+    // act
+    let page = app.get_issue(issue_id).await;
+    let requeue = app.post_requeue_failed_deliveries(issue_id).await;
+
+    // assert
+    assert_eq!(page.status().as_u16(), 200);
+    assert_eq!(requeue.status().as_u16(), 403);
+    assert_eq!(get_delivery(&app, issue_id).await.status, "failed");
+}
+
+#[tokio::test]
+async fn unknown_issues_are_not_found() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    let response = app.get_issue(Uuid::new_v4()).await;
+
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_see_issues() {
+    let app = spawn_app().await;
+
+    let response = app.get_issue(Uuid::new_v4()).await;
+
+    assert_is_redirect_to(&response, "/login");
+}
@@ -1,4 +1,4 @@
-use crate::helpers::spawn_app;
+use crate::helpers::{assert_is_redirect_to, spawn_app};
 
 #[tokio::test]
 async fn the_header_contains_an_error_message_on_failure() {
@@ -34,3 +34,329 @@ async fn the_header_contains_an_error_message_on_failure() {
     let html_page = app.get_login_html().await;
     assert!(!html_page.contains("Invalid credentials"));
 }
+
+#[tokio::test]
+async fn a_successful_login_establishes_a_session() {
+    // arrange
+    let app = spawn_app().await;
+
+    // act - 1
+    // the home page does not know the user before logging in
+    let html_page = app.get_home_html().await;
+    assert!(!html_page.contains(&app.test_user.username));
+
+    // act - 2
+    // login with valid credentials, htmx is told to go to the home page
+    let login_body = serde_json::json!({
+        "email": &app.test_user.username,
+        "password": &app.test_user.password,
+    });
+    let response = app.post_login(&login_body).await;
+    assert_eq!(response.headers().get("HX-Redirect").unwrap(), "/");
+
+    // act - 3
+    // the home page greets the logged in user
+    let html_page = app.get_home_html().await;
+    assert!(html_page.contains(&app.test_user.username));
+}
+
+#[tokio::test]
+async fn the_session_id_is_renewed_on_login() {
+    // arrange
+    let app = spawn_app().await;
+
+    // act
+    app.test_user.login(&app).await;
+    let sessions_after_first_login = sqlx::query!("SELECT session_key FROM sessions")
+        .fetch_all(&app.connection_pool)
+        .await
+        .unwrap();
+
+    app.test_user.login(&app).await;
+    let sessions_after_second_login = sqlx::query!("SELECT session_key FROM sessions")
+        .fetch_all(&app.connection_pool)
+        .await
+        .unwrap();
+
+    // assert
+    // the old session is gone and a new key has been handed out
+    assert_eq!(sessions_after_first_login.len(), 1);
+    assert_eq!(sessions_after_second_login.len(), 1);
+    assert_ne!(
+        sessions_after_first_login[0].session_key,
+        sessions_after_second_login[0].session_key
+    );
+}
+
+#[tokio::test]
+async fn expired_sessions_are_not_honoured() {
+    // arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    // act
+    sqlx::query!("UPDATE sessions SET expires_at = now() - interval '1 minute'")
+        .execute(&app.connection_pool)
+        .await
+        .unwrap();
+
+    // assert
+    let html_page = app.get_home_html().await;
+    assert!(!html_page.contains(&app.test_user.username));
+}
+
+#[tokio::test]
+async fn an_unexpected_error_redirects_to_the_login_page() {
+    // arrange
+    let app = spawn_app().await;
+
+    // sabotage database
+    sqlx::query!("ALTER TABLE users DROP COLUMN password_hash;")
+        .execute(&app.connection_pool)
+        .await
+        .unwrap();
+
+    // act
+    let login_body = serde_json::json!({
+        "email": &app.test_user.username,
+        "password": &app.test_user.password,
+    });
+    let response = app.post_login(&login_body).await;
+
+    // assert
+    assert_is_redirect_to(&response, "/login");
+}
+
+fn wrong_password(app: &crate::helpers::TestApplication) -> serde_json::Value {
+    serde_json::json!({
+        "email": &app.test_user.username,
+        "password": "not-the-password",
+    })
+}
+
+fn right_password(app: &crate::helpers::TestApplication) -> serde_json::Value {
+    serde_json::json!({
+        "email": &app.test_user.username,
+        "password": &app.test_user.password,
+    })
+}
+
+#[tokio::test]
+async fn repeated_failures_for_a_username_are_throttled() {
+    // arrange
+    let app = spawn_app().await;
+    for _ in 0..5 {
+        let response = app.post_login(&wrong_password(&app)).await;
+        assert_eq!(response.status().as_u16(), 418);
+    }
+
+    // act - even the right password is turned away during the lockout
+    let response = app.post_login(&right_password(&app)).await;
+
+    // assert
+    assert_eq!(response.status().as_u16(), 429);
+    assert!(response.headers().get("Retry-After").is_some());
+    let response_html = response.text().await.unwrap();
+    assert!(response_html.contains("Too many failed login attempts"));
+}
+
+#[tokio::test]
+async fn the_username_lockout_applies_to_all_addresses() {
+    let app = spawn_app().await;
+    for i in 0..5 {
+        app.post_login_from(&format!("10.0.0.{}", i), &wrong_password(&app))
+            .await;
+    }
+
+    let response = app.post_login_from("10.0.1.1", &right_password(&app)).await;
+
+    assert_eq!(response.status().as_u16(), 429);
+}
+
+#[tokio::test]
+async fn repeated_failures_from_an_address_are_throttled() {
+    // arrange - guess many different usernames from the same address
+    let app = spawn_app().await;
+    for i in 0..20 {
+        let response = app
+            .post_login_from(
+                "10.0.0.1",
+                &serde_json::json!({
+                    "email": format!("user{}@example.com", i),
+                    "password": "password",
+                }),
+            )
+            .await;
+        assert_eq!(response.status().as_u16(), 418);
+    }
+
+    // act
+    let throttled = app.post_login_from("10.0.0.1", &right_password(&app)).await;
+    let other_address = app.post_login_from("10.0.0.2", &right_password(&app)).await;
+
+    // assert
+    assert_eq!(throttled.status().as_u16(), 429);
+    assert_eq!(other_address.headers().get("HX-Redirect").unwrap(), "/");
+}
+
+#[tokio::test]
+async fn spoofed_forwarded_addresses_do_not_reset_the_address_limit() {
+    // arrange - the client makes up a new address every time, the load balancer appends the real one
+    let app = spawn_app().await;
+    for i in 0..20 {
+        app.post_login_from(
+            &format!("198.51.100.{}, 10.0.0.1", i),
+            &serde_json::json!({
+                "email": format!("user{}@example.com", i),
+                "password": "password",
+            }),
+        )
+        .await;
+    }
+
+    // act
+    let response = app
+        .post_login_from("198.51.100.99, 10.0.0.1", &right_password(&app))
+        .await;
+
+    // assert
+    assert_eq!(response.status().as_u16(), 429);
+}
+
+#[tokio::test]
+async fn the_lockout_is_lifted_after_the_backoff() {
+    // arrange
+    let app = spawn_app().await;
+    for _ in 0..5 {
+        app.post_login(&wrong_password(&app)).await;
+    }
+    assert_eq!(
+        app.post_login(&right_password(&app))
+            .await
+            .status()
+            .as_u16(),
+        429
+    );
+
+    // act - let the (one second) backoff pass
+    sqlx::query!(
+        "UPDATE failed_login_attempts SET attempted_at = attempted_at - interval '2 seconds'"
+    )
+    .execute(&app.connection_pool)
+    .await
+    .unwrap();
+    let response = app.post_login(&right_password(&app)).await;
+
+    // assert - the successful login resets the counter for the username
+    assert_eq!(response.headers().get("HX-Redirect").unwrap(), "/");
+    let failures = sqlx::query!(
+        r#"SELECT count(*) AS "count!" FROM failed_login_attempts WHERE username = $1"#,
+        app.test_user.username
+    )
+    .fetch_one(&app.connection_pool)
+    .await
+    .unwrap()
+    .count;
+    assert_eq!(failures, 0);
+}
+
+#[tokio::test]
+async fn the_backoff_grows_with_every_failure() {
+    let app = spawn_app().await;
+    for _ in 0..8 {
+        app.post_login(&wrong_password(&app)).await;
+        // skip the backoff, the attempts should still be counted
+        sqlx::query!(
+            "UPDATE failed_login_attempts SET attempted_at = attempted_at - interval '1 minute'"
+        )
+        .execute(&app.connection_pool)
+        .await
+        .unwrap();
+    }
+    app.post_login(&wrong_password(&app)).await;
+
+    let response = app.post_login(&right_password(&app)).await;
+
+    assert_eq!(response.status().as_u16(), 429);
+    let retry_after: u64 = response.headers()["Retry-After"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    // 9 failures, 4 beyond the free attempts: 2^4 seconds
+    assert!(retry_after > 8 && retry_after <= 16);
+}
+
+async fn store_password_hash_with_cost(app: &crate::helpers::TestApplication, m: u32, t: u32) {
+    use argon2::password_hash::SaltString;
+    use argon2::{Argon2, PasswordHasher};
+
+    let password_hash = Argon2::new(
+        argon2::Algorithm::Argon2id,
+        argon2::Version::V0x13,
+        argon2::Params::new(m, t, 1, None).unwrap(),
+    )
+    .hash_password(
+        app.test_user.password.as_bytes(),
+        &SaltString::generate(&mut rand::thread_rng()),
+    )
+    .unwrap()
+    .to_string();
+
+    sqlx::query!(
+        "UPDATE users SET password_hash = $1 WHERE user_id = $2",
+        password_hash,
+        app.test_user.user_id
+    )
+    .execute(&app.connection_pool)
+    .await
+    .unwrap();
+}
+
+async fn stored_password_hash(app: &crate::helpers::TestApplication) -> String {
+    sqlx::query!(
+        "SELECT password_hash FROM users WHERE user_id = $1",
+        app.test_user.user_id
+    )
+    .fetch_one(&app.connection_pool)
+    .await
+    .unwrap()
+    .password_hash
+}
+
+#[tokio::test]
+async fn outdated_password_hashes_are_upgraded_on_login() {
+    // arrange - a hash from the days of a lower cost
+    let app = spawn_app().await;
+    store_password_hash_with_cost(&app, 8192, 1).await;
+
+    // act
+    app.test_user.login(&app).await;
+
+    // assert - the upgrade happens in the background
+    let mut password_hash = stored_password_hash(&app).await;
+    for _ in 0..50 {
+        if password_hash.contains("m=15000,t=2,p=1") {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        password_hash = stored_password_hash(&app).await;
+    }
+    assert!(password_hash.contains("m=15000,t=2,p=1"));
+
+    // the upgraded hash still matches the password
+    app.post_logout().await;
+    app.test_user.login(&app).await;
+}
+
+#[tokio::test]
+async fn failed_logins_leave_outdated_hashes_alone() {
+    let app = spawn_app().await;
+    store_password_hash_with_cost(&app, 8192, 1).await;
+    let outdated_hash = stored_password_hash(&app).await;
+
+    app.post_login(&wrong_password(&app)).await;
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    assert_eq!(stored_password_hash(&app).await, outdated_hash);
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+
+use actix_session::storage::SessionStore;
+use actix_web::cookie::time::Duration;
+use rust2prod::session_store::{revoke_user_sessions, PostgresSessionStore};
+
+use crate::helpers::{assert_is_redirect_to, get_csrf_token, spawn_app};
+
+#[tokio::test]
+async fn logout_clears_the_session() {
+    // arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    let html_page = app.get_home_html().await;
+    assert!(html_page.contains(&app.test_user.username));
+
+    // act - 1
+    // logout redirects to the login page
+    let response = app.post_logout().await;
+    assert_is_redirect_to(&response, "/login");
+
+    // act - 2
+    // the login page shows the flash message
+    let html_page = app.get_login_html().await;
+    assert!(html_page.contains("You have successfully logged out."));
+
+    // act - 3
+    // the home page does not know the user anymore
+    let html_page = app.get_home_html().await;
+    assert!(!html_page.contains(&app.test_user.username));
+
+    let sessions = sqlx::query!("SELECT session_key FROM sessions")
+        .fetch_all(&app.connection_pool)
+        .await
+        .unwrap();
+    assert!(sessions.is_empty());
+}
+
+#[tokio::test]
+async fn logout_everywhere_revokes_sessions_on_other_devices() {
+    // arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    // log in from a second "device" with its own cookie jar
+    let other_client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .cookie_store(true)
+        .build()
+        .unwrap();
+    let other_csrf_token = get_csrf_token(&other_client, &app.address).await;
+
+    other_client
+        .post(format!("{}/login", &app.address))
+        .header("X-CSRF-Token", &other_csrf_token)
+        .form(&serde_json::json!({
+            "email": &app.test_user.username,
+            "password": &app.test_user.password,
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    let get_other_home_html = || async {
+        other_client
+            .get(format!("{}/", &app.address))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap()
+    };
+
+    assert!(get_other_home_html()
+        .await
+        .contains(&app.test_user.username));
+
+    // act
+    let response = app.post_logout_everywhere().await;
+    assert_is_redirect_to(&response, "/login");
+
+    // assert
+    let html_page = app.get_login_html().await;
+    assert!(html_page.contains("You have been logged out on all devices."));
+
+    assert!(!app.get_home_html().await.contains(&app.test_user.username));
+    assert!(!get_other_home_html()
+        .await
+        .contains(&app.test_user.username));
+}
+
+#[tokio::test]
+async fn a_revoked_session_is_not_brought_back_by_a_late_update() {
+    // arrange - a request loaded the session just before it was revoked
+    let app = spawn_app().await;
+    let store = PostgresSessionStore::new(app.connection_pool.clone());
+    let state = HashMap::from([(
+        "user_id".to_string(),
+        serde_json::to_string(&app.test_user.user_id).unwrap(),
+    )]);
+    let ttl = Duration::days(1);
+    let session_key = store.save(state.clone(), &ttl).await.unwrap();
+    revoke_user_sessions(&app.connection_pool, app.test_user.user_id)
+        .await
+        .unwrap();
+
+    // act
+    let new_key = store.update(session_key, state, &ttl).await.unwrap();
+
+    // assert
+    let restored = store.load(&new_key).await.unwrap().unwrap();
+    assert!(restored.is_empty());
+    let n_user_sessions = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM sessions WHERE user_id = $1"#,
+        app.test_user.user_id
+    )
+    .fetch_one(&app.connection_pool)
+    .await
+    .unwrap();
+    assert_eq!(n_user_sessions, 0);
+}
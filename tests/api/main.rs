@@ -1,6 +1,18 @@
+mod admin_dashboard;
+mod api_tokens;
+mod authorization;
+mod change_password;
+mod cli;
+mod csrf;
 mod health_check;
 mod helpers;
+mod issues;
 mod login;
+mod logout;
 mod newsletters;
+mod password_reset;
 mod subscriptions;
 mod subscriptions_confirm;
+mod subscriptions_preferences;
+mod subscriptions_unsubscribe;
+mod two_factor;
@@ -1,10 +1,46 @@
 use uuid::Uuid;
 use wiremock::{
     matchers::{any, method, path},
-    Mock, ResponseTemplate,
+    Mock, Request, Respond, ResponseTemplate,
 };
 
-use crate::helpers::{spawn_app, ConfirmationLinks, TestApplication};
+use crate::helpers::{
+    create_confirmed_subscriber, create_confirmed_subscriber_with_email,
+    create_unconfirmed_subscriber, spawn_app, TestUser,
+};
+
+/// Answers a batch request like the provider does with permissive validation,
+/// rejecting the given recipient and accepting everybody else
+struct RejectingBatchResponder(&'static str);
+
+impl Respond for RejectingBatchResponder {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let emails: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+        let mut data = Vec::new();
+        let mut errors = Vec::new();
+        for (index, email) in emails.iter().enumerate() {
+            if email["to"] == self.0 {
+                errors
+                    .push(serde_json::json!({ "index": index, "message": "Invalid `to` field." }));
+            } else {
+                data.push(serde_json::json!({ "id": Uuid::new_v4() }));
+            }
+        }
+
+        ResponseTemplate::new(200)
+            .set_body_json(serde_json::json!({ "data": data, "errors": errors }))
+    }
+}
+
+fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "newsletter title",
+        "content": {
+            "text": "Newsletter content",
+            "html": "<h1>Newsletter content</h1>"
+        }
+    })
+}
 
 #[tokio::test]
 async fn non_existing_user_is_rejected() {
@@ -14,7 +50,7 @@ async fn non_existing_user_is_rejected() {
     let password = Uuid::new_v4().to_string();
 
     let response = reqwest::Client::new()
-        .post(&format!("{}/newsletters", &app.address))
+        .post(format!("{}/newsletters", &app.address))
         .basic_auth(&username, Some(&password))
         .header("Content-Type", "application/json")
         .json(&serde_json::json!({
@@ -45,7 +81,7 @@ async fn invalid_password_is_rejected() {
     assert_ne!(test_user.password, password);
 
     let response = reqwest::Client::new()
-        .post(&format!("{}/newsletters", &app.address))
+        .post(format!("{}/newsletters", &app.address))
         .basic_auth(&test_user.username, Some(&password))
         .header("Content-Type", "application/json")
         .json(&serde_json::json!({
@@ -90,7 +126,8 @@ async fn newsletters_are_not_delivered_to_unconfirmed_subscribers() {
     let response = app.post_newsletters(newsletter_request_body).await;
 
     // assert
-    assert_eq!(200, response.status().as_u16());
+    assert_eq!(202, response.status().as_u16());
+    app.wait_for_delivery_queue_to_drain().await;
 
     // mock verification on drop
 }
@@ -100,7 +137,7 @@ async fn newsletters_are_delivered_to_confirmed_subscribers() {
     let app = spawn_app().await;
     create_confirmed_subscriber(&app).await;
 
-    Mock::given(path("/emails"))
+    Mock::given(path("/emails/batch"))
         .and(method("POST"))
         .respond_with(ResponseTemplate::new(200))
         .expect(1)
@@ -118,7 +155,8 @@ async fn newsletters_are_delivered_to_confirmed_subscribers() {
 
     let response = app.post_newsletters(newsletter_request_body).await;
 
-    assert_eq!(200, response.status().as_u16());
+    assert_eq!(202, response.status().as_u16());
+    app.wait_for_delivery_queue_to_drain().await;
 
     // remember mock is asserted on drop (that the request POST /emails was made successfully -> email has been sent)
 }
@@ -151,6 +189,21 @@ async fn newsletters_return_400_for_invalid_data() {
     }
 }
 
+#[tokio::test]
+async fn publishing_under_an_unknown_topic_is_rejected() {
+    // arrange
+    let app = spawn_app().await;
+    let mut body = newsletter_request_body();
+    body["topic"] = "unknown".into();
+
+    // act
+    let response = app.post_newsletters(body).await;
+
+    // assert
+    assert_eq!(response.status().as_u16(), 400);
+    assert_eq!(response.text().await.unwrap(), "There is no topic unknown.");
+}
+
 #[tokio::test]
 async fn request_missing_authorization_are_rejected() {
     let app = spawn_app().await;
@@ -164,7 +217,7 @@ async fn request_missing_authorization_are_rejected() {
     });
 
     let response = reqwest::Client::new()
-        .post(&format!("{}/newsletters", &app.address))
+        .post(format!("{}/newsletters", &app.address))
         .header("Content-Type", "application/json")
         .body(newsletter_request_body.to_string())
         .send()
@@ -178,40 +231,318 @@ async fn request_missing_authorization_are_rejected() {
     );
 }
 
-async fn create_confirmed_subscriber(app: &TestApplication) {
-    let confirmation_links = create_unconfirmed_subscriber(app).await;
+#[tokio::test]
+async fn repeated_basic_auth_failures_are_throttled() {
+    // arrange
+    let app = spawn_app().await;
+    let publish = |password: String| {
+        reqwest::Client::new()
+            .post(format!("{}/newsletters", &app.address))
+            .basic_auth(&app.test_user.username, Some(password))
+            .json(&serde_json::json!({
+                "title": "newsletter title",
+                "content": {
+                    "text": "Newsletter content",
+                    "html": "<h1>Newsletter content</h1>"
+                }
+            }))
+            .send()
+    };
+    for _ in 0..5 {
+        let response = publish(Uuid::new_v4().to_string()).await.unwrap();
+        assert_eq!(response.status().as_u16(), 401);
+    }
+
+    // act
+    let response = publish(app.test_user.password.clone()).await.unwrap();
+
+    // assert
+    assert_eq!(response.status().as_u16(), 429);
+    assert!(response.headers().get("Retry-After").is_some());
+}
+
+#[tokio::test]
+async fn publishing_stores_the_issue_and_returns_before_delivery() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // the email provider is down, which no longer concerns the publisher
+    Mock::given(path("/emails/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+
+    // act
+    let response = app.post_newsletters(newsletter_request_body()).await;
+
+    // assert
+    assert_eq!(response.status().as_u16(), 202);
 
-    reqwest::get(confirmation_links.html)
+    let issue = sqlx::query!("SELECT title, published_by FROM newsletter_issues")
+        .fetch_one(&app.connection_pool)
         .await
-        .unwrap()
-        .error_for_status()
         .unwrap();
+    assert_eq!(issue.title, "newsletter title");
+    assert_eq!(issue.published_by, app.test_user.user_id);
+
+    app.wait_for_delivery_queue_to_drain().await;
 }
 
-// use application api to create a new subscriber
-async fn create_unconfirmed_subscriber(app: &TestApplication) -> ConfirmationLinks {
-    let body = "name=the%20boss&email=the_boss%40gmail.com";
+#[tokio::test]
+async fn a_failed_delivery_does_not_stop_the_others() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber_with_email(&app, "failing@example.com").await;
+    create_confirmed_subscriber_with_email(&app, "working@example.com").await;
 
-    let _mock_guard = Mock::given(path("/emails"))
-        .and(method("POST"))
-        .respond_with(ResponseTemplate::new(200))
-        .named("Create unconfirmed subscriber")
+    Mock::given(path("/emails/batch"))
+        .respond_with(RejectingBatchResponder("failing@example.com"))
+        // both go out in the same batch
         .expect(1)
-        .mount_as_scoped(&app.email_server)
+        .mount(&app.email_server)
         .await;
 
-    app.post_subscriptions(body.into())
+    // act
+    let response = app.post_newsletters(newsletter_request_body()).await;
+
+    // assert
+    assert_eq!(response.status().as_u16(), 202);
+    app.wait_for_delivery_queue_to_drain().await;
+
+    let deliveries = sqlx::query!("SELECT subscriber_email, status FROM deliveries ORDER BY 1")
+        .fetch_all(&app.connection_pool)
         .await
-        .error_for_status()
-        .unwrap();
+        .unwrap()
+        .into_iter()
+        .map(|delivery| (delivery.subscriber_email, delivery.status))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        deliveries,
+        vec![
+            ("failing@example.com".to_string(), "bounced".to_string()),
+            ("working@example.com".to_string(), "sent".to_string()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn large_lists_are_delivered_in_batches_of_100() {
+    // arrange
+    let app = spawn_app().await;
+    // going through the sign-up flow 150 times would only slow the test down
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+        SELECT gen_random_uuid(), 'subscriber-' || i || '@example.com', 'subscriber', now(), 'confirmed'
+        FROM generate_series(1, 150) AS i
+        "#
+    )
+    .execute(&app.connection_pool)
+    .await
+    .unwrap();
+
+    Mock::given(path("/emails/batch"))
+        .respond_with(RejectingBatchResponder("nobody"))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    // act
+    let response = app.post_newsletters(newsletter_request_body()).await;
 
-    let email_request = &app
+    // assert
+    assert_eq!(response.status().as_u16(), 202);
+    app.wait_for_delivery_queue_to_drain().await;
+
+    let n_sent =
+        sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM deliveries WHERE status = 'sent'"#)
+            .fetch_one(&app.connection_pool)
+            .await
+            .unwrap();
+    assert_eq!(n_sent, 150);
+    // mock verification on drop
+}
+
+#[tokio::test]
+async fn publishing_to_a_large_list_enqueues_every_confirmed_subscriber() {
+    // arrange - a few hundred are enough to need several batches
+    let app = spawn_app().await;
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+        SELECT
+            gen_random_uuid(),
+            'subscriber-' || i || '@example.com',
+            'subscriber',
+            now(),
+            CASE WHEN i % 10 = 0 THEN 'pending_confirmation' ELSE 'confirmed' END
+        FROM generate_series(1, 350) AS i
+        "#
+    )
+    .execute(&app.connection_pool)
+    .await
+    .unwrap();
+
+    Mock::given(path("/emails/batch"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    // act
+    let response = app.post_newsletters(newsletter_request_body()).await;
+
+    // assert - every confirmed subscriber got the issue, at most 100 per batch
+    assert_eq!(response.status().as_u16(), 202);
+    app.wait_for_delivery_queue_to_drain().await;
+
+    let batches: Vec<serde_json::Value> = app
         .email_server
         .received_requests()
         .await
         .unwrap()
-        .pop()
+        .iter()
+        .map(|request| serde_json::from_slice(&request.body).unwrap())
+        .collect();
+    assert!(batches.len() >= 4);
+    let mut recipients = Vec::new();
+    for batch in &batches {
+        let batch = batch.as_array().unwrap();
+        assert!(batch.len() <= 100, "a batch of {} emails", batch.len());
+        recipients.extend(batch.iter().map(|email| email["to"].clone()));
+    }
+    assert_eq!(recipients.len(), 315);
+    assert!(!recipients.contains(&serde_json::json!("subscriber-10@example.com")));
+}
+
+#[tokio::test]
+async fn newsletter_creation_is_idempotent() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/emails/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let idempotency_key = Uuid::new_v4().to_string();
+
+    // act - submit the same issue twice
+    let first_response = app
+        .post_newsletters_with_idempotency_key(&idempotency_key, newsletter_request_body())
+        .await;
+    let second_response = app
+        .post_newsletters_with_idempotency_key(&idempotency_key, newsletter_request_body())
+        .await;
+
+    // assert - same answer, but only one issue
+    assert_eq!(first_response.status().as_u16(), 202);
+    assert_eq!(second_response.status().as_u16(), 202);
+
+    let n_issues = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM newsletter_issues"#)
+        .fetch_one(&app.connection_pool)
+        .await
         .unwrap();
+    assert_eq!(n_issues, 1);
+
+    app.wait_for_delivery_queue_to_drain().await;
+    // mock verification on drop
+}
+
+#[tokio::test]
+async fn concurrent_submissions_with_the_same_key_are_handled_gracefully() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/emails/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let idempotency_key = Uuid::new_v4().to_string();
+
+    // act - the second request arrives while the first one is still running
+    let (first_response, second_response) = tokio::join!(
+        app.post_newsletters_with_idempotency_key(&idempotency_key, newsletter_request_body()),
+        app.post_newsletters_with_idempotency_key(&idempotency_key, newsletter_request_body()),
+    );
 
-    app.get_confirmation_links(email_request)
+    // assert
+    assert_eq!(first_response.status(), second_response.status());
+    assert_eq!(
+        first_response.text().await.unwrap(),
+        second_response.text().await.unwrap()
+    );
+
+    app.wait_for_delivery_queue_to_drain().await;
+    // mock verification on drop
+}
+
+#[tokio::test]
+async fn idempotency_keys_are_scoped_per_user() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let other_user = TestUser::generate();
+    other_user.store(&app.connection_pool).await;
+
+    Mock::given(path("/emails/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+    let idempotency_key = Uuid::new_v4().to_string();
+
+    // act - both users happen to use the same key
+    let response = app
+        .post_newsletters_with_idempotency_key(&idempotency_key, newsletter_request_body())
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+
+    let response = app
+        .api_client
+        .post(format!("{}/newsletters", &app.address))
+        .header("Idempotency-Key", &idempotency_key)
+        .basic_auth(&other_user.username, Some(&other_user.password))
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .unwrap();
+
+    // assert - two independent issues
+    assert_eq!(response.status().as_u16(), 202);
+
+    app.wait_for_delivery_queue_to_drain().await;
+    // mock verification on drop
+}
+
+#[tokio::test]
+async fn invalid_idempotency_keys_are_rejected() {
+    // arrange
+    let app = spawn_app().await;
+    let test_cases = vec![
+        ("".to_string(), "empty key"),
+        ("a".repeat(51), "key too long"),
+    ];
+
+    for (idempotency_key, description) in test_cases {
+        // act
+        let response = app
+            .post_newsletters_with_idempotency_key(&idempotency_key, newsletter_request_body())
+            .await;
+
+        // assert
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "The API did not reject the request with an {}.",
+            description
+        );
+    }
 }
@@ -0,0 +1,230 @@
+use uuid::Uuid;
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+use crate::helpers::{assert_is_redirect_to, spawn_app, TestApplication};
+
+const STRONG_PASSWORD: &str = "correct-Horse-battery-staple";
+
+async fn request_reset_link(app: &TestApplication) -> reqwest::Url {
+    Mock::given(path("/emails"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_password_reset(&app.test_user.username).await;
+    assert_is_redirect_to(&response, "/login");
+
+    let email_request = &app.wait_for_email_requests(1).await[0];
+    app.get_confirmation_links(email_request).html
+}
+
+fn reset_token(reset_link: &reqwest::Url) -> String {
+    reset_link
+        .query_pairs()
+        .find(|(key, _)| key == "token")
+        .unwrap()
+        .1
+        .into_owned()
+}
+
+#[tokio::test]
+async fn unknown_accounts_get_the_same_response_but_no_email() {
+    // arrange
+    let app = spawn_app().await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // act - 1
+    let response = app
+        .post_password_reset(&format!("{}@example.com", Uuid::new_v4()))
+        .await;
+    assert_is_redirect_to(&response, "/login");
+
+    // act - 2
+    let html_page = app.get_login_html().await;
+    assert!(html_page.contains("If an account exists for that address"));
+}
+
+#[tokio::test]
+async fn known_accounts_get_a_reset_link() {
+    // arrange
+    let app = spawn_app().await;
+
+    // act - 1
+    let reset_link = request_reset_link(&app).await;
+
+    // act - 2
+    let html_page = app.get_login_html().await;
+    assert!(html_page.contains("If an account exists for that address"));
+
+    // act - 3
+    let response = app.api_client.get(reset_link).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("Choose a new password"));
+}
+
+#[tokio::test]
+async fn reset_tokens_are_stored_hashed() {
+    // arrange
+    let app = spawn_app().await;
+
+    // act
+    let token = reset_token(&request_reset_link(&app).await);
+
+    // assert
+    let saved = sqlx::query!("SELECT token_hash FROM password_reset_tokens")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert_ne!(saved.token_hash, token);
+}
+
+#[tokio::test]
+async fn a_reset_link_lets_the_user_choose_a_new_password_once() {
+    // arrange
+    let app = spawn_app().await;
+    let token = reset_token(&request_reset_link(&app).await);
+    let body = serde_json::json!({
+        "token": &token,
+        "new_password": STRONG_PASSWORD,
+        "new_password_check": STRONG_PASSWORD,
+    });
+
+    // act - 1
+    let response = app.post_password_reset_confirm(&body).await;
+    assert_is_redirect_to(&response, "/login");
+
+    let html_page = app.get_login_html().await;
+    assert!(html_page.contains("Your password has been reset"));
+
+    // act - 2
+    // the new password works
+    let response = app
+        .post_login(&serde_json::json!({
+            "email": &app.test_user.username,
+            "password": STRONG_PASSWORD,
+        }))
+        .await;
+    assert_eq!(response.headers().get("HX-Redirect").unwrap(), "/");
+
+    // act - 3
+    // the token cannot be replayed
+    let response = app.post_password_reset_confirm(&body).await;
+    assert_is_redirect_to(&response, "/password-reset");
+}
+
+#[tokio::test]
+async fn a_failed_reset_leaves_the_password_and_the_link_as_they_were() {
+    // arrange
+    let app = spawn_app().await;
+    let token = reset_token(&request_reset_link(&app).await);
+    let body = serde_json::json!({
+        "token": &token,
+        "new_password": STRONG_PASSWORD,
+        "new_password_check": STRONG_PASSWORD,
+    });
+    // sabotage the last step, ending the sessions
+    sqlx::query!("ALTER TABLE sessions RENAME TO sessions_gone")
+        .execute(&app.connection_pool)
+        .await
+        .unwrap();
+
+    // act - 1
+    let response = app.post_password_reset_confirm(&body).await;
+    assert_eq!(response.status().as_u16(), 500);
+
+    // assert - 1
+    sqlx::query!("ALTER TABLE sessions_gone RENAME TO sessions")
+        .execute(&app.connection_pool)
+        .await
+        .unwrap();
+    let response = app
+        .post_login(&serde_json::json!({
+            "email": &app.test_user.username,
+            "password": &app.test_user.password,
+        }))
+        .await;
+    assert_eq!(response.headers().get("HX-Redirect").unwrap(), "/");
+
+    // act - 2
+    let response = app.post_password_reset_confirm(&body).await;
+
+    // assert - 2
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn a_weak_new_password_does_not_consume_the_token() {
+    // arrange
+    let app = spawn_app().await;
+    let token = reset_token(&request_reset_link(&app).await);
+
+    // act - 1
+    let response = app
+        .post_password_reset_confirm(&serde_json::json!({
+            "token": &token,
+            "new_password": "short",
+            "new_password_check": "short",
+        }))
+        .await;
+    assert_is_redirect_to(
+        &response,
+        &format!("/password-reset/confirm?token={}", token),
+    );
+
+    // act - 2
+    let response = app
+        .post_password_reset_confirm(&serde_json::json!({
+            "token": &token,
+            "new_password": STRONG_PASSWORD,
+            "new_password_check": STRONG_PASSWORD,
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn expired_reset_links_are_rejected() {
+    // arrange
+    let app = spawn_app().await;
+    let reset_link = request_reset_link(&app).await;
+
+    sqlx::query!("UPDATE password_reset_tokens SET expires_at = now() - interval '1 minute'")
+        .execute(&app.connection_pool)
+        .await
+        .unwrap();
+
+    // act - 1
+    let response = app.api_client.get(reset_link.clone()).send().await.unwrap();
+    assert_is_redirect_to(&response, "/password-reset");
+
+    // act - 2
+    let response = app
+        .post_password_reset_confirm(&serde_json::json!({
+            "token": reset_token(&reset_link),
+            "new_password": STRONG_PASSWORD,
+            "new_password_check": STRONG_PASSWORD,
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/password-reset");
+
+    // the old password still works
+    let response = app
+        .post_login(&serde_json::json!({
+            "email": &app.test_user.username,
+            "password": &app.test_user.password,
+        }))
+        .await;
+    assert_eq!(response.headers().get("HX-Redirect").unwrap(), "/");
+}
@@ -1,4 +1,4 @@
-use crate::helpers::spawn_app;
+use crate::helpers::{create_confirmed_subscriber, spawn_app};
 use wiremock::matchers::{method, path};
 use wiremock::{Mock, ResponseTemplate};
 
@@ -20,6 +20,7 @@ async fn subscribe_returns_200_successful_for_valid_data() {
 
     // assert
     assert_eq!(200, response.status().as_u16());
+    app.wait_for_email_requests(1).await;
 }
 
 #[tokio::test]
@@ -113,7 +114,9 @@ async fn subscribe_sends_confirmation_email_for_valid_data() {
 
     // act
     app.post_subscriptions(body.into()).await;
-    // assert on drop
+
+    // assert - the mock checks the count on drop, the email goes out in the background
+    app.wait_for_email_requests(1).await;
 }
 
 #[tokio::test]
@@ -134,7 +137,7 @@ async fn subscribe_sends_a_confirmation_email_with_a_link() {
     // assert
 
     // get first intercepted request
-    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let email_request = &app.wait_for_email_requests(1).await[0];
 
     let confirmation_links = app.get_confirmation_links(email_request);
 
@@ -158,3 +161,175 @@ async fn subscribe_fails_if_there_is_a_database_error() {
     // assert
     assert_eq!(500, response.status().as_u16());
 }
+
+#[tokio::test]
+async fn subscribe_does_not_wait_for_the_confirmation_email() {
+    // arrange
+    let app = spawn_app().await;
+    let body = "name=the%20boss&email=the_boss%40gmail.com";
+
+    // slow enough to stand out against the response time of a confirmed address
+    Mock::given(path("/emails"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500).set_delay(std::time::Duration::from_secs(3)))
+        .mount(&app.email_server)
+        .await;
+
+    // act
+    let started = std::time::Instant::now();
+    let response = app.post_subscriptions(body.into()).await;
+
+    // assert - neither the delay nor the failure shows
+    assert_eq!(200, response.status().as_u16());
+    assert!(started.elapsed() < std::time::Duration::from_secs(2));
+}
+
+#[tokio::test]
+async fn subscribing_twice_while_pending_resends_the_confirmation() {
+    // arrange
+    let app = spawn_app().await;
+    let body = "name=the%20boss&email=the_boss%40gmail.com";
+
+    Mock::given(path("/emails"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    // act
+    let first_response = app.post_subscriptions(body.into()).await;
+    let second_response = app.post_subscriptions(body.into()).await;
+
+    // assert
+    assert_eq!(200, first_response.status().as_u16());
+    assert_eq!(200, second_response.status().as_u16());
+
+    // only hashes are stored, so the resent link is a new one and the first keeps working
+    let email_requests = app.wait_for_email_requests(2).await;
+    let first_links = app.get_confirmation_links(&email_requests[0]);
+    let second_links = app.get_confirmation_links(&email_requests[1]);
+    assert_ne!(first_links.html, second_links.html);
+    reqwest::get(first_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let n_subscriptions = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM subscriptions"#)
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(n_subscriptions, 1);
+}
+
+#[tokio::test]
+async fn subscribing_again_when_confirmed_sends_no_email() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let body = "name=the%20boss&email=the_boss%40gmail.com";
+
+    Mock::given(path("/emails"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // act
+    let response = app.post_subscriptions(body.into()).await;
+
+    // assert - the same answer as for a new subscriber
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn unsubscribed_subscribers_can_subscribe_again_through_double_opt_in() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed'")
+        .execute(&app.connection_pool)
+        .await
+        .unwrap();
+    let body = "name=the%20boss&email=the_boss%40gmail.com";
+    let sent_before = app.email_server.received_requests().await.unwrap().len();
+
+    Mock::given(path("/emails"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // act - part 1: subscribe
+    let response = app.post_subscriptions(body.into()).await;
+
+    // assert - part 1: pending until the new link is followed
+    assert_eq!(200, response.status().as_u16());
+    let status = sqlx::query_scalar!("SELECT status FROM subscriptions")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(status, "pending_confirmation");
+
+    // act - part 2: confirm
+    let email_request = app
+        .wait_for_email_requests(sent_before + 1)
+        .await
+        .pop()
+        .unwrap();
+    let confirmation_links = app.get_confirmation_links(&email_request);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // assert - part 2
+    let status = sqlx::query_scalar!("SELECT status FROM subscriptions")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(status, "confirmed");
+}
+
+#[tokio::test]
+async fn subscribing_again_after_the_link_expired_sends_a_new_link() {
+    // arrange
+    let app = spawn_app().await;
+    let body = "name=the%20boss&email=the_boss%40gmail.com";
+
+    Mock::given(path("/emails"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    sqlx::query!("UPDATE subscription_tokens SET expires_at = now() - interval '1 minute'")
+        .execute(&app.connection_pool)
+        .await
+        .unwrap();
+
+    // act
+    app.post_subscriptions(body.into()).await;
+
+    // assert
+    let email_requests = app.wait_for_email_requests(2).await;
+    let expired_links = app.get_confirmation_links(&email_requests[0]);
+    let new_links = app.get_confirmation_links(&email_requests[1]);
+    assert_ne!(expired_links.html, new_links.html);
+
+    let n_tokens = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM subscription_tokens"#)
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(n_tokens, 1);
+}
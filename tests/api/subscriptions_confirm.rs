@@ -1,9 +1,16 @@
+use rust2prod::configuration::get_configuration;
+use rust2prod::routes::hash_legacy_subscription_tokens;
+use rust2prod::startup::Application;
+use uuid::Uuid;
 use wiremock::{
-    matchers::{method, path},
+    matchers::{any, method, path},
     Mock, ResponseTemplate,
 };
 
-use crate::helpers::spawn_app;
+use crate::helpers::{
+    assert_is_redirect_to, create_confirmed_subscriber_with_email, create_unconfirmed_subscriber,
+    spawn_app, TestApplication,
+};
 
 #[tokio::test]
 async fn confirmation_without_token_is_rejected_with_400() {
@@ -30,7 +37,7 @@ async fn the_link_returned_by_subscribe_returns_200_if_called() {
     app.post_subscriptions(body.into()).await;
 
     // get first intercepted request
-    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let email_request = &app.wait_for_email_requests(1).await[0];
     let confirmation_links = app.get_confirmation_links(email_request);
 
     // act
@@ -52,7 +59,7 @@ async fn clicking_on_the_confirmation_link_confirms_subscriber() {
         .await;
 
     app.post_subscriptions(body.into()).await;
-    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let email_request = &app.wait_for_email_requests(1).await[0];
     let confirmation_links = app.get_confirmation_links(email_request);
 
     // act
@@ -72,3 +79,237 @@ async fn clicking_on_the_confirmation_link_confirms_subscriber() {
     assert_eq!(saved.name, "the boss");
     assert_eq!(saved.status, "confirmed");
 }
+
+#[tokio::test]
+async fn subscription_tokens_are_stored_hashed() {
+    // arrange
+    let app = spawn_app().await;
+
+    // act
+    let confirmation_links = create_unconfirmed_subscriber(&app).await;
+
+    // assert
+    let token = confirmation_links
+        .html
+        .query_pairs()
+        .find(|(key, _)| key == "subscription_token")
+        .map(|(_, value)| value.into_owned())
+        .unwrap();
+    let saved = sqlx::query!("SELECT subscription_token, token_hash FROM subscription_tokens")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.subscription_token, None);
+    assert_ne!(saved.token_hash.unwrap(), token);
+}
+
+#[tokio::test]
+async fn links_sent_before_tokens_were_hashed_keep_working_after_migrating() {
+    // arrange
+    let app = spawn_app().await;
+    insert_legacy_token(&app).await;
+    let secret = get_configuration().unwrap().application.hmac_secret;
+
+    // act
+    let hashed = hash_legacy_subscription_tokens(&app.connection_pool, &secret)
+        .await
+        .unwrap();
+
+    // assert
+    assert_eq!(hashed, 1);
+    let response = reqwest::get(format!(
+        "{}/subscriptions/confirm?subscription_token=legacytoken",
+        app.address
+    ))
+    .await
+    .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscription_status(&app).await, "confirmed");
+}
+
+#[tokio::test]
+async fn links_sent_before_tokens_were_hashed_keep_working_after_a_restart() {
+    // arrange - migrated with `sqlx migrate run`, nothing hashed the token yet
+    let app = spawn_app().await;
+    insert_legacy_token(&app).await;
+    let configuration = {
+        let mut c = get_configuration().unwrap();
+        c.database.database_name = sqlx::query_scalar!(r#"SELECT current_database() AS "name!""#)
+            .fetch_one(&app.connection_pool)
+            .await
+            .unwrap();
+        c.application.port = 0;
+        c
+    };
+
+    // act
+    Application::build(configuration).await.unwrap();
+
+    // assert
+    let response = reqwest::get(format!(
+        "{}/subscriptions/confirm?subscription_token=legacytoken",
+        app.address
+    ))
+    .await
+    .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscription_status(&app).await, "confirmed");
+}
+
+/// A pending subscriber with a token stored the way it was before tokens were hashed
+async fn insert_legacy_token(app: &TestApplication) {
+    let subscriber_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+        VALUES ($1, 'the_boss@gmail.com', 'the boss', now(), 'pending_confirmation')
+        "#,
+        subscriber_id
+    )
+    .execute(&app.connection_pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        r#"
+        INSERT INTO subscription_tokens (subscriber_id, subscription_token, created_at, expires_at)
+        VALUES ($1, 'legacytoken', now(), now() + interval '1 day')
+        "#,
+        subscriber_id
+    )
+    .execute(&app.connection_pool)
+    .await
+    .unwrap();
+}
+
+async fn expire_subscription_tokens(app: &TestApplication) {
+    sqlx::query!("UPDATE subscription_tokens SET expires_at = now() - interval '1 minute'")
+        .execute(&app.connection_pool)
+        .await
+        .unwrap();
+}
+
+async fn subscription_status(app: &TestApplication) -> String {
+    sqlx::query_scalar!("SELECT status FROM subscriptions")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn a_confirmation_link_can_only_be_used_once() {
+    // arrange
+    let app = spawn_app().await;
+    let confirmation_links = create_unconfirmed_subscriber(&app).await;
+    reqwest::get(confirmation_links.html.clone())
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // act
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+
+    // assert
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(subscription_status(&app).await, "confirmed");
+}
+
+#[tokio::test]
+async fn an_expired_link_offers_to_send_a_new_one() {
+    // arrange
+    let app = spawn_app().await;
+    let confirmation_links = create_unconfirmed_subscriber(&app).await;
+    expire_subscription_tokens(&app).await;
+
+    // act
+    let response = reqwest::get(confirmation_links.html.clone()).await.unwrap();
+
+    // assert
+    assert_eq!(response.status().as_u16(), 410);
+    let html = response.text().await.unwrap();
+    assert!(html.contains("This link has expired"));
+    assert!(html.contains(r#"id="resend-confirmation-form""#));
+    assert!(html.contains(r#"value="the_boss@gmail.com""#));
+    assert_eq!(subscription_status(&app).await, "pending_confirmation");
+
+    // the expired token isn't used up, it keeps getting the same answer
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+    assert_eq!(response.status().as_u16(), 410);
+}
+
+#[tokio::test]
+async fn resending_after_expiry_sends_a_new_link_that_works() {
+    // arrange
+    let app = spawn_app().await;
+    let expired_links = create_unconfirmed_subscriber(&app).await;
+    expire_subscription_tokens(&app).await;
+
+    Mock::given(path("/emails"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // act - part 1: resend
+    let response = app.post_resend_confirmation("the_boss@gmail.com").await;
+
+    // assert - part 1
+    assert_is_redirect_to(&response, "/subscriptions/resend-confirmation");
+    let html = app.get_resend_confirmation_html().await;
+    assert!(html.contains("If the_boss@gmail.com is waiting to be confirmed"));
+
+    let email_request = app.wait_for_email_requests(2).await.pop().unwrap();
+    let confirmation_links = app.get_confirmation_links(&email_request);
+    assert_ne!(confirmation_links.html, expired_links.html);
+
+    // act - part 2: confirm
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // assert - part 2
+    assert_eq!(subscription_status(&app).await, "confirmed");
+}
+
+#[tokio::test]
+async fn resending_is_silent_for_addresses_that_are_not_pending() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber_with_email(&app, "ursula@example.com").await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    for email in ["ursula@example.com", "le_guin@example.com"] {
+        // act
+        let response = app.post_resend_confirmation(email).await;
+
+        // assert - the same answer either way
+        assert_is_redirect_to(&response, "/subscriptions/resend-confirmation");
+        let html = app.get_resend_confirmation_html().await;
+        assert!(html.contains(&format!("If {} is waiting to be confirmed", email)));
+    }
+}
+
+#[tokio::test]
+async fn resending_requires_a_valid_email() {
+    // arrange
+    let app = spawn_app().await;
+
+    // act
+    let response = app
+        .post_resend_confirmation("definitely-not-an-email")
+        .await;
+
+    // assert
+    assert_is_redirect_to(&response, "/subscriptions/resend-confirmation");
+    let html = app.get_resend_confirmation_html().await;
+    assert!(html.contains("definitely-not-an-email"));
+    assert!(!html.contains("is waiting to be confirmed"));
+}
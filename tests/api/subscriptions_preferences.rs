@@ -0,0 +1,549 @@
+use reqwest::Url;
+use rust2prod::topics::create_topic;
+use wiremock::{
+    matchers::{any, method, path},
+    Mock, ResponseTemplate,
+};
+
+use crate::helpers::{
+    assert_is_redirect_to, create_confirmed_subscriber, create_confirmed_subscriber_with_email,
+    spawn_app, TestApplication,
+};
+
+/// The preference center link from the footer of an issue
+async fn preferences_link(app: &TestApplication) -> Url {
+    let batch = app.publish_issue().await;
+    app.get_email_link(
+        batch[0]["text"].as_str().unwrap(),
+        "/subscriptions/preferences",
+    )
+}
+
+fn token(link: &Url) -> String {
+    link.query_pairs()
+        .find(|(key, _)| key == "token")
+        .unwrap()
+        .1
+        .to_string()
+}
+
+struct Subscription {
+    name: String,
+    email: String,
+}
+
+async fn subscription(app: &TestApplication) -> Subscription {
+    sqlx::query_as!(Subscription, "SELECT name, email FROM subscriptions")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn every_issue_links_to_the_preference_center() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // act
+    let batch = app.publish_issue().await;
+
+    // assert
+    let email = &batch[0];
+    let text_link = app.get_email_link(
+        email["text"].as_str().unwrap(),
+        "/subscriptions/preferences",
+    );
+    let html_link = app.get_email_link(
+        email["html"].as_str().unwrap(),
+        "/subscriptions/preferences",
+    );
+    assert_eq!(text_link, html_link);
+}
+
+#[tokio::test]
+async fn the_preference_center_shows_the_current_details() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let link = preferences_link(&app).await;
+
+    // act
+    let response = app.api_client.get(link).send().await.unwrap();
+
+    // assert
+    assert_eq!(response.status().as_u16(), 200);
+    let html = response.text().await.unwrap();
+    assert!(html.contains(r#"id="preferences-form""#));
+    assert!(html.contains(r#"value="the boss""#));
+    assert!(html.contains(r#"value="the_boss@gmail.com""#));
+    assert!(html.contains("/subscriptions/unsubscribe?token="));
+}
+
+#[tokio::test]
+async fn a_new_name_is_saved_right_away() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let link = preferences_link(&app).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // act
+    let response = app
+        .post_preferences(&serde_json::json!({
+            "token": token(&link),
+            "name": "le guin",
+            "email": "the_boss@gmail.com",
+        }))
+        .await;
+
+    // assert
+    assert_is_redirect_to(
+        &response,
+        &format!("/subscriptions/preferences?{}", link.query().unwrap()),
+    );
+    let saved = subscription(&app).await;
+    assert_eq!(saved.name, "le guin");
+    assert_eq!(saved.email, "the_boss@gmail.com");
+
+    let html = app
+        .api_client
+        .get(link)
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(html.contains("Your preferences have been saved."));
+}
+
+#[tokio::test]
+async fn a_new_email_address_is_only_used_once_confirmed() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let link = preferences_link(&app).await;
+
+    Mock::given(path("/emails"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // act - part 1: ask for the change
+    let sent_before = app.email_server.received_requests().await.unwrap().len();
+    app.post_preferences(&serde_json::json!({
+        "token": token(&link),
+        "name": "the boss",
+        "email": "ursula@example.com",
+    }))
+    .await;
+
+    // assert - part 1: nothing changed yet, the confirmation went to the new address
+    assert_eq!(subscription(&app).await.email, "the_boss@gmail.com");
+    let email_request = app
+        .wait_for_email_requests(sent_before + 1)
+        .await
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["to"], "ursula@example.com");
+    let html = app
+        .api_client
+        .get(link)
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(html.contains("We sent a confirmation link to ursula@example.com"));
+
+    // act - part 2: follow the link
+    let confirmation_links = app.get_confirmation_links(&email_request);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // assert - part 2
+    let saved = subscription(&app).await;
+    assert_eq!(saved.email, "ursula@example.com");
+}
+
+#[tokio::test]
+async fn confirming_an_address_subscribed_in_the_meantime_is_refused() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let link = preferences_link(&app).await;
+
+    let mock_guard = Mock::given(path("/emails"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    let sent_before = app.email_server.received_requests().await.unwrap().len();
+    app.post_preferences(&serde_json::json!({
+        "token": token(&link),
+        "name": "the boss",
+        "email": "ursula@example.com",
+    }))
+    .await;
+    let email_request = app
+        .wait_for_email_requests(sent_before + 1)
+        .await
+        .pop()
+        .unwrap();
+    let confirmation_links = app.get_confirmation_links(&email_request);
+    drop(mock_guard);
+    create_confirmed_subscriber_with_email(&app, "ursula@example.com").await;
+
+    // act
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+
+    // assert
+    assert_eq!(response.status().as_u16(), 409);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("subscribed by someone else"));
+    let email =
+        sqlx::query_scalar!("SELECT email FROM subscriptions WHERE email <> 'ursula@example.com'")
+            .fetch_one(&app.connection_pool)
+            .await
+            .unwrap();
+    assert_eq!(email, "the_boss@gmail.com");
+}
+
+#[tokio::test]
+async fn queued_issues_are_skipped_for_the_old_address_after_a_change() {
+    // arrange - the provider is down, the delivery fails and waits to be re-queued
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.test_user.login(&app).await;
+    Mock::given(path("/emails/batch"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "newsletter title",
+            "content": {
+                "text": "Newsletter content",
+                "html": "<h1>Newsletter content</h1>"
+            }
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    app.wait_for_delivery_queue_to_drain().await;
+
+    // the change was confirmed in the meantime
+    sqlx::query!("UPDATE subscriptions SET email = 'ursula@example.com'")
+        .execute(&app.connection_pool)
+        .await
+        .unwrap();
+    app.email_server.reset().await;
+    Mock::given(path("/emails/batch"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    let issue_id = sqlx::query_scalar!("SELECT newsletter_issue_id FROM newsletter_issues")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+
+    // act
+    app.post_requeue_failed_deliveries(issue_id).await;
+    app.wait_for_delivery_queue_to_drain().await;
+
+    // assert - not held against the address as a bounce
+    let delivery = sqlx::query!("SELECT status, last_error FROM deliveries")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(delivery.status, "skipped");
+    assert!(delivery
+        .last_error
+        .unwrap()
+        .contains("changed their email address"));
+}
+
+#[tokio::test]
+async fn an_address_that_is_subscribed_already_gets_no_email() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber_with_email(&app, "ursula@example.com").await;
+    let link = {
+        let batch = app.publish_issue().await;
+        app.get_email_link(
+            batch[0]["text"].as_str().unwrap(),
+            "/subscriptions/preferences",
+        )
+    };
+    create_confirmed_subscriber_with_email(&app, "le_guin@example.com").await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // act
+    app.post_preferences(&serde_json::json!({
+        "token": token(&link),
+        "name": "the boss",
+        "email": "le_guin@example.com",
+    }))
+    .await;
+
+    // assert - the same answer as for an address nobody uses
+    let html = app
+        .api_client
+        .get(link)
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(html.contains("We sent a confirmation link to le_guin@example.com"));
+}
+
+#[tokio::test]
+async fn a_failing_confirmation_email_does_not_fail_the_change() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let link = preferences_link(&app).await;
+
+    Mock::given(path("/emails"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+
+    // act
+    let response = app
+        .post_preferences(&serde_json::json!({
+            "token": token(&link),
+            "name": "le guin",
+            "email": "ursula@example.com",
+        }))
+        .await;
+
+    // assert - the same answer as when the email goes through
+    assert_is_redirect_to(
+        &response,
+        &format!("/subscriptions/preferences?{}", link.query().unwrap()),
+    );
+    assert_eq!(subscription(&app).await.name, "le guin");
+    let html = app
+        .api_client
+        .get(link)
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(html.contains("We sent a confirmation link to ursula@example.com"));
+}
+
+#[tokio::test]
+async fn invalid_details_are_rejected() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let link = preferences_link(&app).await;
+    let test_cases = vec![
+        ("", "the_boss@gmail.com", "empty name"),
+        ("the (boss)", "the_boss@gmail.com", "forbidden characters"),
+        ("the boss", "definitely-not-an-email", "invalid email"),
+        ("the boss", "", "empty email"),
+    ];
+
+    for (name, email, description) in test_cases {
+        // act
+        let response = app
+            .post_preferences(&serde_json::json!({
+                "token": token(&link),
+                "name": name,
+                "email": email,
+            }))
+            .await;
+
+        // assert
+        assert_eq!(response.status().as_u16(), 303, "{}", description);
+        let html = app
+            .api_client
+            .get(link.clone())
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(html.contains("Invalid"), "{}", description);
+        let saved = subscription(&app).await;
+        assert_eq!(saved.name, "the boss", "{}", description);
+        assert_eq!(saved.email, "the_boss@gmail.com", "{}", description);
+    }
+}
+
+#[tokio::test]
+async fn tokens_that_are_not_ours_are_rejected_with_401() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    // a valid token, but signed for unsubscribing
+    let batch = app.publish_issue().await;
+    let unsubscribe_link = app.get_email_link(
+        batch[0]["text"].as_str().unwrap(),
+        "/subscriptions/unsubscribe",
+    );
+    let token = token(&unsubscribe_link);
+
+    // act
+    let get_response = app
+        .api_client
+        .get(format!(
+            "{}/subscriptions/preferences?token={}",
+            app.address, token
+        ))
+        .send()
+        .await
+        .unwrap();
+    let post_response = app
+        .post_preferences(&serde_json::json!({
+            "token": token,
+            "name": "le guin",
+            "email": "the_boss@gmail.com",
+        }))
+        .await;
+
+    // assert
+    assert_eq!(get_response.status().as_u16(), 401);
+    assert_eq!(post_response.status().as_u16(), 401);
+    assert_eq!(subscription(&app).await.name, "the boss");
+}
+
+#[tokio::test]
+async fn changing_preferences_requires_a_csrf_token() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let link = preferences_link(&app).await;
+
+    // act
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions/preferences", app.address))
+        .form(&serde_json::json!({
+            "token": token(&link),
+            "name": "le guin",
+            "email": "the_boss@gmail.com",
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    // assert
+    assert_eq!(response.status().as_u16(), 403);
+    assert_eq!(subscription(&app).await.name, "the boss");
+}
+
+#[tokio::test]
+async fn subscribers_can_turn_topics_off() {
+    // arrange
+    let app = spawn_app().await;
+    let rust_id = create_topic(&app.connection_pool, "Rust").await.unwrap();
+    let cooking_id = create_topic(&app.connection_pool, "Cooking").await.unwrap();
+    create_confirmed_subscriber(&app).await;
+    let link = preferences_link(&app).await;
+
+    let html = app
+        .api_client
+        .get(link.clone())
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(html.contains(&format!(
+        r#"name="topic_{}" type="checkbox" checked"#,
+        rust_id
+    )));
+
+    // act - only cooking stays ticked
+    let cooking_field = format!("topic_{}", cooking_id);
+    let response = app
+        .post_preferences(&serde_json::json!({
+            "token": token(&link),
+            "name": "the boss",
+            "email": "the_boss@gmail.com",
+            cooking_field: "on",
+        }))
+        .await;
+
+    // assert
+    assert_eq!(response.status().as_u16(), 303);
+    let html = app
+        .api_client
+        .get(link)
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(html.contains(&format!(r#"name="topic_{}""#, rust_id)));
+    assert!(!html.contains(&format!(
+        r#"name="topic_{}" type="checkbox" checked"#,
+        rust_id
+    )));
+
+    Mock::given(path("/emails/batch"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    for topic in ["Rust", "Cooking"] {
+        let response = app
+            .post_newsletters(serde_json::json!({
+                "title": format!("All about {}", topic),
+                "topic": topic,
+                "content": {
+                    "text": "Newsletter content",
+                    "html": "<h1>Newsletter content</h1>"
+                }
+            }))
+            .await;
+        assert_eq!(response.status().as_u16(), 202);
+    }
+    app.wait_for_delivery_queue_to_drain().await;
+
+    let deliveries = sqlx::query_scalar!(
+        r#"
+        SELECT i.title
+        FROM deliveries d
+        JOIN newsletter_issues i USING (newsletter_issue_id)
+        WHERE i.topic_id IS NOT NULL
+        "#
+    )
+    .fetch_all(&app.connection_pool)
+    .await
+    .unwrap();
+    assert_eq!(deliveries, vec!["All about Cooking".to_string()]);
+}
@@ -0,0 +1,304 @@
+use reqwest::Url;
+use wiremock::{matchers::path, Mock, ResponseTemplate};
+
+use crate::helpers::{create_confirmed_subscriber, spawn_app, TestApplication};
+
+/// The link of the `List-Unsubscribe` header, pointed at the test application
+fn unsubscribe_link(app: &TestApplication, email: &serde_json::Value) -> Url {
+    let header = email["headers"]["List-Unsubscribe"].as_str().unwrap();
+    let mut link = Url::parse(header.trim_start_matches('<').trim_end_matches('>')).unwrap();
+    assert_eq!(link.host_str().unwrap(), "127.0.0.1");
+    link.set_port(Some(app.port)).unwrap();
+
+    link
+}
+
+async fn subscription_status(app: &TestApplication) -> String {
+    sqlx::query_scalar!("SELECT status FROM subscriptions")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn every_issue_carries_an_unsubscribe_link_and_headers() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // act
+    let batch = app.publish_issue().await;
+
+    // assert
+    let email = &batch[0];
+    let link = email["headers"]["List-Unsubscribe"].as_str().unwrap();
+    assert!(link.starts_with("<http://127.0.0.1/subscriptions/unsubscribe?token="));
+    assert_eq!(
+        email["headers"]["List-Unsubscribe-Post"],
+        "List-Unsubscribe=One-Click"
+    );
+
+    let link = link.trim_start_matches('<').trim_end_matches('>');
+    assert!(email["html"].as_str().unwrap().contains(link));
+    assert!(email["text"].as_str().unwrap().contains(link));
+}
+
+#[tokio::test]
+async fn one_click_unsubscribe_works_without_a_csrf_token() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let batch = app.publish_issue().await;
+    let link = unsubscribe_link(&app, &batch[0]);
+
+    // act - what a mail client does with `List-Unsubscribe-Post`
+    let response = reqwest::Client::new()
+        .post(link)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("List-Unsubscribe=One-Click")
+        .send()
+        .await
+        .unwrap();
+
+    // assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscription_status(&app).await, "unsubscribed");
+}
+
+#[tokio::test]
+async fn following_the_link_asks_for_confirmation_first() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let batch = app.publish_issue().await;
+    let link = unsubscribe_link(&app, &batch[0]);
+
+    // act
+    let response = reqwest::get(link.clone()).await.unwrap();
+
+    // assert
+    assert_eq!(response.status().as_u16(), 200);
+    let html = response.text().await.unwrap();
+    assert!(html.contains(r#"id="unsubscribe-form""#));
+    assert!(html.contains(&format!(
+        "/subscriptions/unsubscribe?{}",
+        link.query().unwrap()
+    )));
+    assert_eq!(subscription_status(&app).await, "confirmed");
+}
+
+#[tokio::test]
+async fn unsubscribed_subscribers_get_no_further_issues() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let batch = app.publish_issue().await;
+    reqwest::Client::new()
+        .post(unsubscribe_link(&app, &batch[0]))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    Mock::given(path("/emails/batch"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // act
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "newsletter title",
+            "content": {
+                "text": "Newsletter content",
+                "html": "<h1>Newsletter content</h1>"
+            }
+        }))
+        .await;
+
+    // assert
+    assert_eq!(response.status().as_u16(), 202);
+    app.wait_for_delivery_queue_to_drain().await;
+}
+
+#[tokio::test]
+async fn queued_issues_are_skipped_for_who_unsubscribed_in_the_meantime() {
+    // arrange - the provider is down, the delivery fails and waits to be re-queued
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.test_user.login(&app).await;
+    Mock::given(path("/emails/batch"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "newsletter title",
+            "content": {
+                "text": "Newsletter content",
+                "html": "<h1>Newsletter content</h1>"
+            }
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    app.wait_for_delivery_queue_to_drain().await;
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let batch: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    reqwest::Client::new()
+        .post(unsubscribe_link(&app, &batch[0]))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    app.email_server.reset().await;
+    Mock::given(path("/emails/batch"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    let issue_id = sqlx::query_scalar!("SELECT newsletter_issue_id FROM newsletter_issues")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+
+    // act
+    app.post_requeue_failed_deliveries(issue_id).await;
+    app.wait_for_delivery_queue_to_drain().await;
+
+    // assert
+    let status = sqlx::query_scalar!("SELECT status FROM deliveries")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(status, "skipped");
+    let html_page = app.get_issue_html(issue_id).await;
+    assert!(html_page
+        .contains(r#"id="deliveries-skipped" class="text-2xl font-semibold text-gray-900">1<"#));
+}
+
+#[tokio::test]
+async fn unsubscribing_twice_is_fine() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let batch = app.publish_issue().await;
+    let link = unsubscribe_link(&app, &batch[0]);
+
+    for _ in 0..2 {
+        // act
+        let response = reqwest::Client::new()
+            .post(link.clone())
+            .send()
+            .await
+            .unwrap();
+
+        // assert
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    assert_eq!(subscription_status(&app).await, "unsubscribed");
+}
+
+#[tokio::test]
+async fn tampered_tokens_are_rejected_with_401() {
+    // arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let batch = app.publish_issue().await;
+    let link = unsubscribe_link(&app, &batch[0]);
+    let token = link.query_pairs().next().unwrap().1.to_string();
+    let mut tampered = token.into_bytes();
+    let last = tampered.last_mut().unwrap();
+    *last = if *last == b'A' { b'B' } else { b'A' };
+    let tampered = String::from_utf8(tampered).unwrap();
+    let url = format!(
+        "{}/subscriptions/unsubscribe?token={}",
+        app.address, tampered
+    );
+
+    // act
+    let get_response = reqwest::get(&url).await.unwrap();
+    let post_response = reqwest::Client::new().post(&url).send().await.unwrap();
+
+    // assert
+    assert_eq!(get_response.status().as_u16(), 401);
+    assert_eq!(post_response.status().as_u16(), 401);
+    assert_eq!(subscription_status(&app).await, "confirmed");
+}
+
+#[tokio::test]
+async fn unsubscribing_without_a_token_is_rejected_with_400() {
+    // arrange
+    let app = spawn_app().await;
+
+    // act
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions/unsubscribe", app.address))
+        .send()
+        .await
+        .unwrap();
+
+    // assert
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn outstanding_links_do_not_bring_an_unsubscribed_subscriber_back() {
+    // arrange - an email change is waiting to be confirmed
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let batch = app.publish_issue().await;
+    let preferences_link = app.get_email_link(
+        batch[0]["text"].as_str().unwrap(),
+        "/subscriptions/preferences",
+    );
+    let preferences_token = preferences_link
+        .query_pairs()
+        .find(|(key, _)| key == "token")
+        .unwrap()
+        .1
+        .to_string();
+
+    Mock::given(path("/emails"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let sent_before = app.email_server.received_requests().await.unwrap().len();
+    app.post_preferences(&serde_json::json!({
+        "token": preferences_token,
+        "name": "the boss",
+        "email": "ursula@example.com",
+    }))
+    .await;
+    let email_request = app
+        .wait_for_email_requests(sent_before + 1)
+        .await
+        .pop()
+        .unwrap();
+    let confirmation_links = app.get_confirmation_links(&email_request);
+
+    reqwest::Client::new()
+        .post(unsubscribe_link(&app, &batch[0]))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // act
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+
+    // assert
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(subscription_status(&app).await, "unsubscribed");
+}
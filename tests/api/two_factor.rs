@@ -0,0 +1,346 @@
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::helpers::{assert_is_redirect_to, spawn_app, TestApplication};
+
+struct Enrollment {
+    totp: TOTP,
+    recovery_codes: Vec<String>,
+}
+
+fn text_between<'a>(html: &'a str, start: &str, end: &str) -> &'a str {
+    let from = html.find(start).expect("Start marker not found.") + start.len();
+    let to = from + html[from..].find(end).expect("End marker not found.");
+    &html[from..to]
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+// log in, scan the "QR code" and turn on two-factor authentication
+async fn enroll(app: &TestApplication) -> Enrollment {
+    app.test_user.login(app).await;
+
+    let html_page = app.get_two_factor_html().await;
+    let secret = text_between(
+        &html_page,
+        r#"<code class="font-mono text-gray-900">"#,
+        "</code>",
+    );
+    let totp = TOTP::new(
+        Algorithm::SHA1,
+        6,
+        1,
+        30,
+        Secret::Encoded(secret.to_string()).to_bytes().unwrap(),
+        None,
+        "".to_string(),
+    )
+    .unwrap();
+
+    let response = app.post_two_factor(&totp.generate(now())).await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains("Two-factor authentication has been turned on."));
+    let recovery_codes = text_between(&html_page, r#"<ul id="recovery-codes""#, "</ul>")
+        .split("<li>")
+        .skip(1)
+        .map(|item| item.split("</li>").next().unwrap().trim().to_string())
+        .collect();
+
+    Enrollment {
+        totp,
+        recovery_codes,
+    }
+}
+
+async fn log_in_with_password(app: &TestApplication) -> reqwest::Response {
+    app.post_login(&serde_json::json!({
+        "email": &app.test_user.username,
+        "password": &app.test_user.password,
+    }))
+    .await
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_set_up_two_factor_authentication() {
+    // arrange
+    let app = spawn_app().await;
+
+    // act
+    let response = app.post_two_factor("123456").await;
+
+    // assert
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn the_setup_page_shows_a_qr_code_and_otpauth_uri() {
+    // arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    // act
+    let html_page = app.get_two_factor_html().await;
+
+    // assert
+    assert!(html_page.contains("data:image/png;base64,"));
+    assert!(html_page.contains("otpauth://totp/"));
+
+    // reloading keeps the same secret
+    assert_eq!(html_page, app.get_two_factor_html().await);
+}
+
+#[tokio::test]
+async fn an_invalid_code_does_not_enable_two_factor_authentication() {
+    // arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    app.get_two_factor_html().await;
+
+    // act - 1
+    let response = app.post_two_factor("000000").await;
+    assert_is_redirect_to(&response, "/admin/two-factor");
+
+    // act - 2
+    let html_page = app.get_two_factor_html().await;
+    assert!(html_page.contains("Invalid authentication code, please try again."));
+
+    let saved = sqlx::query!("SELECT totp_enabled FROM users")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert!(!saved.totp_enabled);
+}
+
+#[tokio::test]
+async fn the_totp_secret_is_stored_encrypted() {
+    // arrange
+    let app = spawn_app().await;
+
+    // act
+    let enrollment = enroll(&app).await;
+
+    // assert
+    let saved = sqlx::query!("SELECT totp_secret, totp_enabled FROM users")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert!(saved.totp_enabled);
+    assert_ne!(
+        saved.totp_secret.unwrap(),
+        enrollment.totp.get_secret_base32()
+    );
+    assert_eq!(enrollment.recovery_codes.len(), 10);
+}
+
+#[tokio::test]
+async fn login_asks_for_a_code_before_the_session_is_established() {
+    // arrange
+    let app = spawn_app().await;
+    let enrollment = enroll(&app).await;
+    app.post_logout().await;
+
+    // act - 1
+    // the password is right, htmx swaps in the code form
+    let response = log_in_with_password(&app).await;
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        response.headers().get("HX-Retarget").unwrap(),
+        "#login-form"
+    );
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("Authentication code"));
+
+    // act - 2
+    // not logged in yet
+    let response = app.get_admin_dashboard().await;
+    assert_is_redirect_to(&response, "/login");
+
+    // act - 3
+    // the code of the next time step, the current one was used up by the enrollment
+    let response = app
+        .post_login_two_factor(&enrollment.totp.generate(now() + 30))
+        .await;
+    assert_eq!(response.headers().get("HX-Redirect").unwrap(), "/");
+
+    // act - 4
+    let response = app.get_admin_dashboard().await;
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn an_invalid_code_is_rejected_at_login() {
+    // arrange
+    let app = spawn_app().await;
+    enroll(&app).await;
+    app.post_logout().await;
+    log_in_with_password(&app).await;
+
+    // act
+    let response = app.post_login_two_factor("000000").await;
+
+    // assert
+    assert_eq!(response.status().as_u16(), 418);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("Invalid authentication code"));
+
+    let response = app.get_admin_dashboard().await;
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn too_many_invalid_codes_restart_the_login() {
+    // arrange
+    let app = spawn_app().await;
+    let enrollment = enroll(&app).await;
+    app.post_logout().await;
+    log_in_with_password(&app).await;
+
+    // act - 1
+    for _ in 0..4 {
+        let response = app.post_login_two_factor("000000").await;
+        assert_eq!(response.status().as_u16(), 418);
+    }
+    let response = app.post_login_two_factor("000000").await;
+    assert_eq!(response.headers().get("HX-Redirect").unwrap(), "/login");
+
+    // act - 2
+    // even the right code does not help anymore
+    let response = app
+        .post_login_two_factor(&enrollment.totp.generate(now() + 30))
+        .await;
+    assert_eq!(response.headers().get("HX-Redirect").unwrap(), "/login");
+    let html_page = app.get_login_html().await;
+    assert!(html_page.contains("Your login attempt has expired"));
+}
+
+#[tokio::test]
+async fn invalid_codes_count_towards_the_login_throttle_across_logins() {
+    // arrange
+    let app = spawn_app().await;
+    let enrollment = enroll(&app).await;
+    app.post_logout().await;
+    log_in_with_password(&app).await;
+    for _ in 0..4 {
+        app.post_login_two_factor("000000").await;
+    }
+
+    // act - 1
+    // the right password does not reset the counter, the second factor is still owed
+    let response = log_in_with_password(&app).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let response = app.post_login_two_factor("000000").await;
+    assert_eq!(response.status().as_u16(), 418);
+
+    // act - 2
+    let response = log_in_with_password(&app).await;
+    assert_eq!(response.status().as_u16(), 429);
+    let response = app
+        .post_login_two_factor(&enrollment.totp.generate(now() + 30))
+        .await;
+    assert_eq!(response.status().as_u16(), 429);
+}
+
+#[tokio::test]
+async fn a_recovery_code_can_be_used_once() {
+    // arrange
+    let app = spawn_app().await;
+    let enrollment = enroll(&app).await;
+    let recovery_code = &enrollment.recovery_codes[0];
+    app.post_logout().await;
+
+    // act - 1
+    log_in_with_password(&app).await;
+    let response = app.post_login_two_factor(recovery_code).await;
+    assert_eq!(response.headers().get("HX-Redirect").unwrap(), "/");
+    app.post_logout().await;
+
+    // act - 2
+    log_in_with_password(&app).await;
+    let response = app.post_login_two_factor(recovery_code).await;
+    assert_eq!(response.status().as_u16(), 418);
+}
+
+#[tokio::test]
+async fn two_factor_authentication_can_be_turned_off() {
+    // arrange
+    let app = spawn_app().await;
+    let enrollment = enroll(&app).await;
+
+    // act - 1
+    let response = app
+        .post_disable_two_factor(&enrollment.recovery_codes[0])
+        .await;
+    assert_is_redirect_to(&response, "/admin/two-factor");
+    app.post_logout().await;
+
+    // act - 2
+    // the password alone is enough again
+    let response = log_in_with_password(&app).await;
+    assert_eq!(response.headers().get("HX-Redirect").unwrap(), "/");
+}
+
+#[tokio::test]
+async fn publishing_with_a_password_is_rejected_once_two_factor_is_on() {
+    // arrange
+    let app = spawn_app().await;
+    enroll(&app).await;
+    let body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+
+    // act
+    let response = app.post_newsletters(body.clone()).await;
+
+    // assert
+    assert_eq!(response.status().as_u16(), 403);
+    assert!(response.text().await.unwrap().contains("API token"));
+
+    let token = app.create_api_token(&["newsletters:publish"]).await;
+    let response = app.post_newsletters_with_token(&token, body).await;
+    assert_eq!(response.status().as_u16(), 202);
+}
+
+#[tokio::test]
+async fn publishing_with_a_password_does_not_reset_the_login_throttle() {
+    // arrange
+    let app = spawn_app().await;
+    enroll(&app).await;
+    app.post_logout().await;
+    log_in_with_password(&app).await;
+    for _ in 0..4 {
+        app.post_login_two_factor("000000").await;
+    }
+
+    // act - the right password through the API, rejected for lack of a second factor
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 403);
+
+    // assert - the failures still count, the fifth one triggers the backoff
+    app.post_login_two_factor("000000").await;
+    let response = log_in_with_password(&app).await;
+    assert_eq!(response.status().as_u16(), 429);
+}